@@ -129,7 +129,8 @@ impl RandomAccessFile for AesCtrFile {
         self.len
     }
     fn commit(&self) -> Result<(), Error> {
-        Ok(())
+        // The encryption layer holds no data itself, so committing it commits the underlying file.
+        self.data.commit()
     }
 }
 
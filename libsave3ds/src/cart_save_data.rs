@@ -1,11 +1,73 @@
 use crate::aes_ctr_file::*;
 use crate::error::*;
 use crate::file_system::*;
+use crate::padded_file::*;
 use crate::random_access_file::*;
 use crate::save_data::*;
+use crate::sub_file::*;
 use crate::wear_leveling::*;
 use std::rc::Rc;
 
+pub use crate::wear_leveling::{BlockMapEntry, ChunkCrcStatus, JournalEntry, WearLevelingInfo};
+
+/// Physical sizes of cartridge save chips, including the larger ones without wear leveling.
+/// Used for recognizing dumps that are padded or truncated.
+const CHIP_LEN: [usize; 9] = [
+    0x20_000,
+    0x40_000,
+    0x80_000,
+    0x100_000,
+    0x200_000,
+    0x400_000,
+    0x800_000,
+    0x1_000_000,
+    0x2_000_000,
+];
+
+fn is_erased(file: &dyn RandomAccessFile, begin: usize, end: usize) -> Result<bool, Error> {
+    let mut buf = vec![0; 0x10000];
+    let mut pos = begin;
+    while pos < end {
+        let len = std::cmp::min(buf.len(), end - pos);
+        file.read(pos, &mut buf[0..len])?;
+        if buf[0..len].iter().any(|&b| b != 0xFF) {
+            return Ok(false);
+        }
+        pos += len;
+    }
+    Ok(true)
+}
+
+/// Lists possible chip images contained in a dump, from the most likely one to the least.
+///
+/// A dump can be larger than the chip if it is padded with 0xFF,
+/// or smaller than the chip if the dumper stopped at the last non-erased byte.
+fn chip_candidates(
+    file: Rc<dyn RandomAccessFile>,
+    wear_leveling: bool,
+) -> Result<Vec<Rc<dyn RandomAccessFile>>, Error> {
+    let len = file.len();
+    let chip_len_list: &[usize] = if wear_leveling {
+        &WEAR_LEVELING_LEN
+    } else {
+        &CHIP_LEN
+    };
+
+    let mut candidates: Vec<Rc<dyn RandomAccessFile>> = vec![];
+    if !wear_leveling || chip_len_list.contains(&len) {
+        candidates.push(file.clone());
+    }
+    for &chip_len in chip_len_list.iter().filter(|&&chip_len| chip_len < len) {
+        if is_erased(file.as_ref(), chip_len, len)? {
+            candidates.push(Rc::new(SubFile::new(file.clone(), 0, chip_len)?));
+        }
+    }
+    for &chip_len in chip_len_list.iter().filter(|&&chip_len| chip_len > len) {
+        candidates.push(Rc::new(PaddedFile::new(file.clone(), chip_len)?));
+    }
+    Ok(candidates)
+}
+
 pub(crate) struct CartFormat {
     pub wear_leveling: bool,
     pub key: [u8; 16],
@@ -16,7 +78,7 @@ pub(crate) struct CartFormat {
 /// A wrapper of [`SaveData`](../save_data/struct.SaveData.html),
 /// specialized for cartridge save data. Implements [`FileSystem`](../file_system/trait.FileSystem.html).
 pub struct CartSaveData {
-    wear_leveling: Option<Rc<WearLeveling>>,
+    file: Rc<dyn RandomAccessFile>,
     save_data: SaveData,
 }
 
@@ -31,24 +93,190 @@ impl CartSaveData {
         }: &CartFormat,
         param: &SaveDataFormatParam,
     ) -> Result<(), Error> {
-        let (wear_leveling, file): (_, Rc<dyn RandomAccessFile>) = if wear_leveling {
-            Rc::new(WearLeveling::format(file.clone())?);
-            let wear_leveling = Rc::new(WearLeveling::new(file)?);
-            (Some(wear_leveling.clone()), wear_leveling)
+        let raw = file.clone();
+        let len = file.len();
+        let file: Rc<dyn RandomAccessFile> = if !wear_leveling || WEAR_LEVELING_LEN.contains(&len) {
+            file
         } else {
-            (None, file)
+            // Use the largest chip that fits, and pad the rest as erased flash.
+            let chip_len = *WEAR_LEVELING_LEN
+                .iter()
+                .rev()
+                .find(|&&chip_len| chip_len < len)
+                .ok_or(Error::SizeMismatch)?;
+            file.write(chip_len, &vec![0xFF; len - chip_len])?;
+            Rc::new(SubFile::new(file, 0, chip_len)?)
         };
 
+        let file: Rc<dyn RandomAccessFile> = if wear_leveling {
+            WearLeveling::format(file.clone())?;
+            Rc::new(WearLeveling::new(file)?)
+        } else {
+            file
+        };
+
+        // The save data commits the wear leveling layer through the encryption layer.
         let save = Rc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
 
         SaveData::format(save, SaveDataType::Cart(key_cmac), param)?;
-        if let Some(wear_leveling) = wear_leveling {
-            wear_leveling.commit()?;
-        }
-        Ok(())
+        raw.commit()
     }
 
+    /// Opens the save data in a cartridge dump.
+    ///
+    /// Dumps padded with 0xFF or truncated at the end are accepted.
+    /// The dump keeps its original length when written back.
     pub(crate) fn new(
+        file: Rc<dyn RandomAccessFile>,
+        format: &CartFormat,
+    ) -> Result<CartSaveData, Error> {
+        CartSaveData::open(file, format, false)
+    }
+
+    /// Opens the version of the save data in a cartridge dump before the last commit.
+    pub(crate) fn new_previous(
+        file: Rc<dyn RandomAccessFile>,
+        format: &CartFormat,
+    ) -> Result<CartSaveData, Error> {
+        CartSaveData::open(file, format, true)
+    }
+
+    /// Makes the version opened by `new_previous` the current one.
+    pub(crate) fn restore_previous(&self) -> Result<(), Error> {
+        self.save_data.restore_previous()?;
+        self.file.commit()
+    }
+
+    /// Returns how fragmented the blocks of the save data are. See
+    /// [`SaveData::fragmentation`](../save_data/struct.SaveData.html#method.fragmentation).
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        self.save_data.fragmentation()
+    }
+
+    /// Moves every file to a contiguous run of blocks. See
+    /// [`SaveData::defragment`](../save_data/struct.SaveData.html#method.defragment).
+    pub fn defragment(self) -> Result<(), Error> {
+        self.save_data.defragment()?;
+        self.file.commit()
+    }
+
+    /// Rebuilds the directory and file hash tables with new bucket counts. See
+    /// [`SaveData::rehash`](../save_data/struct.SaveData.html#method.rehash).
+    pub fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        self.save_data.rehash(dir_buckets, file_buckets)?;
+        self.file.commit()
+    }
+
+    fn open(
+        file: Rc<dyn RandomAccessFile>,
+        format: &CartFormat,
+        previous: bool,
+    ) -> Result<CartSaveData, Error> {
+        let mut first_error = None;
+        for chip in chip_candidates(file.clone(), format.wear_leveling)? {
+            match CartSaveData::from_chip(file.clone(), chip, format, previous) {
+                Ok(save) => return Ok(save),
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(e)
+                    }
+                }
+            }
+        }
+        Err(first_error.unwrap_or(Error::SizeMismatch))
+    }
+
+    /// Reads the wear leveling structures in a Card1 cartridge dump, without any key.
+    pub(crate) fn inspect_wear_leveling(
+        file: Rc<dyn RandomAccessFile>,
+    ) -> Result<WearLevelingInfo, Error> {
+        let mut first_info = None;
+        for chip in chip_candidates(file, true)? {
+            let info = WearLeveling::inspect(chip)?;
+            if info.block_map_crc_valid {
+                return Ok(info);
+            }
+            if first_info.is_none() {
+                first_info = Some(info);
+            }
+        }
+        first_info.ok_or(Error::SizeMismatch)
+    }
+
+    fn open_wear_leveling(file: Rc<dyn RandomAccessFile>) -> Result<WearLeveling, Error> {
+        let mut first_error = None;
+        for chip in chip_candidates(file, true)? {
+            match WearLeveling::new(chip) {
+                Ok(wear_leveling) => return Ok(wear_leveling),
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(e)
+                    }
+                }
+            }
+        }
+        Err(first_error.unwrap_or(Error::SizeMismatch))
+    }
+
+    /// Folds the wear leveling journal of a Card1 cartridge dump into its block map.
+    pub(crate) fn compact_wear_leveling(file: Rc<dyn RandomAccessFile>) -> Result<(), Error> {
+        CartSaveData::open_wear_leveling(file.clone())?.commit()?;
+        file.commit()
+    }
+
+    /// Copies the content of a Card1 cartridge dump out of wear leveling into `plain`,
+    /// resulting in a plain image as used by cartridges without wear leveling.
+    /// `plain` must be [`unwrapped_len`](#method.unwrapped_len) bytes long.
+    /// The content stays encrypted and signed.
+    pub(crate) fn unwrap_wear_leveling(
+        file: Rc<dyn RandomAccessFile>,
+        plain: &dyn RandomAccessFile,
+    ) -> Result<(), Error> {
+        copy_file(&CartSaveData::open_wear_leveling(file)?, plain)
+    }
+
+    /// Returns the length of the plain image of a Card1 cartridge dump.
+    pub(crate) fn unwrapped_len(file: Rc<dyn RandomAccessFile>) -> Result<usize, Error> {
+        Ok(CartSaveData::open_wear_leveling(file)?.len())
+    }
+
+    /// Returns the size of the smallest Card1 chip that can hold a plain image of `len` bytes.
+    pub(crate) fn wrapped_len(len: usize) -> Result<usize, Error> {
+        Ok(*WEAR_LEVELING_LEN
+            .iter()
+            .find(|&&chip_len| chip_len - 0x2000 >= len)
+            .ok_or(Error::NoSpace)?)
+    }
+
+    /// Puts a plain cartridge save image into the Card1 chip image `chip` with wear leveling,
+    /// overwriting all of it. See [`wrapped_len`](#method.wrapped_len) for the chip size.
+    pub(crate) fn wrap_wear_leveling(
+        plain: &dyn RandomAccessFile,
+        chip: Rc<dyn RandomAccessFile>,
+    ) -> Result<(), Error> {
+        let mut data = vec![0; plain.len()];
+        plain.read(0, &mut data)?;
+
+        // Trailing erased bytes don't need to be stored
+        let used = data.len() - data.iter().rev().take_while(|&&b| b == 0xFF).count();
+
+        chip.write(0, &vec![0xFF; chip.len()])?;
+        WearLeveling::format(chip.clone())?;
+        let wear_leveling = WearLeveling::new(chip.clone())?;
+        if used > wear_leveling.len() {
+            return make_error(Error::NoSpace);
+        }
+        for (i, block) in data[0..used].chunks(0x1000).enumerate() {
+            if block.iter().any(|&b| b != 0xFF) {
+                wear_leveling.write(i * 0x1000, block)?;
+            }
+        }
+        wear_leveling.commit()?;
+        chip.commit()
+    }
+
+    fn from_chip(
+        raw: Rc<dyn RandomAccessFile>,
         file: Rc<dyn RandomAccessFile>,
         &CartFormat {
             wear_leveling,
@@ -56,19 +284,24 @@ impl CartSaveData {
             key_cmac,
             repeat_ctr,
         }: &CartFormat,
+        previous: bool,
     ) -> Result<CartSaveData, Error> {
-        let (wear_leveling, file): (_, Rc<dyn RandomAccessFile>) = if wear_leveling {
-            let wear_leveling = Rc::new(WearLeveling::new(file)?);
-            (Some(wear_leveling.clone()), wear_leveling)
+        let file: Rc<dyn RandomAccessFile> = if wear_leveling {
+            Rc::new(WearLeveling::new(file)?)
         } else {
-            (None, file)
+            file
         };
 
         let save = Rc::new(AesCtrFile::new(file, key, [0; 16], repeat_ctr));
+        let save_data = if previous {
+            SaveData::new_previous(save, SaveDataType::Cart(key_cmac))?
+        } else {
+            SaveData::new(save, SaveDataType::Cart(key_cmac))?
+        };
 
         Ok(CartSaveData {
-            wear_leveling,
-            save_data: SaveData::new(save, SaveDataType::Cart(key_cmac))?,
+            file: raw,
+            save_data,
         })
     }
 }
@@ -87,11 +320,9 @@ impl FileSystem for CartSaveData {
     }
 
     fn commit(&self) -> Result<(), Error> {
+        // The wear leveling layer is committed by the save data through the encryption layer.
         self.save_data.commit()?;
-        if let Some(wear_leveling) = &self.wear_leveling {
-            wear_leveling.commit()?;
-        }
-        Ok(())
+        self.file.commit()
     }
 
     fn stat(&self) -> Result<Stat, Error> {
@@ -148,7 +379,7 @@ mod test {
                 repeat_ctr: rng.gen(),
             };
 
-            let len = [0x20_000, 0x80_000, 0x100_000][rng.gen_range(0, 3)];
+            let len = [0x20_000, 0x40_000, 0x80_000, 0x100_000][rng.gen_range(0, 4)];
             let raw = Rc::new(MemoryFile::new(vec![0; len]));
             CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
             let file_system = CartSaveData::new(raw.clone(), &cart_format).unwrap();
@@ -163,4 +394,135 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn padded_and_truncated() {
+        use crate::memory_file::*;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10 {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data: rng.gen(),
+            };
+
+            let cart_format = CartFormat {
+                wear_leveling: rng.gen(),
+                key: rng.gen(),
+                key_cmac: rng.gen(),
+                repeat_ctr: rng.gen(),
+            };
+
+            let chip_len = [0x20_000, 0x40_000, 0x80_000][rng.gen_range(0, 3)];
+            let raw = Rc::new(MemoryFile::new(vec![0xFF; chip_len]));
+            CartSaveData::format(raw.clone(), &cart_format, &param).unwrap();
+            let mut image = vec![0; chip_len];
+            raw.read(0, &mut image).unwrap();
+
+            // Padded dump
+            let padded_len = chip_len * 2 + rng.gen_range(0, 0x1000);
+            let mut padded_image = image.clone();
+            padded_image.resize(padded_len, 0xFF);
+            let padded = Rc::new(MemoryFile::new(padded_image));
+            let save = CartSaveData::new(padded.clone(), &cart_format).unwrap();
+            let root = save.open_dir(1).unwrap();
+            root.new_sub_file([1; 16], 100).unwrap();
+            save.commit().unwrap();
+            assert_eq!(padded.len(), padded_len);
+            let save = CartSaveData::new(padded.clone(), &cart_format).unwrap();
+            save.open_dir(1).unwrap().open_sub_file([1; 16]).unwrap();
+            let mut tail = vec![0; padded_len - chip_len];
+            padded.read(chip_len, &mut tail).unwrap();
+            assert!(tail.iter().all(|&b| b == 0xFF));
+
+            // Truncated dump
+            let erased = image.iter().rev().take_while(|&&b| b == 0xFF).count();
+            if erased != 0 {
+                let truncated_len = chip_len - rng.gen_range(1, erased + 1);
+                let truncated = Rc::new(MemoryFile::new(image[0..truncated_len].to_vec()));
+                CartSaveData::new(truncated.clone(), &cart_format).unwrap();
+                assert_eq!(truncated.len(), truncated_len);
+            }
+        }
+    }
+
+    #[test]
+    fn wear_leveling_conversion() {
+        use crate::memory_file::*;
+        use rand::prelude::*;
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10 {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data: rng.gen(),
+            };
+
+            let key = rng.gen();
+            let key_cmac = rng.gen();
+            let repeat_ctr = rng.gen();
+            let leveled = CartFormat {
+                wear_leveling: true,
+                key,
+                key_cmac,
+                repeat_ctr,
+            };
+            let plain = CartFormat {
+                wear_leveling: false,
+                key,
+                key_cmac,
+                repeat_ctr,
+            };
+
+            let len = [0x20_000, 0x40_000, 0x80_000][rng.gen_range(0, 3)];
+            let raw = Rc::new(MemoryFile::new(vec![0xFF; len]));
+            CartSaveData::format(raw.clone(), &leveled, &param).unwrap();
+            let save = CartSaveData::new(raw.clone(), &leveled).unwrap();
+            save.open_dir(1)
+                .unwrap()
+                .new_sub_file([1; 16], 100)
+                .unwrap()
+                .write(0, &[1, 2, 3])
+                .unwrap();
+            save.commit().unwrap();
+
+            let plain_len = CartSaveData::unwrapped_len(raw.clone()).unwrap();
+            assert_eq!(plain_len, len - 0x2000);
+            let plain_file = Rc::new(MemoryFile::new(vec![0; plain_len]));
+            CartSaveData::unwrap_wear_leveling(raw, plain_file.as_ref()).unwrap();
+            let save = CartSaveData::new(plain_file.clone(), &plain).unwrap();
+            let mut buf = [0; 3];
+            save.open_dir(1)
+                .unwrap()
+                .open_sub_file([1; 16])
+                .unwrap()
+                .read(0, &mut buf)
+                .unwrap();
+            assert_eq!(buf, [1, 2, 3]);
+
+            let new_len = [None, Some(0x100_000)][rng.gen_range(0, 2)];
+            let chip_len = CartSaveData::wrapped_len(plain_file.len()).unwrap();
+            assert_eq!(chip_len, len);
+            let image = Rc::new(MemoryFile::new(vec![0; new_len.unwrap_or(chip_len)]));
+            CartSaveData::wrap_wear_leveling(plain_file.as_ref(), image.clone()).unwrap();
+            let save = CartSaveData::new(image, &leveled).unwrap();
+            let mut buf = [0; 3];
+            save.open_dir(1)
+                .unwrap()
+                .open_sub_file([1; 16])
+                .unwrap()
+                .read(0, &mut buf)
+                .unwrap();
+            assert_eq!(buf, [1, 2, 3]);
+        }
+    }
 }
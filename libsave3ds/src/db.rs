@@ -2,7 +2,7 @@ use crate::diff::Diff;
 use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
-use crate::fs_meta::{self, DirInfo, FileInfo, FsInfo, ParentedKey};
+use crate::fs_meta::{self, DirInfo, FatFileInfo, FileInfo, FsInfo, ParentedKey};
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::signed_file::*;
@@ -105,6 +105,15 @@ impl FileInfo for DbFile {
     }
 }
 
+impl FatFileInfo for DbFile {
+    fn set_block(&mut self, block: u32) {
+        self.block = block;
+    }
+    fn get_block(&self) -> u32 {
+        self.block
+    }
+}
+
 type FsMeta = fs_meta::FsMeta<DbDirKey, DbDir, DbFileKey, DbFile>;
 type DirMeta = fs_meta::DirMeta<DbDirKey, DbDir, DbFileKey, DbFile>;
 type FileMeta = fs_meta::FileMeta<DbDirKey, DbDir, DbFileKey, DbFile>;
@@ -174,8 +183,10 @@ impl Signer for DbSigner {
 
 struct DbInner {
     diff: Rc<Diff>,
+    partition: Rc<dyn RandomAccessFile>,
     fat: Rc<Fat>,
     fs: Rc<FsMeta>,
+    fs_info: Rc<dyn RandomAccessFile>,
     block_len: usize,
     block_count: usize,
 }
@@ -190,6 +201,30 @@ impl Db {
         file: Rc<dyn RandomAccessFile>,
         db_type: DbType,
         key: [u8; 16],
+    ) -> Result<Db, Error> {
+        Db::open(file, db_type, key, false)
+    }
+
+    /// Opens the version of the database before the last commit. See
+    /// [`Resource::open_db_previous`](../struct.Resource.html#method.open_db_previous).
+    pub(crate) fn new_previous(
+        file: Rc<dyn RandomAccessFile>,
+        db_type: DbType,
+        key: [u8; 16],
+    ) -> Result<Db, Error> {
+        Db::open(file, db_type, key, true)
+    }
+
+    /// Makes the version opened by `new_previous` the current one.
+    pub(crate) fn restore_previous(&self) -> Result<(), Error> {
+        self.center.diff.restore_previous()
+    }
+
+    fn open(
+        file: Rc<dyn RandomAccessFile>,
+        db_type: DbType,
+        key: [u8; 16],
+        previous: bool,
     ) -> Result<Db, Error> {
         let signer: (Box<dyn Signer>, [u8; 16]) = (
             Box::new(DbSigner {
@@ -203,7 +238,11 @@ impl Db {
             }),
             key,
         );
-        let diff = Rc::new(Diff::new(file, Some(signer))?);
+        let diff = Rc::new(if previous {
+            Diff::new_previous(file, Some(signer))?
+        } else {
+            Diff::new(file, Some(signer))?
+        });
         let pre_len = if db_type == DbType::Ticket {
             0x10
         } else {
@@ -247,7 +286,12 @@ impl Db {
             error!("Unexpected magic {:?} {:X}", header.magic, header.version);
             return make_error(Error::MagicMismatch);
         }
-        let fs_info: FsInfo = read_struct(without_pre.as_ref(), header.fs_info_offset as usize)?;
+        let fs_info_file = Rc::new(SubFile::new(
+            without_pre.clone(),
+            header.fs_info_offset as usize,
+            FsInfo::BYTE_LEN,
+        )?);
+        let fs_info: FsInfo = read_struct(fs_info_file.as_ref(), 0)?;
         if fs_info.data_block_count != fs_info.fat_size {
             error!(
                 "Unexpected data_block_count={}, fat_size={}",
@@ -287,7 +331,7 @@ impl Db {
 
         let data: Rc<dyn RandomAccessFile> = Rc::new(FakeSizeFile {
             parent: Rc::new(SubFile::new(
-                without_pre,
+                without_pre.clone(),
                 fs_info.data_offset as usize,
                 data_len - data_delta,
             )?),
@@ -311,13 +355,90 @@ impl Db {
         Ok(Db {
             center: Rc::new(DbInner {
                 diff,
+                partition: without_pre,
                 fat,
                 fs,
+                fs_info: fs_info_file,
                 block_len: fs_info.block_len as usize,
                 block_count: fs_info.data_block_count as usize,
             }),
         })
     }
+
+    /// Returns how fragmented the blocks of the database are.
+    /// See [`SaveData::fragmentation`](../save_data/struct.SaveData.html#method.fragmentation).
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        fs_meta::fat_fragmentation(
+            &self.center.fs,
+            &self.center.fat,
+            self.center.fs_info.as_ref(),
+            true,
+        )
+    }
+
+    /// Moves every file to a contiguous run of blocks, and all free blocks to one run after them.
+    /// See [`SaveData::defragment`](../save_data/struct.SaveData.html#method.defragment).
+    ///
+    /// Data of a database is not duplicated, so blocks are moved in place while the new
+    /// allocation table only takes effect on the commit. If the program stops halfway, files in
+    /// the database are corrupted, unless it is opened in
+    /// [atomic mode](../struct.ResourceBuilder.html#method.atomic).
+    pub fn defragment(self) -> Result<(), Error> {
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        self.defragment_blocks()?;
+        self.center.diff.commit()
+    }
+
+    fn defragment_blocks(&self) -> Result<(), Error> {
+        fs_meta::defragment_fat(
+            &self.center.fs,
+            &self.center.fat,
+            self.center.fs_info.as_ref(),
+            true,
+        )
+    }
+
+    /// Rebuilds the directory and file hash tables with the specified bucket counts.
+    /// See [`Stat::dir_hash`](../file_system/struct.Stat.html#structfield.dir_hash) and
+    /// [`Stat::file_hash`](../file_system/struct.Stat.html#structfield.file_hash)
+    /// for how entries are distributed over the current buckets.
+    ///
+    /// The database is defragmented first. Then the allocation table and the blocks in use are
+    /// moved to fit the new hash tables, while the end of the data region stays in place,
+    /// so the number of blocks changes with the size of the hash tables. Entries keep their inodes.
+    /// If the blocks in use don't fit, `Error::NoSpace` is returned and the database is left
+    /// untouched. As with [`defragment`](#method.defragment), this is not crash-safe
+    /// unless the database is opened in atomic mode.
+    ///
+    /// No file or directory of the database can be open, otherwise `Error::Busy` is returned.
+    /// The database is committed and closed afterwards.
+    pub fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+
+        // The data region may extend past the end of the file
+        let fs_info: FsInfo = read_struct(self.center.fs_info.as_ref(), 0)?;
+        let data_end =
+            fs_info.data_offset as usize + self.center.block_count * self.center.block_len;
+        let region = Rc::new(FakeSizeFile {
+            parent: self.center.partition.clone(),
+            len: std::cmp::max(data_end, self.center.partition.len()),
+        });
+        fs_meta::rehash_in_place(
+            &self.center.fs,
+            &self.center.fat,
+            region,
+            self.center.fs_info.as_ref(),
+            true,
+            dir_buckets,
+            file_buckets,
+        )?;
+
+        self.center.diff.commit()
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for title database file.
@@ -569,6 +690,198 @@ impl FileSystem for Db {
             free_files: meta_stat.files.free,
             total_dirs: meta_stat.dirs.total,
             free_dirs: meta_stat.dirs.free,
+            dir_hash: Some(meta_stat.dirs.chains),
+            file_hash: Some(meta_stat.files.chains),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::db::*;
+    use crate::difi_partition::DifiPartitionParam;
+    use crate::fs_meta::OffsetOrFatFile;
+    use crate::memory_file::MemoryFile;
+
+    fn signer() -> Option<(Box<dyn Signer>, [u8; 16])> {
+        Some((Box::new(DbSigner { id: 2 }), [0x42; 16]))
+    }
+
+    /// Builds an empty title database in memory, as there is no formatting in the library.
+    fn format(block_count: usize) -> Rc<MemoryFile> {
+        let block_len = 0x80;
+        let (max_dir, max_file) = (1, 10);
+        let (dir_buckets, file_buckets) = (3, 5);
+        let fs_info_offset = DbHeader::BYTE_LEN;
+        let dir_hash_offset = fs_info_offset + FsInfo::BYTE_LEN;
+        let file_hash_offset = dir_hash_offset + dir_buckets * 4;
+        let fat_offset = file_hash_offset + file_buckets * 4;
+        let data_offset = align_up(fat_offset + (block_count + 1) * 8, block_len);
+        let data_len = 0x80 + data_offset + block_count * block_len;
+
+        let param = DifiPartitionParam {
+            dpfs_level2_block_len: 128,
+            dpfs_level3_block_len: 4096,
+            ivfc_level1_block_len: 512,
+            ivfc_level2_block_len: 512,
+            ivfc_level3_block_len: 4096,
+            ivfc_level4_block_len: 4096,
+            data_len,
+            external_ivfc_level4: true,
+        };
+        let file = Rc::new(MemoryFile::new(vec![0; Diff::calculate_size(&param)]));
+        Diff::format(file.clone(), signer(), &param, 0).unwrap();
+        let diff = Diff::new(file.clone(), signer()).unwrap();
+        diff.partition().write(0, b"NANDTDB\0").unwrap();
+        let partition =
+            Rc::new(SubFile::new(diff.partition().clone(), 0x80, data_len - 0x80).unwrap());
+
+        let fat_table =
+            Rc::new(SubFile::new(partition.clone(), fat_offset, (block_count + 1) * 8).unwrap());
+        Fat::format(fat_table.as_ref()).unwrap();
+        let data =
+            Rc::new(SubFile::new(partition.clone(), data_offset, block_count * block_len).unwrap());
+        let fat = Fat::new(fat_table, data, block_len).unwrap();
+        let dir_entry_len = DbDirKey::BYTE_LEN + DbDir::BYTE_LEN + 4;
+        let file_entry_len = DbFileKey::BYTE_LEN + DbFile::BYTE_LEN + 4;
+        let (dir_table, dir_table_block) = FatFile::create(
+            fat.clone(),
+            divide_up((max_dir + 2) * dir_entry_len, block_len),
+        )
+        .unwrap();
+        let (file_table, file_table_block) =
+            FatFile::create(fat, divide_up((max_file + 1) * file_entry_len, block_len)).unwrap();
+        let dir_table_blocks = dir_table.len() / block_len;
+        let file_table_blocks = file_table.len() / block_len;
+        FsMeta::format(
+            Rc::new(SubFile::new(partition.clone(), dir_hash_offset, dir_buckets * 4).unwrap()),
+            Rc::new(dir_table),
+            max_dir + 2,
+            Rc::new(SubFile::new(partition.clone(), file_hash_offset, file_buckets * 4).unwrap()),
+            Rc::new(file_table),
+            max_file + 1,
+        )
+        .unwrap();
+
+        let header = DbHeader {
+            magic: *b"BDRI",
+            version: 0x30000,
+            fs_info_offset: fs_info_offset as u64,
+            image_size: (partition.len() / block_len) as u64,
+            image_block_len: block_len as u32,
+            padding: 0,
+        };
+        write_struct(partition.as_ref(), 0, header).unwrap();
+        let fs_info = FsInfo {
+            unknown: 0,
+            block_len: block_len as u32,
+            dir_hash_offset: dir_hash_offset as u64,
+            dir_buckets: dir_buckets as u32,
+            p0: 0,
+            file_hash_offset: file_hash_offset as u64,
+            file_buckets: file_buckets as u32,
+            p1: 0,
+            fat_offset: fat_offset as u64,
+            fat_size: block_count as u32,
+            p2: 0,
+            data_offset: data_offset as u64,
+            data_block_count: block_count as u32,
+            p3: 0,
+            dir_table: OffsetOrFatFile {
+                block_index: dir_table_block as u32,
+                block_count: dir_table_blocks as u32,
+            },
+            max_dir: max_dir as u32,
+            p4: 0,
+            file_table: OffsetOrFatFile {
+                block_index: file_table_block as u32,
+                block_count: file_table_blocks as u32,
+            },
+            max_file: max_file as u32,
+            p5: 0,
+        };
+        write_struct(partition.as_ref(), fs_info_offset, fs_info).unwrap();
+        diff.commit().unwrap();
+        file
+    }
+
+    #[test]
+    fn defragment() {
+        let raw = format(40);
+        let db = Db::new(raw.clone(), DbType::NandTitle, [0x42; 16]).unwrap();
+        let root = db.open_root().unwrap();
+        let mut files = vec![
+            root.new_sub_file(1, 1).unwrap(),
+            root.new_sub_file(2, 1).unwrap(),
+        ];
+        for len in 2..6 {
+            for file in files.iter_mut() {
+                file.resize(len * 0x80).unwrap();
+            }
+        }
+        for (i, file) in files.iter().enumerate() {
+            file.write(0, &vec![i as u8 + 1; file.len()]).unwrap();
+        }
+        assert_eq!(db.fragmentation().unwrap().max_file_runs, 5);
+
+        db.commit().unwrap();
+        assert!(matches!(db.defragment(), Err(Error::Busy)));
+        drop(files);
+        drop(root);
+        let db = Db::new(raw.clone(), DbType::NandTitle, [0x42; 16]).unwrap();
+        let free_blocks = db.stat().unwrap().free_blocks;
+        db.defragment().unwrap();
+
+        let db = Db::new(raw, DbType::NandTitle, [0x42; 16]).unwrap();
+        assert_eq!(db.stat().unwrap().free_blocks, free_blocks);
+        let fragmentation = db.fragmentation().unwrap();
+        assert_eq!(fragmentation.files, 4);
+        assert_eq!(fragmentation.max_file_runs, 1);
+        assert_eq!(fragmentation.free_runs, 1);
+        assert_eq!(fragmentation.largest_free_run, free_blocks);
+        let root = db.open_root().unwrap();
+        for i in 0..2 {
+            let file = root.open_sub_file(i + 1).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![i as u8 + 1; 5 * 0x80]);
+        }
+    }
+
+    #[test]
+    fn rehash() {
+        let raw = format(40);
+        let db = Db::new(raw.clone(), DbType::NandTitle, [0x42; 16]).unwrap();
+        let root = db.open_root().unwrap();
+        let mut inos = vec![];
+        for i in 0..6 {
+            let file = root.new_sub_file(i, 0x100).unwrap();
+            file.write(0, &[i as u8; 0x100]).unwrap();
+            inos.push(file.get_ino());
+        }
+        root.open_sub_file(2).unwrap().delete().unwrap();
+        db.commit().unwrap();
+        assert!(matches!(db.rehash(1, 1), Err(Error::Busy)));
+        drop(root);
+
+        let db = Db::new(raw.clone(), DbType::NandTitle, [0x42; 16]).unwrap();
+        assert!(matches!(db.rehash(0x1000, 1), Err(Error::NoSpace)));
+        let db = Db::new(raw.clone(), DbType::NandTitle, [0x42; 16]).unwrap();
+        db.rehash(1, 7).unwrap();
+
+        let db = Db::new(raw, DbType::NandTitle, [0x42; 16]).unwrap();
+        let stat = db.stat().unwrap();
+        assert_eq!(stat.dir_hash.unwrap().buckets, 1);
+        let file_hash = stat.file_hash.unwrap();
+        assert_eq!((file_hash.buckets, file_hash.entries), (7, 5));
+        let root = db.open_root().unwrap();
+        for i in [0, 1, 3, 4, 5].iter().cloned() {
+            let file = root.open_sub_file(i).unwrap();
+            assert_eq!(file.get_ino(), inos[i as usize]);
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![i as u8; 0x100]);
+        }
+        root.new_sub_file(2, 0x80).unwrap();
+    }
+}
@@ -28,10 +28,12 @@ struct DiffHeader {
 
 /// DIFF container format that contains one DIFI partition.
 pub struct Diff {
+    file: Rc<dyn RandomAccessFile>,
     parent_len: usize,
     header_file: Rc<dyn RandomAccessFile>,
-    table_upper: Rc<DualFile>,
-    table_lower: Rc<IvfcLevel>,
+    table_upper: Rc<dyn RandomAccessFile>,
+    table_lower: Rc<dyn RandomAccessFile>,
+    previous: bool,
     partition: Rc<DifiPartition>,
     unique_id: u64,
 }
@@ -117,12 +119,33 @@ impl Diff {
         DifiPartition::format(table.as_ref(), param)?;
         table.commit()?;
         header_file.commit()?;
-        Ok(())
+        file.commit()
     }
 
     pub fn new(
         file: Rc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
+    ) -> Result<Diff, Error> {
+        Diff::open(file, signer, false)
+    }
+
+    /// Opens the version of the container before the last commit, which is usually still intact
+    /// in the inactive copy of the table and the data.
+    ///
+    /// The inactive table can't be verified, as the header only holds the hash of the active one,
+    /// but the partition data are still verified against the hashes in it.
+    /// The container must not be written in this mode.
+    pub fn new_previous(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+    ) -> Result<Diff, Error> {
+        Diff::open(file, signer, true)
+    }
+
+    fn open(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        previous: bool,
     ) -> Result<Diff, Error> {
         let parent_len = file.len();
         let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
@@ -162,13 +185,19 @@ impl Diff {
             )?),
         ];
 
-        let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
-
-        let table_lower = Rc::new(IvfcLevel::new(
-            table_hash,
-            table_upper.clone(),
-            header.table_size as usize,
-        )?);
+        let (table_upper, table_lower): (Rc<dyn RandomAccessFile>, Rc<dyn RandomAccessFile>) =
+            if previous {
+                let table = DualFile::inactive(table_selector.as_ref(), &table_pair)?;
+                (table.clone(), table)
+            } else {
+                let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
+                let table_lower = Rc::new(IvfcLevel::new(
+                    table_hash,
+                    table_upper.clone(),
+                    header.table_size as usize,
+                )?);
+                (table_upper, table_lower)
+            };
 
         let partition = Rc::new(SubFile::new(
             file.clone(),
@@ -178,10 +207,12 @@ impl Diff {
         let partition = Rc::new(DifiPartition::new(table_lower.clone(), partition)?);
 
         Ok(Diff {
+            file,
             parent_len,
             header_file,
             table_upper,
             table_lower,
+            previous,
             partition,
             unique_id: header.unique_id,
         })
@@ -192,10 +223,73 @@ impl Diff {
     }
 
     pub fn commit(&self) -> Result<(), Error> {
+        if self.previous {
+            return make_error(Error::Unsupported);
+        }
         self.partition.commit()?;
         self.table_lower.commit()?;
         self.table_upper.commit()?;
-        self.header_file.commit()
+        self.header_file.commit()?;
+        self.file.commit()
+    }
+
+    /// Makes the version opened by [`new_previous`](#method.new_previous) the current one,
+    /// by switching to the inactive table and updating its hash in the header.
+    /// The version that was current becomes the previous one.
+    pub fn restore_previous(&self) -> Result<(), Error> {
+        if !self.previous {
+            return make_error(Error::Unsupported);
+        }
+        let table_hash = Rc::new(SubFile::new(self.header_file.clone(), 0x34, 0x20)?);
+        let table_len = self.table_upper.len();
+        IvfcLevel::rehash(table_hash, self.table_upper.clone(), table_len)?;
+        DualFile::switch(&SubFile::new(self.header_file.clone(), 0x30, 1)?)?;
+        self.header_file.commit()?;
+        self.file.commit()
+    }
+
+    /// Changes the data length of the partition to `param.data_len` in place,
+    /// keeping the data within the new length, and commits.
+    /// `param` must only differ from the current layout in the data length,
+    /// see [`DifiPartition::same_layout`](../difi_partition/struct.DifiPartition.html#method.same_layout),
+    /// and the container file must already be large enough for the new length.
+    /// The container file can be truncated to the new length afterwards.
+    pub fn resize_partition(&mut self, param: &DifiPartitionParam) -> Result<(), Error> {
+        if self.previous {
+            return make_error(Error::Unsupported);
+        }
+        self.partition.commit()?;
+
+        // The hash of the last block covers the data up to the length,
+        // so the block is written again to have its hash recalculated.
+        let old_len = self.partition.len();
+        let len = param.data_len;
+        let keep_len = std::cmp::min(old_len, len);
+        let tail_begin = keep_len - keep_len % param.ivfc_level4_block_len;
+        let mut tail = vec![0; len - tail_begin];
+        match self
+            .partition
+            .read(tail_begin, &mut tail[0..keep_len - tail_begin])
+        {
+            Ok(()) => {}
+            Err(Error::HashMismatch) => tail.iter_mut().for_each(|b| *b = 0),
+            Err(e) => return Err(e),
+        }
+
+        let (_, partition_len) = DifiPartition::calculate_size(param);
+        let mut header: DiffHeader = read_struct(self.header_file.as_ref(), 0)?;
+        let partition_offset = header.partition_offset as usize;
+        header.partition_size = partition_len as u64;
+        write_struct(self.header_file.as_ref(), 0, header)?;
+        DifiPartition::set_data_len(self.table_lower.as_ref(), len)?;
+        let partition = Rc::new(SubFile::new(
+            self.file.clone(),
+            partition_offset,
+            partition_len,
+        )?);
+        self.partition = Rc::new(DifiPartition::new(self.table_lower.clone(), partition)?);
+        self.partition.write(tail_begin, &tail)?;
+        self.commit()
     }
 
     pub fn partition(&self) -> &Rc<DifiPartition> {
@@ -205,6 +299,17 @@ impl Diff {
     pub fn unique_id(&self) -> u64 {
         self.unique_id
     }
+
+    pub fn set_unique_id(&mut self, unique_id: u64) -> Result<(), Error> {
+        if self.previous {
+            return make_error(Error::Unsupported);
+        }
+        self.header_file.write(0x54, &unique_id.to_le_bytes())?;
+        self.header_file.commit()?;
+        self.file.commit()?;
+        self.unique_id = unique_id;
+        Ok(())
+    }
 }
 #[cfg(test)]
 mod test {
@@ -242,6 +347,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn resize_partition() {
+        let signer = Box::new(SimpleSigner::new());
+        let key = [3; 16];
+        let param = |data_len| DifiPartitionParam {
+            dpfs_level2_block_len: 128,
+            dpfs_level3_block_len: 4096,
+            ivfc_level1_block_len: 512,
+            ivfc_level2_block_len: 512,
+            ivfc_level3_block_len: 4096,
+            ivfc_level4_block_len: 4096,
+            data_len,
+            external_ivfc_level4: true,
+        };
+        let parent = Rc::new(MemoryFile::new(vec![
+            0;
+            Diff::calculate_size(&param(0x1F00))
+        ]));
+        Diff::format(
+            parent.clone(),
+            Some((signer.clone(), key)),
+            &param(0x1801),
+            0,
+        )
+        .unwrap();
+        let mut diff = Diff::new(parent.clone(), Some((signer.clone(), key))).unwrap();
+        let init: Vec<u8> = (0..0x1801).map(|i| (i % 251) as u8).collect();
+        diff.partition().write(0, &init).unwrap();
+        diff.commit().unwrap();
+
+        let mut expected = init;
+        for &len in &[0x1F00, 0x1100, 0x1801] {
+            assert!(DifiPartition::same_layout(
+                &param(diff.partition().len()),
+                len
+            ));
+            diff.resize_partition(&param(len)).unwrap();
+            expected.resize(len, 0);
+            diff = Diff::new(parent.clone(), Some((signer.clone(), key))).unwrap();
+            let mut buf = vec![0xFF; len];
+            diff.partition().read(0, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+        }
+    }
+
     #[test]
     fn fuzz() {
         use rand::distributions::Standard;
@@ -276,6 +276,36 @@ impl DifiPartition {
         (info.descriptor_len, info.partition_len)
     }
 
+    /// Returns whether the data length of a partition formatted with `param` can be changed
+    /// to `data_len` by only changing the descriptor, with everything else at the same place.
+    /// Only the end of the partition moves, which requires the IVFC level 4 to be external.
+    pub fn same_layout(param: &DifiPartitionParam, data_len: usize) -> bool {
+        fn to_bytes<T: ByteStruct>(data: &T) -> Vec<u8> {
+            let mut buf = vec![0; T::BYTE_LEN];
+            data.write_bytes(&mut buf);
+            buf
+        }
+        let info = DifiPartition::calculate_info(param);
+        let mut new_info =
+            DifiPartition::calculate_info(&DifiPartitionParam { data_len, ..*param });
+        new_info.ivfc_descriptor.level4_size = info.ivfc_descriptor.level4_size;
+        param.external_ivfc_level4
+            && info.descriptor_len == new_info.descriptor_len
+            && to_bytes(&info.difi_header) == to_bytes(&new_info.difi_header)
+            && to_bytes(&info.ivfc_descriptor) == to_bytes(&new_info.ivfc_descriptor)
+            && to_bytes(&info.dpfs_descriptor) == to_bytes(&new_info.dpfs_descriptor)
+    }
+
+    /// Changes the data length in the descriptor. The layout must stay the same,
+    /// see [`same_layout`](#method.same_layout).
+    pub fn set_data_len(descriptor: &dyn RandomAccessFile, data_len: usize) -> Result<(), Error> {
+        let header: DifiHeader = read_struct(descriptor, 0)?;
+        let offset = header.ivfc_descriptor_offset as usize;
+        let mut ivfc: IvfcDescriptor = read_struct(descriptor, offset)?;
+        ivfc.level4_size = data_len as u64;
+        write_struct(descriptor, offset, ivfc)
+    }
+
     pub fn format(
         descriptor: &dyn RandomAccessFile,
         param: &DifiPartitionParam,
@@ -42,9 +42,11 @@ struct DisaHeader {
 
 /// DISA container format that contains one or two DIFI partitions.
 pub struct Disa {
+    file: Rc<dyn RandomAccessFile>,
     header_file: Rc<dyn RandomAccessFile>,
-    table_upper: Rc<DualFile>,
-    table_lower: Rc<IvfcLevel>,
+    table_upper: Rc<dyn RandomAccessFile>,
+    table_lower: Rc<dyn RandomAccessFile>,
+    previous: bool,
     partitions: Vec<Rc<DifiPartition>>,
 }
 
@@ -201,12 +203,33 @@ impl Disa {
 
         table.commit()?;
         header_file.commit()?;
-        Ok(())
+        file.commit()
     }
 
     pub fn new(
         file: Rc<dyn RandomAccessFile>,
         signer: Option<(Box<dyn Signer>, [u8; 16])>,
+    ) -> Result<Disa, Error> {
+        Disa::open(file, signer, false)
+    }
+
+    /// Opens the version of the container before the last commit, which is usually still intact
+    /// in the inactive copy of the table and the data.
+    ///
+    /// The inactive table can't be verified, as the header only holds the hash of the active one,
+    /// but the partition data are still verified against the hashes in it.
+    /// The container must not be written in this mode.
+    pub fn new_previous(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+    ) -> Result<Disa, Error> {
+        Disa::open(file, signer, true)
+    }
+
+    fn open(
+        file: Rc<dyn RandomAccessFile>,
+        signer: Option<(Box<dyn Signer>, [u8; 16])>,
+        previous: bool,
     ) -> Result<Disa, Error> {
         let header_file_bare = Rc::new(SubFile::new(file.clone(), 0x100, 0x100)?);
         let header_file: Rc<dyn RandomAccessFile> = match signer {
@@ -249,13 +272,19 @@ impl Disa {
             )?),
         ];
 
-        let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
-
-        let table_lower = Rc::new(IvfcLevel::new(
-            table_hash,
-            table_upper.clone(),
-            header.table_size as usize,
-        )?);
+        let (table_upper, table_lower): (Rc<dyn RandomAccessFile>, Rc<dyn RandomAccessFile>) =
+            if previous {
+                let table = DualFile::inactive(table_selector.as_ref(), &table_pair)?;
+                (table.clone(), table)
+            } else {
+                let table_upper = Rc::new(DualFile::new(table_selector, table_pair)?);
+                let table_lower = Rc::new(IvfcLevel::new(
+                    table_hash,
+                    table_upper.clone(),
+                    header.table_size as usize,
+                )?);
+                (table_upper, table_lower)
+            };
 
         let mut partitions = Vec::with_capacity(header.partition_count as usize);
         for i in 0..header.partition_count as usize {
@@ -275,20 +304,41 @@ impl Disa {
         }
 
         Ok(Disa {
+            file,
             header_file,
             table_upper,
             table_lower,
+            previous,
             partitions,
         })
     }
 
     pub fn commit(&self) -> Result<(), Error> {
+        if self.previous {
+            return make_error(Error::Unsupported);
+        }
         for partition in self.partitions.iter() {
             partition.commit()?;
         }
         self.table_lower.commit()?;
         self.table_upper.commit()?;
-        self.header_file.commit()
+        self.header_file.commit()?;
+        self.file.commit()
+    }
+
+    /// Makes the version opened by [`new_previous`](#method.new_previous) the current one,
+    /// by switching to the inactive table and updating its hash in the header.
+    /// The version that was current becomes the previous one.
+    pub fn restore_previous(&self) -> Result<(), Error> {
+        if !self.previous {
+            return make_error(Error::Unsupported);
+        }
+        let table_hash = Rc::new(SubFile::new(self.header_file.clone(), 0x6C, 0x20)?);
+        let table_len = self.table_upper.len();
+        IvfcLevel::rehash(table_hash, self.table_upper.clone(), table_len)?;
+        DualFile::switch(&SubFile::new(self.header_file.clone(), 0x68, 1)?)?;
+        self.header_file.commit()?;
+        self.file.commit()
     }
 
     pub fn partition_count(&self) -> usize {
@@ -1,5 +1,6 @@
 use crate::error::*;
 use crate::random_access_file::*;
+use log::*;
 use std::cell::Cell;
 use std::rc::Rc;
 
@@ -33,6 +34,31 @@ impl DualFile {
             len,
         })
     }
+
+    /// Returns the image that is not active, which holds the content before the last commit.
+    pub fn inactive(
+        selector: &dyn RandomAccessFile,
+        pair: &[Rc<dyn RandomAccessFile>; 2],
+    ) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        Ok(pair[1 - DualFile::read_selector(selector)?].clone())
+    }
+
+    /// Makes the inactive image the active one, without copying any data.
+    /// The image that was active becomes the inactive one.
+    pub fn switch(selector: &dyn RandomAccessFile) -> Result<(), Error> {
+        let select = DualFile::read_selector(selector)?;
+        selector.write(0, &[1 - select as u8])
+    }
+
+    fn read_selector(selector: &dyn RandomAccessFile) -> Result<usize, Error> {
+        let mut select = [0; 1];
+        selector.read(0, &mut select)?;
+        if select[0] > 1 {
+            error!("Unexpected selector {}", select[0]);
+            return make_error(Error::InvalidValue);
+        }
+        Ok(select[0] as usize)
+    }
 }
 
 impl RandomAccessFile for DualFile {
@@ -88,6 +114,7 @@ impl RandomAccessFile for DualFile {
 #[cfg(test)]
 mod test {
     use crate::dual_file::DualFile;
+    use crate::error::*;
     use crate::memory_file::MemoryFile;
     use crate::random_access_file::*;
     use std::rc::Rc;
@@ -123,4 +150,38 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn inactive() {
+        let selector = Rc::new(MemoryFile::new(vec![0; 1]));
+        let pair: [Rc<dyn RandomAccessFile>; 2] = [
+            Rc::new(MemoryFile::new(vec![1; 10])),
+            Rc::new(MemoryFile::new(vec![1; 10])),
+        ];
+        let dual_file = DualFile::new(selector.clone(), pair.clone()).unwrap();
+        dual_file.write(3, &[2; 4]).unwrap();
+        dual_file.commit().unwrap();
+
+        let mut buf = [0; 10];
+        DualFile::inactive(selector.as_ref(), &pair)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1; 10]);
+
+        DualFile::switch(selector.as_ref()).unwrap();
+        dual_file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 10]);
+        DualFile::inactive(selector.as_ref(), &pair)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 1, 1, 2, 2, 2, 2, 1, 1, 1]);
+
+        selector.write(0, &[2]).unwrap();
+        assert!(matches!(
+            DualFile::inactive(selector.as_ref(), &pair),
+            Err(Error::InvalidValue)
+        ));
+    }
 }
@@ -15,14 +15,19 @@ pub enum Error {
     AlreadyExist,
     DeletingRoot,
     SignatureMismatch,
+    #[deprecated(note = "missing keys are reported as `MissingKey`")]
     MissingBoot9,
     MissingSd,
     MissingNand,
     MissingGame,
     MissingPriv,
+    #[deprecated(note = "missing keys are reported as `MissingKey`")]
     MissingKeyY2F,
+    #[deprecated(note = "missing keys are reported as `MissingKey`")]
     MissingKeyX19,
+    #[deprecated(note = "missing keys are reported as `MissingKey`")]
     MissingKeyX1A,
+    #[deprecated(note = "missing keys are reported as `MissingKey`")]
     MissingOtp,
     BrokenSd,
     NotEmpty,
@@ -31,9 +36,12 @@ pub enum Error {
     BrokenOtp,
     Busy,
     BrokenGame,
+    TruncatedImage,
+    MissingKey(String),
 }
 
 impl fmt::Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IO(e) => write!(f, "IO error from host file system: {:?}", e),
@@ -72,6 +80,10 @@ impl fmt::Display for Error {
                 "The file or directory is currently used by other program"
             ),
             Error::BrokenGame => write!(f, "Provided game file is broken"),
+            Error::TruncatedImage => {
+                write!(f, "Trying to write beyond the end of a truncated image")
+            }
+            Error::MissingKey(name) => write!(f, "Missing key {}", name),
         }
     }
 }
@@ -85,6 +97,29 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// Converts to the closest `std::io::ErrorKind`, keeping the original error as the source.
+/// Errors caused by corrupted or uninitialized data, such as `HashMismatch`, become `InvalidData`.
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        use std::io::ErrorKind;
+        let kind = match e {
+            Error::IO(e) => return e,
+            Error::HashMismatch
+            | Error::OutOfBound
+            | Error::MagicMismatch
+            | Error::SizeMismatch
+            | Error::InvalidValue
+            | Error::BrokenFat
+            | Error::SignatureMismatch
+            | Error::UniqueIdMismatch => ErrorKind::InvalidData,
+            Error::NotFound => ErrorKind::NotFound,
+            Error::AlreadyExist => ErrorKind::AlreadyExists,
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
 pub(crate) fn make_error<T>(e: Error) -> Result<T, Error> {
     info!("Error thrown: {:?}", e);
     Err(e)
@@ -1,10 +1,13 @@
+use crate::byte_struct_common::*;
 use crate::diff::Diff;
-use crate::difi_partition::DifiPartitionParam;
+use crate::difi_partition::{DifiPartition, DifiPartitionParam};
 use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
 use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::memory_file::MemoryFile;
 use crate::misc::*;
+use crate::overlay_file_system::OverlayFileSystem;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
 use crate::sd_nand_common::*;
@@ -71,7 +74,7 @@ struct ExtHeader {
     mount_path: [[u8; 0x10]; 0x10],
 }
 
-#[derive(ByteStruct, Debug)]
+#[derive(ByteStruct, Clone, Copy, Debug)]
 #[byte_struct_le]
 struct Quota {
     magic: [u8; 4],
@@ -105,11 +108,281 @@ pub struct ExtDataFormatParam {
     pub file_buckets: usize,
 }
 
+/// Mount information kept in the header of the extdata metadata file.
+/// Formatting sets all of them to zero, but some system extdata have them set.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct ExtDataMountInfo {
+    /// The most recent action performed on the extdata.
+    pub action: u32,
+
+    /// The ID the extdata was most recently mounted with.
+    pub mount_id: u32,
+
+    /// The path the extdata was most recently mounted at, as 16 entries of 16 bytes each.
+    pub mount_path: [[u8; 0x10]; 0x10],
+}
+
+/// Space accounting of an extdata with a quota (i.e. extdata on NAND), kept in `Quota.dat`.
+/// All counts are in blocks.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct ExtDataQuota {
+    /// Size in bytes of a block.
+    pub block_len: usize,
+
+    /// Number of blocks the extdata can take.
+    pub max_block: usize,
+
+    /// Number of blocks not taken by the metadata file or data files.
+    pub free_block: usize,
+
+    /// Number of blocks that would be free without the data file most recently created or
+    /// deleted, i.e. the value of `free_block` before that creation or after that deletion.
+    /// After formatting, this is the number of blocks that would be free without the metadata file.
+    pub potential_free_block: usize,
+}
+
+impl From<&Quota> for ExtDataQuota {
+    fn from(quota: &Quota) -> ExtDataQuota {
+        ExtDataQuota {
+            block_len: quota.block_len as usize,
+            max_block: quota.max_block as usize,
+            free_block: quota.free_block as usize,
+            potential_free_block: quota.potential_free_block as usize,
+        }
+    }
+}
+
+struct ExtDataInfo {
+    block_len: usize,
+    param: DifiPartitionParam,
+    dir_hash_offset: usize,
+    file_hash_offset: usize,
+    fat_offset: usize,
+    data_block_count: usize,
+    data_offset: usize,
+    dir_table_len: usize,
+    file_table_len: usize,
+}
+
+/// Name of the directory in an extdata that holds a transaction being committed.
+const JOURNAL_DIR: &str = "Journal";
+
+/// Name of the directory in an extdata that holds the original of a file being resized,
+/// in the same format as [`JOURNAL_DIR`](constant.JOURNAL_DIR.html), so that replaying it
+/// rolls back an interrupted resize.
+const RESIZE_JOURNAL_DIR: &str = "ResizeJournal";
+
+fn ext_path(base_path: &[String], id: u64) -> Vec<String> {
+    let mut path = base_path.to_vec();
+    path.push(format!("{:08x}", id >> 32));
+    path.push(format!("{:08x}", id & 0xFFFF_FFFF));
+    path
+}
+
+/// Returns the layout of the data file of a file with length `len`.
+fn data_file_param(len: usize) -> DifiPartitionParam {
+    DifiPartitionParam {
+        dpfs_level2_block_len: 128,
+        dpfs_level3_block_len: 4096,
+        ivfc_level1_block_len: 512,
+        ivfc_level2_block_len: 512,
+        ivfc_level3_block_len: 4096,
+        ivfc_level4_block_len: 4096,
+        data_len: len,
+        external_ivfc_level4: true,
+    }
+}
+
+/// Returns the signer of the data file of the file with inode `ino`.
+fn data_file_signer(id: u64, ino: u32) -> Box<ExtSigner> {
+    let file_index = ino + 1;
+    Box::new(ExtSigner {
+        id,
+        sub_id: Some((u64::from(file_index / 126) << 32) | u64::from(file_index % 126)),
+    })
+}
+
+/// Returns the path of the data file of the file with inode `ino`.
+fn data_file_path(ext_path: &[String], ino: u32) -> Vec<String> {
+    let file_index = ino + 1;
+    let fid_high = format!("{:08x}", file_index / 126);
+    let fid_low = format!("{:08x}", file_index % 126);
+    join(ext_path, vec![fid_high.as_str(), fid_low.as_str()])
+}
+
+fn as_str_path(path: &[String]) -> Vec<&str> {
+    path.iter().map(|s| s.as_str()).collect()
+}
+
+fn join<'a>(path: &[String], sub: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    path.iter()
+        .cloned()
+        .chain(sub.into_iter().map(|s| s.to_owned()))
+        .collect()
+}
+
+fn copy_to(
+    src: &dyn SdNandFileSystem,
+    src_path: &[String],
+    dst: &dyn SdNandFileSystem,
+    dst_path: &[String],
+) -> Result<(), Error> {
+    let src = src.open(&as_str_path(src_path), false)?;
+    dst.create(&as_str_path(dst_path), src.len())?;
+    let dst = dst.open(&as_str_path(dst_path), true)?;
+    copy_file(src.as_ref(), dst.as_ref())?;
+    dst.commit()
+}
+
+/// Copies the entries and free slots of a metadata table into a bigger one,
+/// keeping the entry capacity of `dst`.
+fn copy_table(
+    src: &dyn RandomAccessFile,
+    dst: &dyn RandomAccessFile,
+    entry_len: usize,
+) -> Result<(), Error> {
+    let max_entry_count: U32le = read_struct(dst, 4)?;
+    let entry_count = read_struct::<U32le>(src, 0)?.v as usize;
+    if entry_count > max_entry_count.v as usize {
+        return make_error(Error::NoSpace);
+    }
+    let mut buf = vec![0; entry_count * entry_len];
+    src.read(0, &mut buf)?;
+    dst.write(0, &buf)?;
+    write_struct(dst, 4, max_entry_count)
+}
+
+/// Copies all changes made in `overlay` to the journal directory of the extdata at `ext_path`.
+fn write_journal(
+    sd_nand: &dyn SdNandFileSystem,
+    ext_path: &[String],
+    overlay: &OverlayFileSystem,
+) -> Result<(), Error> {
+    let delta = overlay.delta()?;
+    record_journal(
+        sd_nand,
+        ext_path,
+        JOURNAL_DIR,
+        overlay,
+        &delta.removed,
+        &delta.changed,
+    )
+}
+
+/// Records in the directory `journal_dir` of the extdata at `ext_path` that the paths in
+/// `removed` are to be removed, and the paths in `changed` are to be replaced by their
+/// content in `src`.
+///
+/// The journal consists of copies of created or modified files, and a manifest listing all
+/// changes. The manifest is written last and ends with a marker,
+/// so an incomplete journal can be recognized.
+fn record_journal(
+    sd_nand: &dyn SdNandFileSystem,
+    ext_path: &[String],
+    journal_dir: &str,
+    src: &dyn SdNandFileSystem,
+    removed: &[Vec<String>],
+    changed: &[Vec<String>],
+) -> Result<(), Error> {
+    let journal_path = join(ext_path, vec![journal_dir]);
+    sd_nand.remove_dir(&as_str_path(&journal_path))?;
+
+    let mut manifest = String::new();
+    for path in removed.iter() {
+        if !path.starts_with(ext_path) {
+            return make_error(Error::InvalidValue);
+        }
+        manifest += &format!("remove {}\n", path[ext_path.len()..].join("/"));
+    }
+    for path in changed.iter() {
+        if !path.starts_with(ext_path) {
+            return make_error(Error::InvalidValue);
+        }
+        let sub_path = as_str_path(&path[ext_path.len()..]);
+        let staged_path = journal_data_path(&journal_path, &sub_path);
+        copy_to(src, path, sd_nand, &staged_path)?;
+        manifest += &format!("change {}\n", sub_path.join("/"));
+    }
+    manifest += "end\n";
+
+    let manifest_path = join(&journal_path, vec!["Manifest"]);
+    sd_nand.create(&as_str_path(&manifest_path), manifest.len())?;
+    let manifest_file = sd_nand.open(&as_str_path(&manifest_path), true)?;
+    manifest_file.write(0, manifest.as_bytes())?;
+    manifest_file.commit()
+}
+
+/// Returns the path in the journal at `journal_path` where the content of `sub_path` is kept.
+fn journal_data_path(journal_path: &[String], sub_path: &[&str]) -> Vec<String> {
+    join(
+        journal_path,
+        std::iter::once("Data").chain(sub_path.iter().cloned()),
+    )
+}
+
+/// Returns the manifest of the journal at `journal_path` if the journal is complete.
+fn read_journal_manifest(
+    sd_nand: &dyn SdNandFileSystem,
+    journal_path: &[String],
+) -> Result<Option<String>, Error> {
+    let manifest_path = join(journal_path, vec!["Manifest"]);
+    if !sd_nand.is_file(&as_str_path(&manifest_path)) {
+        return Ok(None);
+    }
+    let file = sd_nand.open(&as_str_path(&manifest_path), false)?;
+    let mut buf = vec![0; file.len()];
+    file.read(0, &mut buf)?;
+    Ok(String::from_utf8(buf)
+        .ok()
+        .filter(|manifest| manifest.lines().last() == Some("end")))
+}
+
+/// Applies the journal in the directory `journal_dir` of the extdata at `ext_path`
+/// if it is complete, and removes it. Applying a journal more than once has the same effect
+/// as applying it once, so this can be repeated if interrupted.
+fn replay_journal(
+    sd_nand: &dyn SdNandFileSystem,
+    ext_path: &[String],
+    journal_dir: &str,
+) -> Result<(), Error> {
+    let journal_path = join(ext_path, vec![journal_dir]);
+    if !sd_nand.is_dir(&as_str_path(&journal_path)) {
+        return Ok(());
+    }
+
+    match read_journal_manifest(sd_nand, &journal_path)? {
+        Some(manifest) => {
+            info!("Replaying extdata journal");
+            for line in manifest.lines() {
+                if let Some(sub_path) = line.strip_prefix("remove ") {
+                    let path = join(ext_path, sub_path.split('/'));
+                    let path = as_str_path(&path);
+                    if sd_nand.is_file(&path) {
+                        sd_nand.remove(&path)?;
+                    } else if sd_nand.is_dir(&path) {
+                        sd_nand.remove_dir(&path)?;
+                    }
+                } else if let Some(sub_path) = line.strip_prefix("change ") {
+                    let sub_path: Vec<&str> = sub_path.split('/').collect();
+                    let staged_path = journal_data_path(&journal_path, &sub_path);
+                    let path = join(ext_path, sub_path);
+                    copy_to(sd_nand, &staged_path, sd_nand, &path)?;
+                }
+            }
+        }
+        None => warn!("Discarding incomplete extdata journal"),
+    }
+
+    sd_nand.remove_dir(&as_str_path(&journal_path))
+}
+
 struct ExtDataInner {
     sd_nand: Rc<dyn SdNandFileSystem>,
     base_path: Vec<String>,
     id: u64,
+    fat: Rc<Fat>,
     fs: Rc<FsMeta>,
+    param: ExtDataFormatParam,
     meta_file: Diff,
     quota_file: Option<Diff>,
     key: [u8; 16],
@@ -122,28 +395,7 @@ pub struct ExtData {
 }
 
 impl ExtData {
-    pub(crate) fn format(
-        sd_nand: &dyn SdNandFileSystem,
-        base_path: &[&str],
-        id: u64,
-        key: [u8; 16],
-        quota: Option<u32>,
-        param: &ExtDataFormatParam,
-    ) -> Result<(), Error> {
-        let id_high = format!("{:08x}", id >> 32);
-        let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
-        let ext_path: Vec<&str> = base_path
-            .iter()
-            .cloned()
-            .chain([id_high.as_str(), id_low.as_str()].iter().cloned())
-            .collect();
-
-        sd_nand.remove_dir(&ext_path)?;
-
-        let mut meta_path = ext_path.clone();
-        meta_path.push("00000000");
-        meta_path.push("00000001");
-
+    fn calculate_info(param: &ExtDataFormatParam) -> ExtDataInfo {
         let block_len = 4096;
 
         let fs_info_offset = ExtHeader::BYTE_LEN;
@@ -159,7 +411,7 @@ impl ExtData {
         let data_offset = align_up(fat_offset + fat_len, block_len);
         let partition_end = data_offset + data_len;
 
-        let diff_param = DifiPartitionParam {
+        let param = DifiPartitionParam {
             dpfs_level2_block_len: 128,
             dpfs_level3_block_len: 4096,
             ivfc_level1_block_len: 512,
@@ -170,11 +422,46 @@ impl ExtData {
             external_ivfc_level4: false,
         };
 
-        let meta_diff_len = Diff::calculate_size(&diff_param);
+        ExtDataInfo {
+            block_len,
+            param,
+            dir_hash_offset,
+            file_hash_offset,
+            fat_offset,
+            data_block_count,
+            data_offset,
+            dir_table_len,
+            file_table_len,
+        }
+    }
+
+    pub(crate) fn format(
+        sd_nand: &dyn SdNandFileSystem,
+        base_path: &[&str],
+        id: u64,
+        key: [u8; 16],
+        quota: Option<u32>,
+        param: &ExtDataFormatParam,
+    ) -> Result<(), Error> {
+        let id_high = format!("{:08x}", id >> 32);
+        let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
+        let ext_path: Vec<&str> = base_path
+            .iter()
+            .cloned()
+            .chain([id_high.as_str(), id_low.as_str()].iter().cloned())
+            .collect();
+
+        sd_nand.remove_dir(&ext_path)?;
+
+        let mut meta_path = ext_path.clone();
+        meta_path.push("00000000");
+        meta_path.push("00000001");
+
+        let meta_diff_len = Diff::calculate_size(&ExtData::calculate_info(param).param);
 
         if let Some(capacity) = quota {
             let meta_block = (divide_up(meta_diff_len, 0x1000)) as u32;
-            if meta_block > capacity - 2 {
+            if meta_block + 2 > capacity {
                 return make_error(Error::NoSpace);
             }
 
@@ -231,6 +518,19 @@ impl ExtData {
 
         sd_nand.create(&meta_path, meta_diff_len)?;
         let meta_raw = sd_nand.open(&meta_path, true)?;
+        ExtData::format_meta(meta_raw, id, key, param, 0x01234567_89ABCDEF)
+    }
+
+    /// Formats the metadata file `00000001`, which holds the directory and file tables.
+    fn format_meta(
+        meta_raw: Rc<dyn RandomAccessFile>,
+        id: u64,
+        key: [u8; 16],
+        param: &ExtDataFormatParam,
+        unique_id: u64,
+    ) -> Result<(), Error> {
+        let info = ExtData::calculate_info(param);
+        let block_len = info.block_len;
         let signer = Box::new(ExtSigner {
             id,
             sub_id: Some(1),
@@ -238,42 +538,42 @@ impl ExtData {
         Diff::format(
             meta_raw.clone(),
             Some((signer.clone(), key)),
-            &diff_param,
-            0x01234567_89ABCDEF,
+            &info.param,
+            unique_id,
         )?;
         let meta_file = Diff::new(meta_raw, Some((signer, key)))?;
 
         let dir_hash = Rc::new(SubFile::new(
             meta_file.partition().clone(),
-            dir_hash_offset,
+            info.dir_hash_offset,
             param.dir_buckets * 4,
         )?);
 
         let file_hash = Rc::new(SubFile::new(
             meta_file.partition().clone(),
-            file_hash_offset,
+            info.file_hash_offset,
             param.file_buckets * 4,
         )?);
 
         let fat_table = Rc::new(SubFile::new(
             meta_file.partition().clone(),
-            fat_offset,
-            (data_block_count + 1) * 8,
+            info.fat_offset,
+            (info.data_block_count + 1) * 8,
         )?);
 
         Fat::format(fat_table.as_ref())?;
 
         let data = Rc::new(SubFile::new(
             meta_file.partition().clone(),
-            data_offset,
-            data_block_count * block_len,
+            info.data_offset,
+            info.data_block_count * block_len,
         )?);
 
         let fat = Fat::new(fat_table, data, block_len)?;
         let (dir_table, dir_table_block_index) =
-            FatFile::create(fat.clone(), divide_up(dir_table_len, block_len))?;
+            FatFile::create(fat.clone(), divide_up(info.dir_table_len, block_len))?;
         let (file_table, file_table_block_index) =
-            FatFile::create(fat, divide_up(file_table_len, block_len))?;
+            FatFile::create(fat, divide_up(info.file_table_len, block_len))?;
         let dir_table_combo = OffsetOrFatFile {
             block_index: dir_table_block_index as u32,
             block_count: (dir_table.len() / block_len) as u32,
@@ -312,17 +612,17 @@ impl ExtData {
         let fs_info = FsInfo {
             unknown: 0,
             block_len: block_len as u32,
-            dir_hash_offset: dir_hash_offset as u64,
+            dir_hash_offset: info.dir_hash_offset as u64,
             dir_buckets: param.dir_buckets as u32,
             p0: 0,
-            file_hash_offset: file_hash_offset as u64,
+            file_hash_offset: info.file_hash_offset as u64,
             file_buckets: param.file_buckets as u32,
             p1: 0,
-            fat_offset: fat_offset as u64,
-            fat_size: data_block_count as u32,
+            fat_offset: info.fat_offset as u64,
+            fat_size: info.data_block_count as u32,
             p2: 0,
-            data_offset: data_offset as u64,
-            data_block_count: data_block_count as u32,
+            data_offset: info.data_offset as u64,
+            data_block_count: info.data_block_count as u32,
             p3: 0,
             dir_table: dir_table_combo,
             max_dir: param.max_dir as u32,
@@ -333,11 +633,15 @@ impl ExtData {
         };
 
         write_struct(meta_file.partition().as_ref(), ExtHeader::BYTE_LEN, fs_info)?;
-        meta_file.commit()?;
-
-        Ok(())
+        meta_file.commit()
     }
 
+    /// Opens an extdata. If a transaction was interrupted while being committed,
+    /// it is completed first, or discarded if it had not been fully recorded.
+    /// If a file was interrupted while being resized, it is restored to the original.
+    ///
+    /// When opened read-only, the extdata is left untouched, and `Error::Busy` is returned
+    /// if there is a complete journal that still needs to be applied.
     pub(crate) fn new(
         sd_nand: Rc<dyn SdNandFileSystem>,
         base_path: &[&str],
@@ -346,6 +650,22 @@ impl ExtData {
         has_quota: bool,
         write: bool,
     ) -> Result<ExtData, Error> {
+        let owned_base_path: Vec<String> = base_path.iter().map(|&s| s.to_string()).collect();
+        let owned_ext_path = ext_path(&owned_base_path, id);
+        for journal_dir in [JOURNAL_DIR, RESIZE_JOURNAL_DIR].iter() {
+            if write {
+                replay_journal(sd_nand.as_ref(), &owned_ext_path, journal_dir)?;
+            } else if read_journal_manifest(
+                sd_nand.as_ref(),
+                &join(&owned_ext_path, vec![*journal_dir]),
+            )?
+            .is_some()
+            {
+                error!("Extdata has a pending journal that can only be applied when writable");
+                return make_error(Error::Busy);
+            }
+        }
+
         let id_high = format!("{:08x}", id >> 32);
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
         let ext_path: Vec<&str> = base_path
@@ -431,17 +751,28 @@ impl ExtData {
             fs_info.dir_table.block_index as usize,
         )?);
 
-        let file_table: Rc<dyn RandomAccessFile> =
-            Rc::new(FatFile::open(fat, fs_info.file_table.block_index as usize)?);
+        let file_table: Rc<dyn RandomAccessFile> = Rc::new(FatFile::open(
+            fat.clone(),
+            fs_info.file_table.block_index as usize,
+        )?);
 
         let fs = FsMeta::new(dir_hash, dir_table, file_hash, file_table)?;
 
+        let param = ExtDataFormatParam {
+            max_dir: fs_info.max_dir as usize,
+            dir_buckets: fs_info.dir_buckets as usize,
+            max_file: fs_info.max_file as usize,
+            file_buckets: fs_info.file_buckets as usize,
+        };
+
         Ok(ExtData {
             center: Rc::new(ExtDataInner {
                 sd_nand,
-                base_path: base_path.iter().map(|&s| s.to_string()).collect(),
+                base_path: owned_base_path,
                 id,
+                fat,
                 fs,
+                param,
                 meta_file,
                 quota_file,
                 key,
@@ -449,6 +780,415 @@ impl ExtData {
             }),
         })
     }
+
+    /// Starts a transaction. All changes made through
+    /// [`ExtDataTransaction::ext_data`](struct.ExtDataTransaction.html#method.ext_data),
+    /// including creating, resizing, writing and deleting files, are kept aside from the
+    /// extdata until [`ExtDataTransaction::commit`](struct.ExtDataTransaction.html#method.commit)
+    /// applies all of them.
+    ///
+    /// The extdata must be opened for writing, and all files and directories opened from it
+    /// must be dropped before this.
+    pub fn begin_transaction(self) -> Result<ExtDataTransaction, Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        let sd_nand = self.center.sd_nand.clone();
+        let base_path = self.center.base_path.clone();
+        let id = self.center.id;
+        let key = self.center.key;
+        let has_quota = self.center.quota_file.is_some();
+        std::mem::drop(self); // close all files first
+
+        let overlay = Rc::new(OverlayFileSystem::new(sd_nand.clone()));
+        let ext_data = ExtData::new(
+            overlay.clone(),
+            &as_str_path(&base_path),
+            id,
+            key,
+            has_quota,
+            true,
+        )?;
+        Ok(ExtDataTransaction {
+            sd_nand,
+            base_path,
+            id,
+            key,
+            has_quota,
+            overlay,
+            ext_data,
+        })
+    }
+
+    /// Returns the parameters the extdata was formatted with.
+    pub fn format_param(&self) -> ExtDataFormatParam {
+        self.center.param
+    }
+
+    /// Returns the mount information in the header of the extdata.
+    pub fn mount_info(&self) -> Result<ExtDataMountInfo, Error> {
+        let header: ExtHeader = read_struct(self.center.meta_file.partition().as_ref(), 0)?;
+        Ok(ExtDataMountInfo {
+            action: header.action,
+            mount_id: header.mount_id,
+            mount_path: header.mount_path,
+        })
+    }
+
+    /// Changes the mount information in the header of the extdata.
+    /// The change takes effect on the next [`commit`](#method.commit).
+    pub fn set_mount_info(&self, info: &ExtDataMountInfo) -> Result<(), Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        let partition = self.center.meta_file.partition().as_ref();
+        let mut header: ExtHeader = read_struct(partition, 0)?;
+        header.action = info.action;
+        header.mount_id = info.mount_id;
+        header.mount_path = info.mount_path;
+        write_struct(partition, 0, header)
+    }
+
+    /// Returns the quota of the extdata, or `None` if the extdata has no quota.
+    pub fn quota(&self) -> Result<Option<ExtDataQuota>, Error> {
+        self.center
+            .quota_file
+            .as_ref()
+            .map(|quota_file| {
+                let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+                Ok(ExtDataQuota::from(&quota))
+            })
+            .transpose()
+    }
+
+    /// Changes the number of blocks the extdata can take, and returns the new quota.
+    ///
+    /// Returns `Error::NoSpace` if the blocks in use don't fit the new capacity,
+    /// `Error::InvalidValue` if the quota has more free blocks than its capacity,
+    /// which [`repair_quota`](#method.repair_quota) can fix,
+    /// or `Error::Unsupported` if the extdata has no quota or is not opened for writing.
+    pub fn set_quota_capacity(&self, capacity: u32) -> Result<ExtDataQuota, Error> {
+        self.update_quota(|quota| {
+            let used = match quota.max_block.checked_sub(quota.free_block) {
+                Some(used) => used,
+                None => {
+                    error!(
+                        "Quota has free_block={} beyond max_block={}",
+                        quota.free_block, quota.max_block
+                    );
+                    return make_error(Error::InvalidValue);
+                }
+            };
+            if used > capacity {
+                return make_error(Error::NoSpace);
+            }
+            quota.max_block = capacity;
+            quota.free_block = capacity - used;
+            Ok(())
+        })
+    }
+
+    /// Recalculates the free blocks in the quota from the actual sizes of the metadata file
+    /// and all data files, and returns the new quota. This fixes counters that drifted from
+    /// the content, for example after an interrupted operation.
+    ///
+    /// Returns `Error::NoSpace` if the files take more than the capacity,
+    /// or `Error::Unsupported` if the extdata has no quota or is not opened for writing.
+    pub fn repair_quota(&self) -> Result<ExtDataQuota, Error> {
+        let ext_path = ext_path(&self.center.base_path, self.center.id);
+        // The 2 blocks might come from directory block in FAT16, as in format
+        let mut used = 2 + divide_up(self.center.meta_file.parent_len(), 0x1000);
+        let mut dirs = vec![DirMeta::open_ino(self.center.fs.clone(), 1)?];
+        while let Some(dir) = dirs.pop() {
+            for (name, _) in dir.list_sub_dir()? {
+                dirs.push(dir.open_sub_dir(name)?);
+            }
+            for (_, ino) in dir.list_sub_file()? {
+                let path = data_file_path(&ext_path, ino);
+                let path = as_str_path(&path);
+                if self.center.sd_nand.is_file(&path) {
+                    let len = self.center.sd_nand.open(&path, false)?.len();
+                    used += divide_up(len, 0x1000);
+                }
+            }
+        }
+
+        self.update_quota(|quota| {
+            if used > quota.max_block as usize {
+                return make_error(Error::NoSpace);
+            }
+            quota.free_block = quota.max_block - used as u32;
+            Ok(())
+        })
+    }
+
+    fn update_quota(
+        &self,
+        f: impl FnOnce(&mut Quota) -> Result<(), Error>,
+    ) -> Result<ExtDataQuota, Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        let quota_file = self.center.quota_file.as_ref().ok_or(Error::Unsupported)?;
+        let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+        f(&mut quota)?;
+        let result = ExtDataQuota::from(&quota);
+        write_struct(quota_file.partition().as_ref(), 0, quota)?;
+        quota_file.commit()?;
+        Ok(result)
+    }
+
+    /// Rebuilds the directory and file hash tables with the specified bucket counts.
+    /// [`Stat::dir_hash`](../file_system/struct.Stat.html#structfield.dir_hash) and
+    /// [`Stat::file_hash`](../file_system/struct.Stat.html#structfield.file_hash)
+    /// show how entries are distributed over the current buckets.
+    ///
+    /// See [`grow_tables`](#method.grow_tables) for how the metadata file is rewritten.
+    pub fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        if dir_buckets == 0 || file_buckets == 0 {
+            return make_error(Error::InvalidValue);
+        }
+        let param = ExtDataFormatParam {
+            dir_buckets,
+            file_buckets,
+            ..self.center.param
+        };
+        self.rebuild_meta(&param)
+    }
+
+    /// Makes room for up to `max_dir` directories and `max_file` files,
+    /// which can't be less than the current capacity.
+    ///
+    /// The metadata file is rewritten with bigger tables, and all entries and free slots are
+    /// copied across, keeping their inodes. Data files are named after the inodes, so they are
+    /// left untouched along with their unique IDs. The new metadata file is applied through the
+    /// same journal as [`ExtDataTransaction::commit`](struct.ExtDataTransaction.html#method.commit).
+    ///
+    /// The extdata must be opened for writing, and all files and directories opened from it
+    /// must be dropped before this.
+    pub fn grow_tables(self, max_dir: usize, max_file: usize) -> Result<(), Error> {
+        if max_dir < self.center.param.max_dir || max_file < self.center.param.max_file {
+            return make_error(Error::InvalidValue);
+        }
+        let param = ExtDataFormatParam {
+            max_dir,
+            max_file,
+            ..self.center.param
+        };
+        self.rebuild_meta(&param)
+    }
+
+    /// Rewrites the metadata file with the layout of `param`, copying all entries.
+    fn rebuild_meta(self, param: &ExtDataFormatParam) -> Result<(), Error> {
+        if !self.center.write {
+            return make_error(Error::Unsupported);
+        }
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+
+        let meta_diff_len = Diff::calculate_size(&ExtData::calculate_info(param).param);
+        let ext_path = ext_path(&self.center.base_path, self.center.id);
+        let overlay = OverlayFileSystem::new(self.center.sd_nand.clone());
+
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            let old_block = divide_up(self.center.meta_file.parent_len(), 0x1000) as u32;
+            let new_block = divide_up(meta_diff_len, 0x1000) as u32;
+            if new_block > quota.free_block + old_block {
+                return make_error(Error::NoSpace);
+            }
+            quota.mount_id = 1;
+            quota.mount_len = meta_diff_len as u64;
+            quota.free_block = quota.free_block + old_block - new_block;
+            // As after formatting, without the recreated metadata file
+            quota.potential_free_block = quota.free_block + new_block;
+
+            let quota_path = join(&ext_path, vec!["Quota.dat"]);
+            copy_to(
+                self.center.sd_nand.as_ref(),
+                &quota_path,
+                &overlay,
+                &quota_path,
+            )?;
+            let quota_file = Diff::new(
+                overlay.open(&as_str_path(&quota_path), true)?,
+                Some((
+                    Box::new(ExtSigner {
+                        id: self.center.id,
+                        sub_id: None,
+                    }),
+                    self.center.key,
+                )),
+            )?;
+            write_struct(quota_file.partition().as_ref(), 0, quota)?;
+            quota_file.commit()?;
+        }
+
+        let image = Rc::new(MemoryFile::new(vec![0; meta_diff_len]));
+        ExtData::format_meta(
+            image.clone(),
+            self.center.id,
+            self.center.key,
+            param,
+            self.center.meta_file.unique_id(),
+        )?;
+        let meta_file = Diff::new(
+            image.clone(),
+            Some((
+                Box::new(ExtSigner {
+                    id: self.center.id,
+                    sub_id: Some(1),
+                }),
+                self.center.key,
+            )),
+        )?;
+        let partition = meta_file.partition();
+
+        // Keep the rest of the header, such as the mount path
+        let old = self.center.meta_file.partition();
+        let mut header: ExtHeader = read_struct(old.as_ref(), 0)?;
+        let old_fs_info: FsInfo = read_struct(old.as_ref(), header.fs_info_offset as usize)?;
+        let new_header: ExtHeader = read_struct(partition.as_ref(), 0)?;
+        let fs_info: FsInfo = read_struct(partition.as_ref(), new_header.fs_info_offset as usize)?;
+        header.fs_info_offset = new_header.fs_info_offset;
+        header.image_size = new_header.image_size;
+        write_struct(partition.as_ref(), 0, header)?;
+
+        let fat = Fat::new(
+            Rc::new(SubFile::new(
+                partition.clone(),
+                fs_info.fat_offset as usize,
+                (fs_info.fat_size + 1) as usize * 8,
+            )?),
+            Rc::new(SubFile::new(
+                partition.clone(),
+                fs_info.data_offset as usize,
+                (fs_info.data_block_count * fs_info.block_len) as usize,
+            )?),
+            fs_info.block_len as usize,
+        )?;
+        let dir_table = Rc::new(FatFile::open(
+            fat.clone(),
+            fs_info.dir_table.block_index as usize,
+        )?);
+        let file_table = Rc::new(FatFile::open(fat, fs_info.file_table.block_index as usize)?);
+        copy_table(
+            &FatFile::open(
+                self.center.fat.clone(),
+                old_fs_info.dir_table.block_index as usize,
+            )?,
+            dir_table.as_ref(),
+            SaveExtKey::BYTE_LEN + SaveExtDir::BYTE_LEN + 4,
+        )?;
+        copy_table(
+            &FatFile::open(
+                self.center.fat.clone(),
+                old_fs_info.file_table.block_index as usize,
+            )?,
+            file_table.as_ref(),
+            SaveExtKey::BYTE_LEN + ExtFile::BYTE_LEN + 4,
+        )?;
+
+        FsMeta::new(
+            Rc::new(SubFile::new(
+                partition.clone(),
+                fs_info.dir_hash_offset as usize,
+                fs_info.dir_buckets as usize * 4,
+            )?),
+            dir_table,
+            Rc::new(SubFile::new(
+                partition.clone(),
+                fs_info.file_hash_offset as usize,
+                fs_info.file_buckets as usize * 4,
+            )?),
+            file_table,
+        )?
+        .rehash()?;
+        meta_file.commit()?;
+        std::mem::drop(meta_file);
+
+        let meta_path = join(&ext_path, vec!["00000000", "00000001"]);
+        overlay.create(&as_str_path(&meta_path), meta_diff_len)?;
+        let meta_raw = overlay.open(&as_str_path(&meta_path), true)?;
+        copy_file(image.as_ref(), meta_raw.as_ref())?;
+        meta_raw.commit()?;
+        std::mem::drop(meta_raw);
+
+        let sd_nand = self.center.sd_nand.clone();
+        std::mem::drop(self); // close all files first
+        write_journal(sd_nand.as_ref(), &ext_path, &overlay)?;
+        std::mem::drop(overlay);
+        replay_journal(sd_nand.as_ref(), &ext_path, JOURNAL_DIR)
+    }
+}
+
+/// A group of changes to an extdata that are applied all together or not at all.
+///
+/// Changes are kept in memory until committed. On commit, they are first recorded in a journal
+/// next to the extdata files, and then applied. If the program stops halfway, the journal is
+/// applied or discarded the next time the extdata is opened.
+pub struct ExtDataTransaction {
+    sd_nand: Rc<dyn SdNandFileSystem>,
+    base_path: Vec<String>,
+    id: u64,
+    key: [u8; 16],
+    has_quota: bool,
+    overlay: Rc<OverlayFileSystem>,
+    ext_data: ExtData,
+}
+
+impl ExtDataTransaction {
+    /// Returns the extdata to make changes on. Its `commit` only makes changes
+    /// visible within this transaction.
+    pub fn ext_data(&self) -> &ExtData {
+        &self.ext_data
+    }
+
+    /// Applies all changes made in this transaction to the extdata, and reopens it.
+    ///
+    /// All files and directories opened from this transaction must be dropped before this,
+    /// otherwise `Error::Busy` is returned and all changes are discarded.
+    /// Changes to files not committed by `File::commit` are not included.
+    pub fn commit(self) -> Result<ExtData, Error> {
+        let ExtDataTransaction {
+            sd_nand,
+            base_path,
+            id,
+            key,
+            has_quota,
+            overlay,
+            ext_data,
+        } = self;
+        ext_data.commit()?;
+        if Rc::strong_count(&ext_data.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        std::mem::drop(ext_data);
+
+        let ext_path = ext_path(&base_path, id);
+        write_journal(sd_nand.as_ref(), &ext_path, &overlay)?;
+        std::mem::drop(overlay);
+        replay_journal(sd_nand.as_ref(), &ext_path, JOURNAL_DIR)?;
+
+        ExtData::new(sd_nand, &as_str_path(&base_path), id, key, has_quota, true)
+    }
+
+    /// Discards all changes made in this transaction, and reopens the extdata.
+    pub fn rollback(self) -> Result<ExtData, Error> {
+        let ExtDataTransaction {
+            sd_nand,
+            base_path,
+            id,
+            key,
+            has_quota,
+            ..
+        } = self;
+        ExtData::new(sd_nand, &as_str_path(&base_path), id, key, has_quota, true)
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for extdata file.
@@ -485,16 +1225,7 @@ impl File {
         let mut param = None;
         if let Some((len, _)) = new {
             if len != 0 {
-                param = Some(DifiPartitionParam {
-                    dpfs_level2_block_len: 128,
-                    dpfs_level3_block_len: 4096,
-                    ivfc_level1_block_len: 512,
-                    ivfc_level2_block_len: 512,
-                    ivfc_level3_block_len: 4096,
-                    ivfc_level4_block_len: 4096,
-                    data_len: len,
-                    external_ivfc_level4: true,
-                });
+                param = Some(data_file_param(len));
 
                 let physical_len = Diff::calculate_size(param.as_ref().unwrap());
 
@@ -516,10 +1247,7 @@ impl File {
             }
         }
         let file = center.sd_nand.open(&path, center.write).ok();
-        let signer = Box::new(ExtSigner {
-            id: center.id,
-            sub_id: Some((u64::from(fid_high) << 32) | u64::from(fid_low)),
-        });
+        let signer = data_file_signer(center.id, meta.get_ino());
 
         if let Some((_, unique_id)) = new {
             if let Some(file) = file.as_ref() {
@@ -583,6 +1311,148 @@ impl File {
 
         Ok(())
     }
+
+    /// Changes the length of the data file in place. The data file must keep the same layout
+    /// and the same number of blocks with the new length.
+    fn resize_in_place(&mut self, len: usize) -> Result<(), Error> {
+        let param = data_file_param(len);
+        let physical_len = Diff::calculate_size(&param);
+        let old_physical_len = self.data.as_ref().unwrap().parent_len();
+        let ext_path = ext_path(&self.center.base_path, self.center.id);
+        let path = data_file_path(&ext_path, self.meta.get_ino());
+        let path = as_str_path(&path);
+        let sd_nand = self.center.sd_nand.clone();
+
+        // The data file is extended before the header takes the new length, and truncated after
+        // that, so that it stays valid if interrupted.
+        if physical_len > old_physical_len {
+            self.commit()?;
+            self.data = None; // close the file first
+            sd_nand.resize(&path, physical_len)?;
+            self.data = Some(self.open_data(&path)?);
+        }
+        self.data.as_mut().unwrap().resize_partition(&param)?;
+        if physical_len < old_physical_len {
+            self.data = None;
+            sd_nand.resize(&path, physical_len)?;
+            self.data = Some(self.open_data(&path)?);
+        }
+        Ok(())
+    }
+
+    fn open_data(&self, path: &[&str]) -> Result<Diff, Error> {
+        Diff::new(
+            self.center.sd_nand.open(path, true)?,
+            Some((
+                data_file_signer(self.center.id, self.meta.get_ino()),
+                self.center.key,
+            )),
+        )
+    }
+
+    /// Recreates the data file with length `len`, and copies the content
+    /// from the copy of the old data file with length `old_len` at `backup_path`.
+    fn copy_from_backup(
+        &mut self,
+        backup_path: &[String],
+        old_len: usize,
+        len: usize,
+    ) -> Result<(), Error> {
+        self.delete_data()?;
+
+        let ino = self.meta.get_ino();
+        let unique_id = self.meta.get_info()?.unique_id;
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        *self = File::from_meta(self.center.clone(), meta, Some((len, unique_id)))?;
+
+        let copy_len = std::cmp::min(old_len, len);
+        let old = if copy_len != 0 {
+            let file = self.center.sd_nand.open(&as_str_path(backup_path), false)?;
+            Some(Diff::new(
+                file,
+                Some((data_file_signer(self.center.id, ino), self.center.key)),
+            )?)
+        } else {
+            None
+        };
+
+        let mut buf = vec![0; 0x1000];
+        for pos in (0..len).step_by(buf.len()) {
+            let end = std::cmp::min(pos + buf.len(), len);
+            let buf = &mut buf[0..end - pos];
+            if pos < copy_len {
+                let old = old.as_ref().unwrap();
+                match old
+                    .partition()
+                    .read(pos, &mut buf[0..std::cmp::min(end, copy_len) - pos])
+                {
+                    Ok(()) => {}
+                    Err(Error::HashMismatch) => buf.iter_mut().for_each(|b| *b = 0),
+                    Err(e) => return Err(e),
+                }
+            }
+            if end > copy_len {
+                let zero_from = std::cmp::max(pos, copy_len) - pos;
+                buf[zero_from..].iter_mut().for_each(|b| *b = 0);
+            }
+            self.write(pos, buf)?;
+        }
+        self.commit()
+    }
+
+    /// Puts back the old data file from the copy at `backup_path` and the quota
+    /// after a failed resize, and removes the journal.
+    fn restore_from_backup(
+        &mut self,
+        backup_path: &[String],
+        original_quota: Option<Quota>,
+    ) -> Result<(), Error> {
+        let ino = self.meta.get_ino();
+        let ext_path = ext_path(&self.center.base_path, self.center.id);
+        let path = data_file_path(&ext_path, ino);
+        let sd_nand = self.center.sd_nand.clone();
+        let sd_nand = sd_nand.as_ref();
+        self.data = None; // close the file first
+        if sd_nand.is_file(&as_str_path(backup_path)) {
+            copy_to(sd_nand, backup_path, sd_nand, &path)?;
+        } else if sd_nand.is_file(&as_str_path(&path)) {
+            sd_nand.remove(&as_str_path(&path))?;
+        }
+        if let Some(quota) = original_quota {
+            self.write_quota(quota)?;
+        }
+        sd_nand.remove_dir(&as_str_path(&join(&ext_path, vec![RESIZE_JOURNAL_DIR])))?;
+
+        let meta = FileMeta::open_ino(self.center.fs.clone(), ino)?;
+        *self = File::from_meta(self.center.clone(), meta, None)?;
+        Ok(())
+    }
+
+    fn write_quota(&self, quota: Quota) -> Result<(), Error> {
+        let quota_file = self.center.quota_file.as_ref().unwrap();
+        write_struct(quota_file.partition().as_ref(), 0, quota)?;
+        quota_file.commit()
+    }
+
+    /// Returns the unique ID of this file.
+    ///
+    /// The ID is stored both in the file entry and in the header of the data file,
+    /// and games check it when opening the file.
+    pub fn unique_id(&self) -> Result<u64, Error> {
+        Ok(self.meta.get_info()?.unique_id)
+    }
+
+    /// Changes the unique ID of this file, in both the file entry and the data file.
+    /// The file entry takes effect on the next [`ExtData::commit`](struct.ExtData.html#method.commit).
+    pub fn set_unique_id(&mut self, unique_id: u64) -> Result<(), Error> {
+        self.meta.check_exclusive()?;
+        if let Some(data) = self.data.as_mut() {
+            data.set_unique_id(unique_id)?;
+        }
+        let mut info = self.meta.get_info()?;
+        info.unique_id = unique_id;
+        self.meta.set_info(info)
+    }
 }
 
 impl FileSystemFile for File {
@@ -604,31 +1474,94 @@ impl FileSystemFile for File {
         self.meta.get_ino()
     }
 
-    /// Changes the size of this file.
-    ///
-    /// Warning: this operation is extremely slow for extdata.
+    /// Changes the size of this file. The region beyond the old size is filled with zeros.
     /// Also, if the size is changed to zero, 3DS will refuse to open the file.
+    ///
+    /// If the data file keeps the same layout and the same number of blocks with the new size,
+    /// which is often the case for small changes, it is resized in place. Otherwise the data
+    /// file is recreated with the new size, and the content is copied over block by block from
+    /// a copy of the old data file, which is slow for large files.
+    ///
+    /// The copy of the old data file is kept in a journal, and counts towards the quota until
+    /// the resize is done. If the resize fails, the file is restored from it.
+    /// If the resize is interrupted, the file is restored the next time the extdata is opened.
     fn resize(&mut self, len: usize) -> Result<(), Error> {
-        if len == self.len() {
+        let old_len = self.len();
+        if len == old_len {
             return Ok(());
         }
 
         self.meta.check_exclusive()?;
 
-        let mut buf = vec![0; len];
-        match self.read(0, &mut buf[0..std::cmp::min(len, self.len())]) {
-            Ok(()) | Err(Error::HashMismatch) => {}
-            e => return e,
+        if let Some(data) = self.data.as_ref() {
+            if len != 0
+                && DifiPartition::same_layout(&data_file_param(old_len), len)
+                && divide_up(Diff::calculate_size(&data_file_param(len)), 0x1000)
+                    == divide_up(data.parent_len(), 0x1000)
+            {
+                return self.resize_in_place(len);
+            }
         }
 
-        self.delete_data()?;
+        let ext_path = ext_path(&self.center.base_path, self.center.id);
+        let path = data_file_path(&ext_path, self.meta.get_ino());
+        let journal_path = join(&ext_path, vec![RESIZE_JOURNAL_DIR]);
+        let backup_path = journal_data_path(&journal_path, &as_str_path(&path[ext_path.len()..]));
+
+        // The copy is taken before the old data file is removed, and the new data file
+        // is created after that, so both must fit in the free blocks.
+        let backup_block = divide_up(self.data.as_ref().map_or(0, Diff::parent_len), 0x1000);
+        let original_quota = if let Some(quota_file) = self.center.quota_file.as_ref() {
+            let quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            let new_block = if len == 0 {
+                0
+            } else {
+                divide_up(Diff::calculate_size(&data_file_param(len)), 0x1000)
+            };
+            if (quota.free_block as usize) < std::cmp::max(backup_block, new_block) {
+                return make_error(Error::NoSpace);
+            }
+            Some(quota)
+        } else {
+            None
+        };
 
-        let unique_id = self.meta.get_info()?.unique_id;
-        let meta = FileMeta::open_ino(self.center.fs.clone(), self.meta.get_ino())?;
-        *self = File::from_meta(self.center.clone(), meta, Some((len, unique_id)))?;
-        self.write(0, &buf)?;
-        self.commit()?;
-        Ok(())
+        let sd_nand = self.center.sd_nand.clone();
+        let mut removed = vec![];
+        let mut changed = vec![];
+        if self.data.is_some() {
+            changed.push(path.clone());
+        } else {
+            removed.push(path.clone());
+        }
+        if original_quota.is_some() {
+            changed.push(join(&ext_path, vec!["Quota.dat"]));
+        }
+        record_journal(
+            sd_nand.as_ref(),
+            &ext_path,
+            RESIZE_JOURNAL_DIR,
+            sd_nand.as_ref(),
+            &removed,
+            &changed,
+        )?;
+
+        if let Some(mut quota) = original_quota {
+            quota.free_block -= backup_block as u32;
+            self.write_quota(quota)?;
+        }
+
+        if let Err(e) = self.copy_from_backup(&backup_path, old_len, len) {
+            self.restore_from_backup(&backup_path, original_quota)?;
+            return Err(e);
+        }
+
+        if let Some(quota_file) = self.center.quota_file.as_ref() {
+            let mut quota: Quota = read_struct(quota_file.partition().as_ref(), 0)?;
+            quota.free_block += backup_block as u32;
+            self.write_quota(quota)?;
+        }
+        sd_nand.remove_dir(&as_str_path(&journal_path))
     }
 
     fn delete(mut self) -> Result<(), Error> {
@@ -803,6 +1736,8 @@ impl FileSystem for ExtData {
             free_files: meta_stat.files.free,
             total_dirs: meta_stat.dirs.total,
             free_dirs: meta_stat.dirs.free,
+            dir_hash: Some(meta_stat.dirs.chains),
+            file_hash: Some(meta_stat.files.chains),
         })
     }
 }
@@ -835,6 +1770,495 @@ mod test {
         }
     }
 
+    #[test]
+    fn transaction() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(0x100), &param).unwrap();
+        let open = || ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+        let read = |ext_data: &ExtData, name| {
+            let file = ext_data.open_root().unwrap().open_sub_file(name).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            buf
+        };
+
+        let ext_data = open();
+        let file = ext_data
+            .open_root()
+            .unwrap()
+            .new_sub_file([1; 16], 4)
+            .unwrap();
+        file.write(0, &[1; 4]).unwrap();
+        file.commit().unwrap();
+        drop(file);
+        ext_data.commit().unwrap();
+
+        let change = |ext_data: ExtData| {
+            let transaction = ext_data.begin_transaction().unwrap();
+            let root = transaction.ext_data().open_root().unwrap();
+            let mut file = root.open_sub_file([1; 16]).unwrap();
+            file.resize(6).unwrap();
+            file.write(0, &[2; 6]).unwrap();
+            file.commit().unwrap();
+            let file = root.new_sub_file([3; 16], 2).unwrap();
+            file.write(0, &[3; 2]).unwrap();
+            file.commit().unwrap();
+            drop(file);
+            drop(root);
+            transaction.ext_data().commit().unwrap();
+            transaction
+        };
+
+        let ext_data = change(ext_data).rollback().unwrap();
+        assert_eq!(read(&ext_data, [1; 16]), vec![1; 4]);
+        assert!(ext_data
+            .open_root()
+            .unwrap()
+            .open_sub_file([3; 16])
+            .is_err());
+
+        let ext_data = change(ext_data).commit().unwrap();
+        assert_eq!(read(&ext_data, [1; 16]), vec![2; 6]);
+        assert_eq!(read(&ext_data, [3; 16]), vec![3; 2]);
+
+        // interrupted after recording the journal
+        let transaction = ext_data.begin_transaction().unwrap();
+        transaction
+            .ext_data()
+            .open_root()
+            .unwrap()
+            .open_sub_file([3; 16])
+            .unwrap()
+            .delete()
+            .unwrap();
+        transaction.ext_data().commit().unwrap();
+        let ExtDataTransaction {
+            base_path,
+            overlay,
+            ext_data,
+            ..
+        } = transaction;
+        drop(ext_data);
+        write_journal(nand.as_ref(), &ext_path(&base_path, 0), &overlay).unwrap();
+        drop(overlay);
+        assert!(matches!(
+            ExtData::new(nand.clone(), &[], 0, [0; 16], true, false),
+            Err(Error::Busy)
+        ));
+        assert!(nand.is_dir(&["00000000", "00000000", JOURNAL_DIR]));
+        let ext_data = open();
+        assert!(ext_data
+            .open_root()
+            .unwrap()
+            .open_sub_file([3; 16])
+            .is_err());
+        assert!(!nand.is_dir(&["00000000", "00000000", JOURNAL_DIR]));
+        drop(ext_data);
+
+        // interrupted while recording the journal
+        nand.create(&["00000000", "00000000", JOURNAL_DIR, "Manifest"], 4)
+            .unwrap();
+        let ext_data = ExtData::new(nand.clone(), &[], 0, [0; 16], true, false).unwrap();
+        assert_eq!(read(&ext_data, [1; 16]), vec![2; 6]);
+        assert!(nand.is_dir(&["00000000", "00000000", JOURNAL_DIR]));
+        drop(ext_data);
+        let ext_data = open();
+        assert_eq!(read(&ext_data, [1; 16]), vec![2; 6]);
+        assert!(!nand.is_dir(&["00000000", "00000000", JOURNAL_DIR]));
+    }
+
+    #[test]
+    fn rehash() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 1,
+            max_file: 10,
+            file_buckets: 1,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(0x100), &param).unwrap();
+        let open = || ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+
+        let ext_data = open();
+        let root = ext_data.open_root().unwrap();
+        for i in 1..5 {
+            let dir = root.new_sub_dir([i; 16]).unwrap();
+            let file = dir.new_sub_file([i; 16], i as usize).unwrap();
+            file.write(0, &vec![i; i as usize]).unwrap();
+            file.commit().unwrap();
+        }
+        drop(root);
+        ext_data.commit().unwrap();
+        let stat = ext_data.stat().unwrap();
+        assert_eq!(stat.dir_hash.unwrap().longest_chain, 5);
+        assert_eq!(stat.file_hash.unwrap().longest_chain, 4);
+
+        ext_data.rehash(7, 13).unwrap();
+        let ext_data = open();
+        let stat = ext_data.stat().unwrap();
+        let dir_hash = stat.dir_hash.unwrap();
+        let file_hash = stat.file_hash.unwrap();
+        assert_eq!((dir_hash.buckets, dir_hash.entries), (7, 5));
+        assert_eq!((file_hash.buckets, file_hash.entries), (13, 4));
+        assert_eq!(stat.free_files, 6);
+
+        let root = ext_data.open_root().unwrap();
+        for i in 1..5 {
+            let dir = root.open_sub_dir([i; 16]).unwrap();
+            let file = dir.open_sub_file([i; 16]).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![i; i as usize]);
+        }
+        root.new_sub_file([5; 16], 1).unwrap();
+    }
+
+    #[test]
+    fn grow_tables() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 1,
+            dir_buckets: 3,
+            max_file: 3,
+            file_buckets: 3,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(0x100), &param).unwrap();
+        let open = || ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+
+        let ext_data = open();
+        let root = ext_data.open_root().unwrap();
+        let dir = root.new_sub_dir([9; 16]).unwrap();
+        for i in 0..3 {
+            let file = dir.new_sub_file([i; 16], 10).unwrap();
+            file.write(0, &[i; 10]).unwrap();
+            file.commit().unwrap();
+        }
+        assert!(matches!(
+            root.new_sub_file([3; 16], 10),
+            Err(Error::NoSpace)
+        ));
+        dir.open_sub_file([1; 16]).unwrap().delete().unwrap();
+        let inos: Vec<u32> = dir
+            .list_sub_file()
+            .unwrap()
+            .into_iter()
+            .map(|(_, ino)| ino)
+            .collect();
+        drop(dir);
+        drop(root);
+        ext_data.commit().unwrap();
+        assert!(matches!(
+            ext_data.grow_tables(1, 2),
+            Err(Error::InvalidValue)
+        ));
+
+        let ext_data = open();
+        ext_data.grow_tables(2, 8).unwrap();
+        let ext_data = open();
+        assert_eq!(
+            ext_data.format_param(),
+            ExtDataFormatParam {
+                max_dir: 2,
+                max_file: 8,
+                ..param
+            }
+        );
+        let stat = ext_data.stat().unwrap();
+        assert_eq!((stat.total_files, stat.free_files), (8, 6));
+        assert_eq!((stat.total_dirs, stat.free_dirs), (3, 1));
+
+        let root = ext_data.open_root().unwrap();
+        let dir = root.open_sub_dir([9; 16]).unwrap();
+        let list = dir.list_sub_file().unwrap();
+        assert_eq!(list.iter().map(|&(_, ino)| ino).collect::<Vec<_>>(), inos);
+        for (name, _) in list {
+            let file = dir.open_sub_file(name).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![name[0]; 10]);
+        }
+        for i in 3..9 {
+            root.new_sub_file([i; 16], 10).unwrap();
+        }
+        assert!(matches!(
+            root.new_sub_file([10; 16], 10),
+            Err(Error::NoSpace)
+        ));
+        root.new_sub_dir([1; 16]).unwrap();
+    }
+
+    #[test]
+    fn quota() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(0x100), &param).unwrap();
+        let ext_data = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+        let root = ext_data.open_root().unwrap();
+        root.new_sub_file([1; 16], 0x3000).unwrap();
+        root.new_sub_file([2; 16], 0).unwrap();
+        root.new_sub_dir([3; 16])
+            .unwrap()
+            .new_sub_file([4; 16], 10)
+            .unwrap()
+            .delete()
+            .unwrap();
+        root.open_sub_dir([3; 16])
+            .unwrap()
+            .new_sub_file([5; 16], 0x1000)
+            .unwrap();
+        drop(root);
+        ext_data.commit().unwrap();
+
+        let quota = ext_data.quota().unwrap().unwrap();
+        assert_eq!(quota.max_block, 0x100);
+        assert_eq!(
+            ext_data.repair_quota().unwrap().free_block,
+            quota.free_block
+        );
+
+        // make the counters drift
+        let quota_file = ext_data.center.quota_file.as_ref().unwrap();
+        let mut raw: Quota = read_struct(quota_file.partition().as_ref(), 0).unwrap();
+        raw.free_block -= 10;
+        write_struct(quota_file.partition().as_ref(), 0, raw).unwrap();
+        assert_eq!(
+            ext_data.quota().unwrap().unwrap().free_block,
+            quota.free_block - 10
+        );
+        assert_eq!(
+            ext_data.repair_quota().unwrap().free_block,
+            quota.free_block
+        );
+
+        // more free blocks than the capacity
+        raw.free_block = raw.max_block + 1;
+        write_struct(quota_file.partition().as_ref(), 0, raw).unwrap();
+        assert!(matches!(
+            ext_data.set_quota_capacity(0x200),
+            Err(Error::InvalidValue)
+        ));
+        assert_eq!(
+            ext_data.repair_quota().unwrap().free_block,
+            quota.free_block
+        );
+
+        let used = quota.max_block - quota.free_block;
+        assert!(matches!(
+            ext_data.set_quota_capacity(used as u32 - 1),
+            Err(Error::NoSpace)
+        ));
+        let potential_free_block = ext_data.quota().unwrap().unwrap().potential_free_block;
+        let quota = ext_data.set_quota_capacity(0x200).unwrap();
+        assert_eq!((quota.max_block, quota.free_block), (0x200, 0x200 - used));
+        assert_eq!(quota.potential_free_block, potential_free_block);
+        drop(ext_data);
+        let ext_data = ExtData::new(nand.clone(), &[], 0, [0; 16], true, false).unwrap();
+        assert_eq!(ext_data.quota().unwrap().unwrap(), quota);
+        assert!(matches!(ext_data.repair_quota(), Err(Error::Unsupported)));
+    }
+
+    #[test]
+    fn unique_id() {
+        let sd = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(sd.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext_data = ExtData::new(sd.clone(), &[], 0, [0; 16], false, true).unwrap();
+        let root = ext_data.open_root().unwrap();
+        let mut file = root.new_sub_file([1; 16], 0x100).unwrap();
+        file.write(0, &[7; 0x100]).unwrap();
+        file.commit().unwrap();
+        assert_eq!(file.unique_id().unwrap(), 0xDEAD_BEEF);
+        file.set_unique_id(0x0123_4567_89AB_CDEF).unwrap();
+        let mut empty = root.new_sub_file([2; 16], 0).unwrap();
+        empty.set_unique_id(5).unwrap();
+        drop(file);
+        drop(empty);
+        drop(root);
+        ext_data.commit().unwrap();
+        drop(ext_data);
+
+        let ext_data = ExtData::new(sd.clone(), &[], 0, [0; 16], false, false).unwrap();
+        let root = ext_data.open_root().unwrap();
+        let file = root.open_sub_file([1; 16]).unwrap();
+        assert_eq!(file.unique_id().unwrap(), 0x0123_4567_89AB_CDEF);
+        let mut buf = [0; 0x100];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], [7; 0x100][..]);
+        assert_eq!(root.open_sub_file([2; 16]).unwrap().unique_id().unwrap(), 5);
+    }
+
+    #[test]
+    fn mount_info() {
+        let sd = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(sd.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+        let ext_data = ExtData::new(sd.clone(), &[], 0, [0; 16], false, true).unwrap();
+        let mut info = ext_data.mount_info().unwrap();
+        assert_eq!(
+            info,
+            ExtDataMountInfo {
+                action: 0,
+                mount_id: 0,
+                mount_path: [[0; 0x10]; 0x10],
+            }
+        );
+        info.action = 1;
+        info.mount_id = 0x12;
+        info.mount_path[0] = *b"/user/abc\0\0\0\0\0\0\0";
+        ext_data.set_mount_info(&info).unwrap();
+        ext_data.commit().unwrap();
+        ext_data.grow_tables(20, 20).unwrap();
+
+        let ext_data = ExtData::new(sd.clone(), &[], 0, [0; 16], false, false).unwrap();
+        assert_eq!(ext_data.mount_info().unwrap(), info);
+        assert!(matches!(
+            ext_data.set_mount_info(&info),
+            Err(Error::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn resize() {
+        let nand = Rc::new(crate::sd_nand_common::test::VirtualFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(nand.as_ref(), &[], 0, [0; 16], Some(0x100), &param).unwrap();
+        let ext_data = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+        let root = ext_data.open_root().unwrap();
+        let mut file = root.new_sub_file([1; 16], 0x2800).unwrap();
+        let content: Vec<u8> = (0..0x2800).map(|i| (i % 251) as u8).collect();
+        file.write(0, &content).unwrap();
+        file.commit().unwrap();
+        file.set_unique_id(0x1234).unwrap();
+
+        let check = |file: &File, len: usize| {
+            let mut buf = vec![0xFF; len];
+            file.read(0, &mut buf).unwrap();
+            let kept = std::cmp::min(len, content.len());
+            assert_eq!(buf[0..kept], content[0..kept]);
+            assert!(buf[kept..].iter().all(|&b| b == 0));
+        };
+
+        for &len in &[0x5001, 0x1801, 0x1801, 0] {
+            file.resize(len).unwrap();
+            assert_eq!(file.len(), len);
+            assert_eq!(file.unique_id().unwrap(), 0x1234);
+            check(&file, len);
+            assert!(!nand.is_dir(&["00000000", "00000000", RESIZE_JOURNAL_DIR]));
+            let quota = ext_data.quota().unwrap().unwrap();
+            assert_eq!(
+                ext_data.repair_quota().unwrap().free_block,
+                quota.free_block
+            );
+        }
+
+        // small changes are done in place
+        file.resize(0x1801).unwrap();
+        file.write(0, &content[0..0x1801]).unwrap();
+        file.commit().unwrap();
+        let quota = ext_data.quota().unwrap().unwrap();
+        let data_path = data_file_path(&ext_path(&[], 0), file.get_ino());
+        let physical_len = nand.open(&as_str_path(&data_path), false).unwrap().len();
+        let mut expected = content[0..0x1801].to_vec();
+        for &len in &[0x1F00, 0x1100, 0x1801] {
+            file.resize(len).unwrap();
+            expected.resize(len, 0);
+            let ino = file.get_ino();
+            drop(file);
+            file = ext_data.open_file(ino).unwrap();
+            let mut buf = vec![0xFF; len];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+        }
+        assert_eq!(ext_data.quota().unwrap().unwrap(), quota);
+        assert_eq!(
+            nand.open(&as_str_path(&data_path), false).unwrap().len(),
+            physical_len
+        );
+
+        file.resize(0x100).unwrap();
+        let mut buf = [0xFF; 0x100];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], content[0..0x100]);
+
+        // not enough space for the new data file
+        file.write(0, &content[0..0x100]).unwrap();
+        file.commit().unwrap();
+        let quota = ext_data.quota().unwrap().unwrap();
+        match file.resize(0x10_0000) {
+            Err(Error::NoSpace) => (),
+            _ => panic!(),
+        }
+        assert_eq!(ext_data.quota().unwrap().unwrap(), quota);
+        assert_eq!(file.len(), 0x100);
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], content[0..0x100]);
+
+        // interrupted after recording the journal
+        let ext_path = ext_path(&[], 0);
+        let path = data_file_path(&ext_path, file.get_ino());
+        record_journal(
+            nand.as_ref(),
+            &ext_path,
+            RESIZE_JOURNAL_DIR,
+            nand.as_ref(),
+            &[],
+            &[path.clone(), join(&ext_path, vec!["Quota.dat"])],
+        )
+        .unwrap();
+        drop(file);
+        drop(root);
+        ext_data.commit().unwrap();
+        drop(ext_data);
+        nand.open(&as_str_path(&path), true)
+            .unwrap()
+            .write(0x1000, &[0xDD; 0x1000])
+            .unwrap();
+
+        assert!(matches!(
+            ExtData::new(nand.clone(), &[], 0, [0; 16], true, false),
+            Err(Error::Busy)
+        ));
+        assert!(nand.is_dir(&["00000000", "00000000", RESIZE_JOURNAL_DIR]));
+        let ext_data = ExtData::new(nand.clone(), &[], 0, [0; 16], true, true).unwrap();
+        assert!(!nand.is_dir(&["00000000", "00000000", RESIZE_JOURNAL_DIR]));
+        assert_eq!(ext_data.quota().unwrap().unwrap(), quota);
+        let file = ext_data
+            .open_root()
+            .unwrap()
+            .open_sub_file([1; 16])
+            .unwrap();
+        assert_eq!(file.len(), 0x100);
+        assert_eq!(file.unique_id().unwrap(), 0x1234);
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf[..], content[0..0x100]);
+    }
+
     #[test]
     fn fs_fuzz() {
         use rand::prelude::*;
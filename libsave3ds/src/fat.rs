@@ -1,4 +1,5 @@
 use crate::error::*;
+use crate::file_system::Fragmentation;
 use crate::misc::*;
 use crate::random_access_file::*;
 use byte_struct::*;
@@ -283,6 +284,17 @@ fn iterate_fat_entry(
     Ok(())
 }
 
+/// Counts the contiguous runs of blocks in a list of nodes.
+fn count_runs(nodes: &[(usize, usize)]) -> usize {
+    nodes.len()
+        - nodes
+            .windows(2)
+            .filter(|pair| pair[0].0 + pair[0].1 == pair[1].0)
+            .count()
+}
+
+type NodeList = Vec<(usize, usize)>;
+
 impl Fat {
     pub fn format(table: &dyn RandomAccessFile) -> Result<(), Error> {
         let block_count = table.len() / 8 - 1;
@@ -328,9 +340,193 @@ impl Fat {
         }))
     }
 
+    /// Returns the data region holding the blocks.
+    pub fn data(&self) -> Rc<dyn RandomAccessFile> {
+        self.data.clone()
+    }
+
     pub fn free_blocks(&self) -> usize {
         self.free_blocks.get()
     }
+
+    fn block_count(&self) -> usize {
+        self.table.len() / 8 - 1
+    }
+
+    /// Collects the nodes as (start, size) of each file starting at `first_blocks`,
+    /// and of the free blocks. Fails if any block is claimed twice.
+    fn collect_nodes(&self, first_blocks: &[usize]) -> Result<(Vec<NodeList>, NodeList), Error> {
+        let table = self.table.as_ref();
+        let mut claimed = vec![false; self.block_count()];
+        let mut collect = |first_block: usize| -> Result<NodeList, Error> {
+            let mut nodes = vec![];
+            iterate_fat_entry(table, first_block, |start, size| nodes.push((start, size)))?;
+            for &(start, size) in nodes.iter() {
+                if start + size > claimed.len() || claimed[start..start + size].contains(&true) {
+                    error!("Block claimed more than once");
+                    return make_error(Error::BrokenFat);
+                }
+                claimed[start..start + size]
+                    .iter_mut()
+                    .for_each(|c| *c = true);
+            }
+            Ok(nodes)
+        };
+
+        let files = first_blocks
+            .iter()
+            .map(|&first_block| collect(first_block))
+            .collect::<Result<Vec<_>, _>>()?;
+        let free = match get_head(table)? {
+            Some(head) => collect(head)?,
+            None => vec![],
+        };
+        Ok((files, free))
+    }
+
+    /// Returns the fragmentation statistics, given the first block of every file.
+    pub fn fragmentation(&self, first_blocks: &[usize]) -> Result<Fragmentation, Error> {
+        let (files, mut free) = self.collect_nodes(first_blocks)?;
+        let file_runs: Vec<usize> = files.iter().map(|nodes| count_runs(nodes)).collect();
+
+        // free nodes are not in order, so merge the adjacent ones first
+        free.sort_unstable();
+        let mut free_runs: Vec<usize> = vec![];
+        let mut end = None;
+        for (start, size) in free {
+            if end == Some(start) {
+                *free_runs.last_mut().unwrap() += size;
+            } else {
+                free_runs.push(size);
+            }
+            end = Some(start + size);
+        }
+
+        Ok(Fragmentation {
+            files: files.len(),
+            file_runs: file_runs.iter().sum(),
+            max_file_runs: file_runs.iter().copied().max().unwrap_or(0),
+            free_runs: free_runs.len(),
+            largest_free_run: free_runs.iter().copied().max().unwrap_or(0),
+        })
+    }
+
+    /// Returns the new first block of each file after
+    /// [`defragment`](#method.defragment) with the same `first_blocks`.
+    pub fn defragment_layout(&self, first_blocks: &[usize]) -> Result<Vec<usize>, Error> {
+        let (files, free) = self.collect_nodes(first_blocks)?;
+        let claimed: usize = files.iter().chain(Some(&free)).flatten().map(|n| n.1).sum();
+        if claimed != self.block_count() {
+            error!("Some blocks belong to no file");
+            return make_error(Error::BrokenFat);
+        }
+
+        let mut layout = Vec::with_capacity(files.len());
+        let mut next = 0;
+        for nodes in files {
+            layout.push(next);
+            next += nodes.iter().map(|n| n.1).sum::<usize>();
+        }
+        Ok(layout)
+    }
+
+    /// Moves the files starting at `first_blocks` to contiguous runs in the same order
+    /// from the beginning, and puts all free blocks in one run after them.
+    ///
+    /// `first_blocks` must list every file in the table, otherwise `Error::BrokenFat` is returned.
+    /// All `FatFile` opened before become invalid.
+    pub fn defragment(&self, first_blocks: &[usize]) -> Result<(), Error> {
+        self.defragment_layout(first_blocks)?;
+        let (files, _) = self.collect_nodes(first_blocks)?;
+        let block_count = self.block_count();
+
+        // map every block to its new position. Free blocks keep their order after the files
+        let mut new_index = vec![0; block_count];
+        let mut used = vec![false; block_count];
+        let mut next = 0;
+        for &(start, size) in files.iter().flatten() {
+            for i in start..start + size {
+                new_index[i] = next;
+                used[i] = true;
+                next += 1;
+            }
+        }
+        let used_count = next;
+        for i in 0..block_count {
+            if !used[i] {
+                new_index[i] = next;
+                next += 1;
+            }
+        }
+
+        // move the blocks cycle by cycle. Content of free blocks is dropped
+        let mut done = vec![false; block_count];
+        let mut buf = vec![0; self.block_len];
+        let mut tmp = vec![0; self.block_len];
+        for start in 0..block_count {
+            if done[start] {
+                continue;
+            }
+            done[start] = true;
+            if new_index[start] == start {
+                continue;
+            }
+            let mut carrying = used[start];
+            if carrying {
+                self.read_block(start, &mut buf)?;
+            }
+            let mut cur = new_index[start];
+            loop {
+                let cur_used = used[cur] && cur != start;
+                if cur_used {
+                    self.read_block(cur, &mut tmp)?;
+                }
+                if carrying {
+                    self.data.write(cur * self.block_len, &buf)?;
+                }
+                if cur == start {
+                    break;
+                }
+                done[cur] = true;
+                std::mem::swap(&mut buf, &mut tmp);
+                carrying = cur_used;
+                cur = new_index[cur];
+            }
+        }
+
+        // rebuild the table with one node per file
+        let table = self.table.as_ref();
+        let mut start = 0;
+        for nodes in files {
+            let size = nodes.iter().map(|n| n.1).sum();
+            let node = Node {
+                size,
+                prev: None,
+                next: None,
+            };
+            set_node(table, start, node)?;
+            start += size;
+        }
+        if used_count == block_count {
+            set_head(table, None)
+        } else {
+            let node = Node {
+                size: block_count - used_count,
+                prev: None,
+                next: None,
+            };
+            set_node(table, used_count, node)?;
+            set_head(table, Some(used_count))
+        }
+    }
+
+    /// Reads a whole block, accepting uninitialized data.
+    fn read_block(&self, index: usize, buf: &mut [u8]) -> Result<(), Error> {
+        match self.data.read(index * self.block_len, buf) {
+            Ok(()) | Err(Error::HashMismatch) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 /// A handle to a file in `Fat` that implements resizing, releasing, reading and writing.
@@ -548,6 +744,73 @@ mod test {
         assert_eq!(Entry::BYTE_LEN, 8);
     }
 
+    #[test]
+    fn defragment() {
+        use rand::distributions::Standard;
+        use rand::prelude::*;
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let block_len = rng.gen_range(1, 10);
+            let block_count = rng.gen_range(10, 100);
+
+            let table = Rc::new(MemoryFile::new(vec![0; 8 * (block_count + 1)]));
+            let data = Rc::new(MemoryFile::new(vec![0; block_count * block_len]));
+            Fat::format(table.as_ref()).unwrap();
+            let fat = Fat::new(table, data, block_len).unwrap();
+
+            // create files growing in turns, and then delete some of them
+            let mut files: Vec<(FatFile, usize)> = (0..5)
+                .map(|_| FatFile::create(fat.clone(), 1).unwrap())
+                .collect();
+            for _ in 0..20 {
+                let file = &mut files[rng.gen_range(0, 5)].0;
+                let new_len = file.len() / block_len + 1;
+                if fat.free_blocks() > 0 {
+                    file.resize(new_len).unwrap();
+                }
+            }
+            for _ in 0..2 {
+                let (file, _) = files.remove(rng.gen_range(0, files.len()));
+                file.delete().unwrap();
+            }
+            let images: Vec<Vec<u8>> = files
+                .iter()
+                .map(|(file, _)| {
+                    let image: Vec<u8> = rng.sample_iter(&Standard).take(file.len()).collect();
+                    file.write(0, &image).unwrap();
+                    image
+                })
+                .collect();
+
+            let first_blocks: Vec<usize> = files.iter().map(|&(_, block)| block).collect();
+            assert!(fat.defragment(&first_blocks[1..]).is_err());
+            let layout = fat.defragment_layout(&first_blocks).unwrap();
+            fat.defragment(&first_blocks).unwrap();
+            drop(files);
+
+            for (image, &block) in images.iter().zip(layout.iter()) {
+                let file = FatFile::open(fat.clone(), block).unwrap();
+                let mut buf = vec![0; file.len()];
+                file.read(0, &mut buf).unwrap();
+                assert_eq!(&buf, image);
+            }
+            let fragmentation = fat.fragmentation(&layout).unwrap();
+            assert_eq!(fragmentation.files, 3);
+            assert_eq!(fragmentation.file_runs, 3);
+            assert_eq!(fragmentation.max_file_runs, 1);
+            assert_eq!(fragmentation.largest_free_run, fat.free_blocks());
+            assert_eq!(
+                fragmentation.free_runs,
+                if fat.free_blocks() == 0 { 0 } else { 1 }
+            );
+
+            if fat.free_blocks() != 0 {
+                FatFile::create(fat.clone(), fat.free_blocks()).unwrap();
+            }
+        }
+    }
+
     #[test]
     fn fuzz() {
         use rand::distributions::Standard;
@@ -112,6 +112,48 @@ pub struct Stat {
 
     /// Number of free directory slots.
     pub free_dirs: usize,
+
+    /// Collision chains of the directory hash table, for archives that look up entries by hash.
+    pub dir_hash: Option<HashChains>,
+
+    /// Collision chains of the file hash table, for archives that look up entries by hash.
+    pub file_hash: Option<HashChains>,
+}
+
+/// Describes how the blocks of a [`FileSystem`](trait.FileSystem.html) are fragmented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Fragmentation {
+    /// Number of files holding blocks, including the internal directory and file tables.
+    pub files: usize,
+
+    /// Total number of contiguous runs of blocks in all files.
+    pub file_runs: usize,
+
+    /// Largest number of contiguous runs of blocks in a single file.
+    pub max_file_runs: usize,
+
+    /// Number of contiguous runs of free blocks.
+    pub free_runs: usize,
+
+    /// Number of blocks in the largest contiguous run of free blocks.
+    pub largest_free_run: usize,
+}
+
+/// Describes how the entries of a hash table in a [`FileSystem`](trait.FileSystem.html)
+/// are distributed over its buckets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HashChains {
+    /// Number of buckets.
+    pub buckets: usize,
+
+    /// Number of buckets holding at least one entry.
+    pub used_buckets: usize,
+
+    /// Number of entries in all buckets.
+    pub entries: usize,
+
+    /// Number of entries in the longest collision chain.
+    pub longest_chain: usize,
 }
 
 /// The common interface for a 3DS archive (save data, extdata, or title database).
@@ -1,9 +1,13 @@
 use crate::byte_struct_common::*;
 use crate::error::*;
+use crate::fat::{Fat, FatFile};
+use crate::file_system::{Fragmentation, HashChains};
+use crate::misc::align_up;
 use crate::random_access_file::*;
+use crate::sub_file::SubFile;
 use byte_struct::*;
 use std::cell::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::rc::Rc;
 
@@ -85,6 +89,7 @@ impl<KeyType, InfoType> RefTicket<KeyType, InfoType> {
 pub struct MetaTableStat {
     pub total: usize,
     pub free: usize,
+    pub chains: HashChains,
 }
 
 /// Generic metadata table that implements children lookup / insertion / removal.
@@ -275,24 +280,80 @@ impl<KeyType: ByteStruct + PartialEq, InfoType: ByteStruct> MetaTable<KeyType, I
         Ok(index)
     }
 
-    fn stat(&self) -> Result<MetaTableStat, Error> {
+    /// Returns the indices of all dummy entries, which are free to reuse.
+    fn dummy_indices(&self) -> Result<HashSet<u32>, Error> {
         let table = self.table.as_ref();
-        let entry_count = read_struct::<U32le>(table, 0)?.v;
-        let max_entry_count = read_struct::<U32le>(table, 4)?.v;
+        let mut dummies = HashSet::new();
         let mut index = read_struct::<U32le>(table, self.eo_collision)?.v;
-        let mut dummy_count = 0;
         while index != 0 {
-            dummy_count += 1;
+            dummies.insert(index);
             let entry_offset = index as usize * self.entry_len;
             index = read_struct::<U32le>(table, entry_offset + self.eo_collision)?.v;
         }
+        Ok(dummies)
+    }
+
+    fn chains(&self) -> Result<HashChains, Error> {
+        let table = self.table.as_ref();
+        let hash = self.hash.as_ref();
+        let mut chains = HashChains {
+            buckets: self.buckets,
+            used_buckets: 0,
+            entries: 0,
+            longest_chain: 0,
+        };
+        for h in 0..self.buckets {
+            let mut len = 0;
+            let mut index = read_struct::<U32le>(hash, h * 4)?.v;
+            while index != 0 {
+                len += 1;
+                let entry_offset = index as usize * self.entry_len;
+                index = read_struct::<U32le>(table, entry_offset + self.eo_collision)?.v;
+            }
+            if len != 0 {
+                chains.used_buckets += 1;
+            }
+            chains.entries += len;
+            chains.longest_chain = std::cmp::max(chains.longest_chain, len);
+        }
+        Ok(chains)
+    }
+
+    fn stat(&self) -> Result<MetaTableStat, Error> {
+        let table = self.table.as_ref();
+        let entry_count = read_struct::<U32le>(table, 0)?.v as usize;
+        let max_entry_count = read_struct::<U32le>(table, 4)?.v as usize;
+        let dummy_count = self.dummy_indices()?.len();
 
         Ok(MetaTableStat {
-            total: max_entry_count as usize - 1,
-            free: (max_entry_count - entry_count + dummy_count) as usize,
+            total: max_entry_count - 1,
+            free: max_entry_count - entry_count + dummy_count,
+            chains: self.chains()?,
         })
     }
 
+    /// Relinks all entries into the hash table, which may have a different bucket count
+    /// from the one the entries were added with. Entries keep their indices.
+    fn rehash(&self) -> Result<(), Error> {
+        let table = self.table.as_ref();
+        let hash = self.hash.as_ref();
+        let entry_count = read_struct::<U32le>(table, 0)?.v;
+        let dummies = self.dummy_indices()?;
+        hash.write(0, &vec![0; hash.len()])?;
+        for index in (1..entry_count).rev() {
+            if dummies.contains(&index) {
+                continue;
+            }
+            let entry_offset = index as usize * self.entry_len;
+            let key: KeyType = read_struct(table, entry_offset)?;
+            let h = self.hash(&key);
+            let collision = read_struct::<U32le>(hash, h * 4)?;
+            write_struct(hash, h * 4, U32le { v: index })?;
+            write_struct(table, entry_offset + self.eo_collision, collision)?;
+        }
+        Ok(())
+    }
+
     /// Acquire a ticket that represents the entry is being opened.
     /// The ticket can be used to check exclusive access before doing operations such as
     /// deleting the entry.
@@ -327,6 +388,13 @@ pub trait FileInfo: ByteStruct + Clone {
     fn get_next(&self) -> u32;
 }
 
+/// A FileInfo for a file whose data is stored in a file allocation table.
+/// It contains a "block" field which is the first block of the data, or 0x8000_0000 if empty.
+pub trait FatFileInfo: FileInfo {
+    fn set_block(&mut self, block: u32);
+    fn get_block(&self) -> u32;
+}
+
 /// A InfoType used in MetaTable that represents a directory entry.
 /// It contains the following field:
 ///  - sub_dir: index of the first child directory.
@@ -405,6 +473,13 @@ impl<
             files: self.files.stat()?,
         })
     }
+
+    /// Relinks all directory and file entries into the hash tables this is opened with.
+    /// Used after the hash tables are replaced with ones of different bucket counts.
+    pub fn rehash(&self) -> Result<(), Error> {
+        self.dirs.rehash()?;
+        self.files.rehash()
+    }
 }
 
 /// A handle to a file entry in the meta table.
@@ -681,6 +756,295 @@ impl<
     }
 }
 
+/// Something stored in a file allocation table.
+pub enum FatFileOwner {
+    DirTable,
+    FileTable,
+    File(u32),
+}
+
+/// Lists everything stored in the file allocation table, with its first block.
+/// The directory and file tables are listed only if `tables_in_fat` is set.
+pub fn list_fat_files<
+    DirKeyType: ParentedKey,
+    DirInfoType: DirInfo,
+    FileKeyType: ParentedKey,
+    FileInfoType: FatFileInfo,
+>(
+    fs: &Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fs_info: &FsInfo,
+    tables_in_fat: bool,
+) -> Result<Vec<(FatFileOwner, usize)>, Error> {
+    let mut list = vec![];
+    if tables_in_fat {
+        list.push((
+            FatFileOwner::DirTable,
+            fs_info.dir_table.block_index as usize,
+        ));
+        list.push((
+            FatFileOwner::FileTable,
+            fs_info.file_table.block_index as usize,
+        ));
+    }
+    let mut dirs = vec![DirMeta::open_ino(fs.clone(), 1)?];
+    while let Some(dir) = dirs.pop() {
+        for (_, ino) in dir.list_sub_dir()? {
+            dirs.push(DirMeta::open_ino(fs.clone(), ino)?);
+        }
+        for (_, ino) in dir.list_sub_file()? {
+            let block = FileMeta::open_ino(fs.clone(), ino)?.get_info()?.get_block();
+            if block != 0x8000_0000 {
+                list.push((FatFileOwner::File(ino), block as usize));
+            }
+        }
+    }
+    Ok(list)
+}
+
+/// Moves everything stored in `fat` to contiguous runs of blocks, and updates the first blocks
+/// kept in the file entries and, if `tables_in_fat` is set, in the `FsInfo` at `fs_info_file`.
+/// See [`Fat::defragment`](../fat/struct.Fat.html#method.defragment).
+pub fn defragment_fat<
+    DirKeyType: ParentedKey,
+    DirInfoType: DirInfo,
+    FileKeyType: ParentedKey,
+    FileInfoType: FatFileInfo,
+>(
+    fs: &Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fat: &Fat,
+    fs_info_file: &dyn RandomAccessFile,
+    tables_in_fat: bool,
+) -> Result<(), Error> {
+    let mut fs_info: FsInfo = read_struct(fs_info_file, 0)?;
+    let files = list_fat_files(fs, &fs_info, tables_in_fat)?;
+    let first_blocks: Vec<usize> = files.iter().map(|&(_, block)| block).collect();
+    let layout = fat.defragment_layout(&first_blocks)?;
+
+    // Update the pointers first, as the tables are still in their old blocks until moved
+    for ((owner, _), block) in files.into_iter().zip(layout) {
+        match owner {
+            FatFileOwner::DirTable => fs_info.dir_table.block_index = block as u32,
+            FatFileOwner::FileTable => fs_info.file_table.block_index = block as u32,
+            FatFileOwner::File(ino) => {
+                let meta = FileMeta::open_ino(fs.clone(), ino)?;
+                let mut info = meta.get_info()?;
+                info.set_block(block as u32);
+                meta.set_info(info)?;
+            }
+        }
+    }
+    write_struct(fs_info_file, 0, fs_info)?;
+
+    fat.defragment(&first_blocks)
+}
+
+/// Returns the fragmentation statistics of everything stored in `fat`.
+/// See [`list_fat_files`](fn.list_fat_files.html) for `tables_in_fat`.
+pub fn fat_fragmentation<
+    DirKeyType: ParentedKey,
+    DirInfoType: DirInfo,
+    FileKeyType: ParentedKey,
+    FileInfoType: FatFileInfo,
+>(
+    fs: &Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fat: &Fat,
+    fs_info_file: &dyn RandomAccessFile,
+    tables_in_fat: bool,
+) -> Result<Fragmentation, Error> {
+    let fs_info: FsInfo = read_struct(fs_info_file, 0)?;
+    let first_blocks: Vec<usize> = list_fat_files(fs, &fs_info, tables_in_fat)?
+        .into_iter()
+        .map(|(_, block)| block)
+        .collect();
+    fat.fragmentation(&first_blocks)
+}
+
+/// Rebuilds the directory and file hash tables in `partition` with the specified bucket counts,
+/// and relinks all entries into them, so that entries keep their inodes.
+/// `fs_info_file` holds the `FsInfo` of the layout in `partition`, and is updated to the new one.
+///
+/// Everything stored in `fat` is defragmented first, and the allocation table and everything
+/// after it are moved to fit the new hash tables. If `tables_in_fat` is set, the directory and
+/// file tables are stored in `fat`, whose data region follows the allocation table in
+/// `partition`. The blocks in use are moved while the end of the data region stays in place,
+/// so the number of blocks changes with the size of the hash tables. Otherwise the data region
+/// is elsewhere and keeps its blocks, and the directory and file tables follow the allocation
+/// table, within the length of `partition`.
+///
+/// If the content doesn't fit, `Error::NoSpace` is returned and nothing is changed.
+/// `fs` and `fat` are stale afterwards.
+pub fn rehash_in_place<
+    DirKeyType: ParentedKey,
+    DirInfoType: DirInfo,
+    FileKeyType: ParentedKey,
+    FileInfoType: FatFileInfo,
+>(
+    fs: &Rc<FsMeta<DirKeyType, DirInfoType, FileKeyType, FileInfoType>>,
+    fat: &Fat,
+    partition: Rc<dyn RandomAccessFile>,
+    fs_info_file: &dyn RandomAccessFile,
+    tables_in_fat: bool,
+    dir_buckets: usize,
+    file_buckets: usize,
+) -> Result<(), Error> {
+    if dir_buckets == 0 || file_buckets == 0 {
+        return make_error(Error::InvalidValue);
+    }
+
+    let mut fs_info: FsInfo = read_struct(fs_info_file, 0)?;
+    let block_len = fs_info.block_len as usize;
+    let old_block_count = fs_info.data_block_count as usize;
+    let used_blocks = old_block_count - fat.free_blocks();
+    let old_data_offset = fs_info.data_offset as usize;
+    let dir_entry_len = DirKeyType::BYTE_LEN + DirInfoType::BYTE_LEN + 4;
+    let file_entry_len = FileKeyType::BYTE_LEN + FileInfoType::BYTE_LEN + 4;
+    let dir_table_len = (fs_info.max_dir as usize + 2) * dir_entry_len;
+    let file_table_len = (fs_info.max_file as usize + 1) * file_entry_len;
+
+    let dir_hash_offset = fs_info.dir_hash_offset as usize;
+    let file_hash_offset = dir_hash_offset + dir_buckets * 4;
+    let fat_offset = file_hash_offset + file_buckets * 4;
+    let dir_table_offset = fat_offset + (old_block_count + 1) * 8;
+    let file_table_offset = dir_table_offset + dir_table_len;
+
+    let (block_count, data_offset) = if tables_in_fat {
+        // Fit as many blocks as possible before the end of the data region
+        let data_end = old_data_offset + old_block_count * block_len;
+        let mut block_count = data_end.saturating_sub(fat_offset) / (block_len + 8);
+        loop {
+            let data_offset = align_up(fat_offset + (block_count + 1) * 8, block_len);
+            if data_offset + block_count * block_len <= data_end {
+                break (block_count, data_offset);
+            }
+            if block_count == 0 {
+                return make_error(Error::NoSpace);
+            }
+            block_count -= 1;
+        }
+    } else {
+        if file_table_offset + file_table_len > partition.len() {
+            return make_error(Error::NoSpace);
+        }
+        (old_block_count, old_data_offset)
+    };
+    if block_count < used_blocks {
+        return make_error(Error::NoSpace);
+    }
+
+    defragment_fat(fs, fat, fs_info_file, tables_in_fat)?;
+    let fs_info_defragmented: FsInfo = read_struct(fs_info_file, 0)?;
+    let mut first_blocks: Vec<usize> = list_fat_files(fs, &fs_info_defragmented, tables_in_fat)?
+        .into_iter()
+        .map(|(_, block)| block)
+        .collect();
+    first_blocks.sort_unstable();
+    fs_info.dir_table = fs_info_defragmented.dir_table;
+    fs_info.file_table = fs_info_defragmented.file_table;
+
+    let data: Rc<dyn RandomAccessFile> = if tables_in_fat {
+        // Blocks may be uninitialized, such as the unused part of the tables,
+        // which are moved as they read, like in Fat::defragment
+        let move_block = |i: usize| -> Result<(), Error> {
+            let mut buf = vec![0; block_len];
+            match partition.read(old_data_offset + i * block_len, &mut buf) {
+                Ok(()) | Err(Error::HashMismatch) => {}
+                Err(e) => return Err(e),
+            }
+            partition.write(data_offset + i * block_len, &buf)
+        };
+        if data_offset > old_data_offset {
+            (0..used_blocks).rev().try_for_each(move_block)?;
+        } else {
+            (0..used_blocks).try_for_each(move_block)?;
+        }
+        Rc::new(SubFile::new(
+            partition.clone(),
+            data_offset,
+            block_count * block_len,
+        )?)
+    } else {
+        // Only the entries in use are moved, as the rest of a table may be uninitialized.
+        // Both are read first, as the new places may overlap the old ones
+        let read_table = |offset: usize, entry_len: usize| -> Result<Vec<u8>, Error> {
+            let entry_count = read_struct::<U32le>(partition.as_ref(), offset)?.v as usize;
+            let mut buf = vec![0; entry_count * entry_len];
+            partition.read(offset, &mut buf)?;
+            Ok(buf)
+        };
+        let dir_table = read_table(fs_info.dir_table.to_offset() as usize, dir_entry_len)?;
+        let file_table = read_table(fs_info.file_table.to_offset() as usize, file_entry_len)?;
+        partition.write(dir_table_offset, &dir_table)?;
+        partition.write(file_table_offset, &file_table)?;
+        fs_info.dir_table = OffsetOrFatFile::from_offset(dir_table_offset as u64);
+        fs_info.file_table = OffsetOrFatFile::from_offset(file_table_offset as u64);
+        fat.data()
+    };
+
+    // Allocating files in the order of their blocks gives them the same blocks as before
+    let fat_table = Rc::new(SubFile::new(
+        partition.clone(),
+        fat_offset,
+        (block_count + 1) * 8,
+    )?);
+    Fat::format(fat_table.as_ref())?;
+    let fat = Fat::new(fat_table, data, block_len)?;
+    let ends = first_blocks
+        .iter()
+        .skip(1)
+        .cloned()
+        .chain(Some(used_blocks));
+    for (&first_block, end) in first_blocks.iter().zip(ends) {
+        let (_, block) = FatFile::create(fat.clone(), end - first_block)?;
+        if block != first_block {
+            return make_error(Error::BrokenFat);
+        }
+    }
+
+    let (dir_table, file_table): (Rc<dyn RandomAccessFile>, Rc<dyn RandomAccessFile>) =
+        if tables_in_fat {
+            let dir_table_block = fs_info.dir_table.block_index as usize;
+            let file_table_block = fs_info.file_table.block_index as usize;
+            (
+                Rc::new(FatFile::open(fat.clone(), dir_table_block)?),
+                Rc::new(FatFile::open(fat, file_table_block)?),
+            )
+        } else {
+            (
+                Rc::new(SubFile::new(
+                    partition.clone(),
+                    dir_table_offset,
+                    dir_table_len,
+                )?),
+                Rc::new(SubFile::new(
+                    partition.clone(),
+                    file_table_offset,
+                    file_table_len,
+                )?),
+            )
+        };
+
+    fs_info.dir_buckets = dir_buckets as u32;
+    fs_info.file_hash_offset = file_hash_offset as u64;
+    fs_info.file_buckets = file_buckets as u32;
+    fs_info.fat_offset = fat_offset as u64;
+    fs_info.fat_size = block_count as u32;
+    fs_info.data_offset = data_offset as u64;
+    fs_info.data_block_count = block_count as u32;
+    write_struct(fs_info_file, 0, fs_info)?;
+
+    FsMeta::<DirKeyType, DirInfoType, FileKeyType, FileInfoType>::new(
+        Rc::new(SubFile::new(
+            partition.clone(),
+            dir_hash_offset,
+            dir_buckets * 4,
+        )?),
+        dir_table,
+        Rc::new(SubFile::new(partition, file_hash_offset, file_buckets * 4)?),
+        file_table,
+    )?
+    .rehash()
+}
+
 #[cfg(test)]
 mod test {
     use crate::fs_meta::*;
@@ -1107,4 +1471,64 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn meta_rehash() {
+        let mut rng = rand::thread_rng();
+
+        #[derive(ByteStruct, PartialEq, Clone, Debug)]
+        #[byte_struct_le]
+        struct Key {
+            v: u32,
+        }
+
+        #[derive(ByteStruct, PartialEq, Clone, Debug)]
+        #[byte_struct_le]
+        struct Info {
+            v: u32,
+        }
+
+        for _ in 0..100 {
+            let entry_count = rng.gen_range(10, 1000);
+            let buckets = rng.gen_range(1, 10);
+            let hash = Rc::new(MemoryFile::new(vec![0; buckets * 4]));
+            let table = Rc::new(MemoryFile::new(vec![0; entry_count * 16]));
+            MetaTable::<Key, Info>::format(hash.as_ref(), table.as_ref(), entry_count).unwrap();
+            let meta = MetaTable::<Key, Info>::new(hash, table.clone()).unwrap();
+
+            let mut entries = vec![];
+            for v in 0..entry_count as u32 - 1 {
+                let pos = meta.add(Key { v }, Info { v: rng.gen() }).unwrap();
+                entries.push((Key { v }, pos));
+            }
+            entries.retain(|(_, pos)| {
+                if rng.gen() {
+                    meta.remove(*pos).unwrap();
+                    false
+                } else {
+                    true
+                }
+            });
+            let stat = meta.stat().unwrap();
+            assert_eq!(stat.chains.buckets, buckets);
+            assert_eq!(stat.chains.entries, entries.len());
+
+            let buckets = rng.gen_range(1, 100);
+            let hash = Rc::new(MemoryFile::new(vec![0xCC; buckets * 4]));
+            let meta = MetaTable::<Key, Info>::new(hash, table).unwrap();
+            meta.rehash().unwrap();
+            let stat = meta.stat().unwrap();
+            assert_eq!(stat.chains.buckets, buckets);
+            assert_eq!(stat.chains.entries, entries.len());
+            assert_eq!(stat.free, entry_count - 1 - entries.len());
+            for (key, pos) in entries.iter() {
+                assert_eq!(meta.get(key).unwrap().1, *pos);
+            }
+
+            if let Some((key, pos)) = entries.pop() {
+                meta.remove(pos).unwrap();
+                assert_eq!(meta.add(key, Info { v: 0 }).unwrap(), pos);
+            }
+        }
+    }
 }
@@ -0,0 +1,121 @@
+use crate::disk_file::DiskFile;
+use crate::error::*;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use crate::wal_file::WalFile;
+use std::path::*;
+use std::rc::Rc;
+
+/// Implements [`SdNandFileSystem`](../sd_nand_common/trait.SdNandFileSystem.html)
+/// over a directory on the host file system, without any encryption.
+pub struct HostFileSystem {
+    path: PathBuf,
+    atomic: bool,
+}
+
+impl HostFileSystem {
+    /// Uses the directory at `root_path` as the root.
+    pub fn new(root_path: &str) -> HostFileSystem {
+        let path = PathBuf::from(root_path);
+        HostFileSystem {
+            path,
+            atomic: false,
+        }
+    }
+
+    /// Same as [`new`](#method.new), but files are opened as [`WalFile`](../wal_file/struct.WalFile.html),
+    /// so that changes to a file only reach it on commit, and a commit interrupted by a crash
+    /// is completed the next time the file is opened.
+    pub fn new_atomic(root_path: &str) -> HostFileSystem {
+        let path = PathBuf::from(root_path);
+        HostFileSystem { path, atomic: true }
+    }
+
+    fn host_path(&self, path: &[&str]) -> PathBuf {
+        path.iter().fold(self.path.clone(), |a, b| a.join(b))
+    }
+}
+
+impl SdNandFileSystem for HostFileSystem {
+    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        if self.atomic {
+            return Ok(Rc::new(WalFile::open(&self.host_path(path), write)?));
+        }
+
+        let file = DiskFile::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(self.host_path(path))?,
+        )?;
+
+        Ok(Rc::new(file))
+    }
+
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        let file_path = self.host_path(path);
+        std::fs::create_dir_all(file_path.parent().unwrap())?;
+        let f = std::fs::File::create(file_path)?;
+        f.set_len(len as u64)?;
+        Ok(())
+    }
+
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        std::fs::remove_file(self.host_path(path))?;
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        let dir_path = self.host_path(path);
+        if dir_path.exists() {
+            std::fs::remove_dir_all(dir_path)?;
+        }
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error> {
+        let mut dirs = vec![];
+        let mut files = vec![];
+        for entry in std::fs::read_dir(self.host_path(path))? {
+            let entry = entry?;
+            let name = if let Ok(name) = entry.file_name().into_string() {
+                name
+            } else {
+                continue;
+            };
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(name);
+            } else if file_type.is_file() {
+                files.push(name);
+            }
+        }
+        Ok((dirs, files))
+    }
+
+    fn is_dir(&self, path: &[&str]) -> bool {
+        self.host_path(path).is_dir()
+    }
+
+    fn is_file(&self, path: &[&str]) -> bool {
+        self.host_path(path).is_file()
+    }
+
+    fn create_dir(&self, path: &[&str]) -> Result<(), Error> {
+        std::fs::create_dir(self.host_path(path))?;
+        Ok(())
+    }
+
+    fn resize(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(self.host_path(path))?
+            .set_len(len as u64)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &[&str], to: &[&str]) -> Result<(), Error> {
+        std::fs::rename(self.host_path(from), self.host_path(to))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+//! Adapters from the positional file interfaces to `std::io::Read`, `Write` and `Seek`.
+//!
+//! Errors from the underlying file are converted by
+//! [`From<Error> for std::io::Error`](../error/enum.Error.html), so a read touching an
+//! uninitialized or corrupted region fails with `ErrorKind::InvalidData`,
+//! and the cursor stays where it was.
+//!
+//! As a consequence, `read_to_end` and `std::io::copy` fail on a file with any uninitialized
+//! region. This is unlike the extract mode of `save3ds_fuse` and
+//! [`path_helper::copy_to_host`](../path_helper/fn.copy_to_host.html), which copy such regions
+//! as they read.
+
+use crate::error::*;
+use crate::file_system::FileSystemFile;
+use crate::random_access_file::RandomAccessFile;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+/// A file with positional access and a fixed length, which [`Cursor`](struct.Cursor.html) can wrap.
+/// This is implemented for all [`FileSystemFile`](../file_system/trait.FileSystemFile.html)
+/// and for `Rc<dyn RandomAccessFile>`.
+pub trait PositionalFile {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error>;
+    fn write_at(&self, pos: usize, buf: &[u8]) -> Result<(), Error>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn commit(&self) -> Result<(), Error>;
+}
+
+impl<T: FileSystemFile> PositionalFile for T {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.read(pos, buf)
+    }
+    fn write_at(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        self.write(pos, buf)
+    }
+    fn len(&self) -> usize {
+        FileSystemFile::len(self)
+    }
+    fn commit(&self) -> Result<(), Error> {
+        FileSystemFile::commit(self)
+    }
+}
+
+impl PositionalFile for Rc<dyn RandomAccessFile> {
+    fn read_at(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.read(pos, buf)
+    }
+    fn write_at(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        self.write(pos, buf)
+    }
+    fn len(&self) -> usize {
+        RandomAccessFile::len(self.as_ref())
+    }
+    fn commit(&self) -> Result<(), Error> {
+        RandomAccessFile::commit(self.as_ref())
+    }
+}
+
+/// Wraps a file with a position, so that it can be used with `std::io::Read`, `Write` and `Seek`.
+///
+/// The file keeps its length: reading stops at the end, and writing past the end
+/// writes nothing, which `write_all` reports as `ErrorKind::WriteZero`.
+/// Seeking past the end is allowed. `flush` commits the file.
+pub struct Cursor<F> {
+    file: F,
+    pos: u64,
+}
+
+impl<F: PositionalFile> Cursor<F> {
+    /// Wraps `file` with the position at the beginning.
+    pub fn new(file: F) -> Cursor<F> {
+        Cursor { file, pos: 0 }
+    }
+
+    /// Returns the wrapped file.
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+
+    /// Returns a reference to the wrapped file.
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Returns the current position.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the range from the current position that `len` bytes of access can cover.
+    fn range(&self, len: usize) -> (usize, usize) {
+        let file_len = self.file.len();
+        let begin = std::cmp::min(self.pos, file_len as u64) as usize;
+        (begin, begin + std::cmp::min(len, file_len - begin))
+    }
+}
+
+impl<F: PositionalFile> Read for Cursor<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (begin, end) = self.range(buf.len());
+        self.file.read_at(begin, &mut buf[0..end - begin])?;
+        self.pos += (end - begin) as u64;
+        Ok(end - begin)
+    }
+}
+
+impl<F: PositionalFile> Write for Cursor<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (begin, end) = self.range(buf.len());
+        self.file.write_at(begin, &buf[0..end - begin])?;
+        self.pos += (end - begin) as u64;
+        Ok(end - begin)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(self.file.commit()?)
+    }
+}
+
+impl<F: PositionalFile> Seek for Cursor<F> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.file.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let new_pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(new_pos) => {
+                self.pos = new_pos;
+                Ok(new_pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::file_system::*;
+    use crate::io_cursor::*;
+    use crate::memory_file::MemoryFile;
+    use crate::save_data::*;
+
+    #[test]
+    fn cursor() {
+        let file: Rc<dyn RandomAccessFile> = Rc::new(MemoryFile::new(vec![0; 10]));
+        let mut cursor = Cursor::new(file.clone());
+        cursor.write_all(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(cursor.seek(SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(cursor.write(&[5, 6, 7]).unwrap(), 2);
+        assert_eq!(
+            cursor.write_all(&[8]).unwrap_err().kind(),
+            std::io::ErrorKind::WriteZero
+        );
+        cursor.flush().unwrap();
+
+        assert_eq!(cursor.seek(SeekFrom::Current(-8)).unwrap(), 2);
+        let mut buf = vec![];
+        cursor.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4, 0, 0, 0, 0, 5, 6]);
+        assert_eq!(cursor.position(), 10);
+        assert_eq!(cursor.read(&mut [0; 4]).unwrap(), 0);
+        assert_eq!(cursor.seek(SeekFrom::Start(20)).unwrap(), 20);
+        assert_eq!(cursor.read(&mut [0; 4]).unwrap(), 0);
+        assert_eq!(
+            cursor.seek(SeekFrom::Current(-21)).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(cursor.position(), 20);
+
+        let io_error: std::io::Error = Error::HashMismatch.into();
+        assert_eq!(io_error.kind(), std::io::ErrorKind::InvalidData);
+        let io_error: std::io::Error = Error::NotFound.into();
+        assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+    }
+    #[test]
+    fn uninitialized() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: false,
+        };
+        let raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+        SaveData::format(raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(raw, SaveDataType::Bare).unwrap();
+        let file = save
+            .open_root()
+            .unwrap()
+            .new_sub_file([1; 16], 0x2000)
+            .unwrap();
+        file.write(0, &[1; 0x1000]).unwrap();
+
+        let mut cursor = Cursor::new(file);
+        let mut buf = vec![0; 0x1000];
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, vec![1; 0x1000]);
+        assert_eq!(
+            cursor.read(&mut [0; 0x10]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert_eq!(cursor.position(), 0x1000);
+        assert_eq!(
+            cursor.read_to_end(&mut vec![]).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        cursor.write_all(&[2; 0x1000]).unwrap();
+        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![];
+        cursor.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf[0x1000..], [2; 0x1000][..]);
+    }
+}
@@ -45,6 +45,20 @@ impl IvfcLevel {
         })
     }
 
+    /// Recalculates the hashes of all blocks from the data as it is,
+    /// for example after switching the data to another copy.
+    pub fn rehash(
+        hash: Rc<dyn RandomAccessFile>,
+        data: Rc<dyn RandomAccessFile>,
+        block_len: usize,
+    ) -> Result<(), Error> {
+        let mut buf = vec![0; data.len()];
+        data.read(0, &mut buf)?;
+        let level = IvfcLevel::new(hash, data, block_len)?;
+        level.write(0, &buf)?;
+        level.commit()
+    }
+
     pub fn get_status(&self, block_index: usize) -> u8 {
         (self.status.borrow()[block_index / 4] >> ((block_index % 4) * 2)) & 3
     }
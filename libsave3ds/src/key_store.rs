@@ -0,0 +1,185 @@
+use crate::error::*;
+use std::collections::HashMap;
+
+/// Key X of AES engine slot 0x2C, used for NCCH.
+pub const KEY_X_NCCH: &str = "slot0x2CKeyX";
+/// Key X of AES engine slot 0x30, used for save data CMAC.
+pub const KEY_X_SIGN: &str = "slot0x30KeyX";
+/// Key X of AES engine slot 0x34, used for SD encryption.
+pub const KEY_X_DEC: &str = "slot0x34KeyX";
+/// Key X of AES engine slot 0x0B, used for title database CMAC. Normally derived from OTP.
+pub const KEY_X_DB: &str = "slot0x0BKeyX";
+/// Key Y of AES engine slot 0x0B, used for title database CMAC.
+pub const KEY_Y_DB: &str = "slot0x0BKeyY";
+/// Key Y of AES engine slot 0x2F.
+pub const KEY_Y_2F: &str = "slot0x2FKeyY";
+/// Key X of AES engine slot 0x19.
+pub const KEY_X_19: &str = "slot0x19KeyX";
+/// Key X of AES engine slot 0x1A.
+pub const KEY_X_1A: &str = "slot0x1AKeyX";
+/// AES key for decrypting OTP.
+pub const OTP_KEY: &str = "otpKey";
+/// AES IV for decrypting OTP.
+pub const OTP_IV: &str = "otpIV";
+/// The 36-byte salt for deriving keys from OTP.
+pub const OTP_SALT: &str = "otpSalt";
+/// AES IV for deriving keys from OTP.
+pub const OTP_SALT_IV: &str = "otpSaltIV";
+/// The 64-byte block for deriving keys from OTP.
+pub const OTP_SALT_BLOCK: &str = "otpSaltBlock";
+
+/// Locations of keys in the ARM9 bootrom, as (name, offset, length).
+const BOOT9_KEYS: [(&str, usize, usize); 9] = [
+    (KEY_X_NCCH, 0xD9D0, 16),
+    (KEY_X_SIGN, 0xD9E0, 16),
+    (KEY_X_DEC, 0xD9F0, 16),
+    (OTP_KEY, 0xD6E0, 16),
+    (OTP_IV, 0xD6F0, 16),
+    (OTP_SALT, 0xD860, 36),
+    (OTP_SALT_IV, 0xD884, 16),
+    (OTP_SALT_BLOCK, 0xD894, 64),
+    (KEY_Y_DB, 0xDAC0, 16),
+];
+
+/// A collection of named keys.
+///
+/// Keys can be loaded from the ARM9 bootrom, from a text file in the `aes_keys.txt` format
+/// (one `name=hex` pair per line), or set individually.
+/// Names are matched case-insensitively. Later loaded keys replace earlier ones with the same name.
+#[derive(Clone, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, Error> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            std::str::from_utf8(c)
+                .ok()
+                .filter(|c| c.len() == 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .ok_or(Error::InvalidValue)
+        })
+        .collect()
+}
+
+impl KeyStore {
+    /// Creates an empty key store.
+    pub fn new() -> KeyStore {
+        KeyStore::default()
+    }
+
+    /// Loads keys from the content of the ARM9 bootrom.
+    pub fn load_boot9(&mut self, boot9: &[u8]) -> Result<(), Error> {
+        for (name, offset, len) in BOOT9_KEYS.iter() {
+            let key = boot9
+                .get(*offset..*offset + *len)
+                .ok_or(Error::SizeMismatch)?;
+            self.set(name, key);
+        }
+        Ok(())
+    }
+
+    /// Loads keys from the ARM9 bootrom file.
+    pub fn load_boot9_file(&mut self, path: &str) -> Result<(), Error> {
+        self.load_boot9(&std::fs::read(path)?)
+    }
+
+    /// Loads keys from text in the `aes_keys.txt` format.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load_text(&mut self, text: &str) -> Result<(), Error> {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mid = line.find('=').ok_or(Error::InvalidValue)?;
+            let (name, value) = line.split_at(mid);
+            self.set_hex(name.trim(), value[1..].trim())?;
+        }
+        Ok(())
+    }
+
+    /// Loads keys from a file in the `aes_keys.txt` format.
+    pub fn load_text_file(&mut self, path: &str) -> Result<(), Error> {
+        self.load_text(&std::fs::read_to_string(path)?)
+    }
+
+    /// Copies all keys from `other`, replacing existing ones with the same name.
+    pub fn merge(&mut self, other: &KeyStore) {
+        for (name, value) in other.keys.iter() {
+            self.keys.insert(name.clone(), value.clone());
+        }
+    }
+
+    /// Sets a key.
+    pub fn set(&mut self, name: &str, value: &[u8]) {
+        self.keys.insert(name.to_ascii_lowercase(), value.to_vec());
+    }
+
+    /// Sets a key from a hex string.
+    pub fn set_hex(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        let value = parse_hex(value)?;
+        self.set(name, &value);
+        Ok(())
+    }
+
+    /// Returns a key, or `None` if it is not present.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.keys
+            .get(&name.to_ascii_lowercase())
+            .map(|v| v.as_slice())
+    }
+
+    /// Returns a key, which must have length `len`.
+    pub(crate) fn get_bytes(&self, name: &str, len: usize) -> Result<&[u8], Error> {
+        let key = self
+            .get(name)
+            .ok_or_else(|| Error::MissingKey(name.to_owned()))?;
+        if key.len() != len {
+            return make_error(Error::SizeMismatch);
+        }
+        Ok(key)
+    }
+
+    /// Returns a 16-byte key.
+    pub(crate) fn get_key(&self, name: &str) -> Result<[u8; 16], Error> {
+        let mut key = [0; 16];
+        key.copy_from_slice(self.get_bytes(name, 16)?);
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::key_store::*;
+
+    #[test]
+    fn load() {
+        let mut boot9 = vec![0; 0x10000];
+        boot9[0xD9F0] = 0x34;
+        boot9[0xD860 + 35] = 0x5A;
+        let mut keys = KeyStore::new();
+        keys.load_boot9(&boot9).unwrap();
+        assert_eq!(keys.get_key(KEY_X_DEC).unwrap()[0], 0x34);
+        assert_eq!(keys.get_bytes(OTP_SALT, 36).unwrap()[35], 0x5A);
+        assert!(keys.load_boot9(&boot9[0..0xD000]).is_err());
+
+        keys.load_text(
+            "# comment\n\nslot0x34keyx = 000102030405060708090A0B0C0D0E0F\r\nslot0x25KeyX=00",
+        )
+        .unwrap();
+        assert_eq!(
+            keys.get_key(KEY_X_DEC).unwrap(),
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        );
+        assert!(keys.get_key("slot0x25KeyX").is_err());
+        match keys.get_key(KEY_X_19) {
+            Err(Error::MissingKey(name)) => assert_eq!(name, KEY_X_19),
+            _ => panic!(),
+        }
+        assert!(keys.load_text("slot0x34KeyX=0g").is_err());
+        assert!(keys.load_text("slot0x34KeyX").is_err());
+    }
+}
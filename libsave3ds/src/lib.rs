@@ -5,7 +5,7 @@ pub mod db;
 mod diff;
 mod difi_partition;
 mod disa;
-mod disk_file;
+pub mod disk_file;
 mod dpfs_level;
 mod dual_file;
 pub mod error;
@@ -13,18 +13,26 @@ pub mod ext_data;
 mod fat;
 pub mod file_system;
 mod fs_meta;
+pub mod host_file_system;
+pub mod io_cursor;
 mod ivfc_level;
 mod key_engine;
-mod memory_file;
+pub mod key_store;
+pub mod memory_file;
+pub mod memory_file_system;
 mod misc;
-mod nand;
-mod random_access_file;
+pub mod overlay_file_system;
+mod padded_file;
+pub mod path_helper;
+pub mod random_access_file;
 pub mod save_data;
 mod save_ext_common;
 mod sd;
-mod sd_nand_common;
+pub mod sd_nand_common;
+pub mod sd_tree;
 mod signed_file;
 mod sub_file;
+pub mod wal_file;
 mod wear_leveling;
 
 use aes::*;
@@ -33,112 +41,215 @@ use db::*;
 use disk_file::DiskFile;
 use error::*;
 use ext_data::*;
+use host_file_system::HostFileSystem;
 use key_engine::*;
+use key_store::KeyStore;
+use memory_file::MemoryFile;
 use misc::*;
-use nand::Nand;
+use random_access_file::*;
 use save_data::*;
 use sd::Sd;
 use sd_nand_common::*;
+use sd_tree::SdTree;
 use sha2::*;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::*;
+use std::path::Path;
 use std::rc::Rc;
+use wal_file::WalFile;
 
-/// Represents all resource associated with a 3DS console.
-/// Works as the root object to access all archives on the console.
-pub struct Resource {
-    sd: Option<Rc<Sd>>,
-    nand: Option<Rc<Nand>>,
-    key_x_ncch: Option<[u8; 16]>,
-    key_x_dec: Option<[u8; 16]>,
-    key_sign: Option<[u8; 16]>,
-    key_x_sign: Option<[u8; 16]>,
-    key_db: Option<[u8; 16]>,
-    id0: Option<String>,
-    cart_id_short: Option<[u8; 8]>,
-    cart_id_long: Option<[u8; 0x40]>,
-    game_path: Option<String>,
-    x2f_key_y: Option<[u8; 16]>,
-    x19_key_x: Option<[u8; 16]>,
-    x1a_key_x: Option<[u8; 16]>,
+/// The source of an input file of [`ResourceBuilder`](struct.ResourceBuilder.html).
+pub enum FileSource {
+    /// A file on the host file system.
+    Path(String),
+
+    /// The content of the file in memory.
+    Bytes(Vec<u8>),
+
+    /// An opened file.
+    File(Rc<dyn RandomAccessFile>),
 }
 
-impl Resource {
-    /// Initializes all resource associated with a 3DS console.
-    /// All parameters are optional. However, if any archive to open later needs
-    /// a parameter that is not provided, it will fail. The meaning of these parameters are
-    /// - `boot9_path`: the path to the ARM9 bootrom image file.
-    /// - `movable_path`: the path to the `movable.sed` file.
-    /// - `sd_path`: the path to the SD root.
-    /// - `nand_path`: the path to the NAND root.
-    /// - `otp_path`: the path to the encrypted OTP file.
-    /// - `priv_path`: the path to the private header of the cartridge.
-    /// - `game_path`: the path to the game image of the cartridge.
-    /// - `x2f_key_y`: key Y of AES engine slot 0x2F.
-    /// - `x19_key_x`: key X of AES engine slot 0x19.
-    /// - `x1a_key_x`: key X of AES engine slot 0x1A.
-    pub fn new(
-        boot9_path: Option<String>,
-        movable_path: Option<String>,
-        sd_path: Option<String>,
-        nand_path: Option<String>,
-        otp_path: Option<String>,
-        priv_path: Option<String>,
-        game_path: Option<String>,
+impl FileSource {
+    fn open(self) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        Ok(match self {
+            FileSource::Path(path) => Rc::new(DiskFile::new(std::fs::File::open(path)?)?),
+            FileSource::Bytes(data) => Rc::new(MemoryFile::new(data)),
+            FileSource::File(file) => file,
+        })
+    }
+}
+
+/// Collects all resource associated with a 3DS console and builds a [`Resource`](struct.Resource.html).
+///
+/// All inputs are optional. However, if any archive to open later needs
+/// an input that is not provided, it will fail.
+#[derive(Default)]
+pub struct ResourceBuilder {
+    keys: KeyStore,
+    boot9: Option<FileSource>,
+    movable: Option<FileSource>,
+    otp: Option<FileSource>,
+    private_header: Option<FileSource>,
+    game: Option<FileSource>,
+    sd: Option<Rc<dyn SdNandFileSystem>>,
+    sd_path: Option<String>,
+    sd_id1: Option<String>,
+    nand: Option<Rc<dyn SdNandFileSystem>>,
+    nand_path: Option<String>,
+    atomic: bool,
+}
+
+impl ResourceBuilder {
+    pub fn new() -> ResourceBuilder {
+        ResourceBuilder::default()
+    }
+
+    /// Sets the ARM9 bootrom image. Keys from it are overridden by those set by
+    /// [`keys`](#method.keys), [`key`](#method.key) and [`console_keys`](#method.console_keys).
+    pub fn boot9(mut self, source: FileSource) -> ResourceBuilder {
+        self.boot9 = Some(source);
+        self
+    }
+
+    /// Sets the `movable.sed` file. If not provided, the one in the NAND root is used.
+    pub fn movable(mut self, source: FileSource) -> ResourceBuilder {
+        self.movable = Some(source);
+        self
+    }
+
+    /// Sets the encrypted OTP.
+    pub fn otp(mut self, source: FileSource) -> ResourceBuilder {
+        self.otp = Some(source);
+        self
+    }
+
+    /// Sets the private header of the cartridge.
+    pub fn private_header(mut self, source: FileSource) -> ResourceBuilder {
+        self.private_header = Some(source);
+        self
+    }
+
+    /// Sets the game image of the cartridge.
+    pub fn game(mut self, source: FileSource) -> ResourceBuilder {
+        self.game = Some(source);
+        self
+    }
+
+    /// Sets the path to the SD root.
+    pub fn sd(mut self, path: &str) -> ResourceBuilder {
+        self.sd = None;
+        self.sd_path = Some(path.to_owned());
+        self
+    }
+
+    /// Sets the storage of the SD root, in place of a host directory.
+    pub fn sd_file_system(mut self, file_system: Rc<dyn SdNandFileSystem>) -> ResourceBuilder {
+        self.sd = Some(file_system);
+        self.sd_path = None;
+        self
+    }
+
+    /// Sets the name of the ID1 directory on SD to use.
+    /// If not provided, the first one in alphabetical order is used.
+    pub fn sd_id1(mut self, id1: &str) -> ResourceBuilder {
+        self.sd_id1 = Some(id1.to_owned());
+        self
+    }
+
+    /// Sets the path to the NAND root.
+    pub fn nand(mut self, path: &str) -> ResourceBuilder {
+        self.nand = None;
+        self.nand_path = Some(path.to_owned());
+        self
+    }
+
+    /// Sets the storage of the NAND root, in place of a host directory.
+    pub fn nand_file_system(mut self, file_system: Rc<dyn SdNandFileSystem>) -> ResourceBuilder {
+        self.nand = Some(file_system);
+        self.nand_path = None;
+        self
+    }
+
+    /// Sets whether archives in host files are committed atomically. Defaults to `false`.
+    ///
+    /// If enabled, changes to host files are staged in a write-ahead log until the archive is
+    /// committed, and a commit interrupted by a crash is completed the next time the file is
+    /// opened for writing. See [`WalFile`](wal_file/struct.WalFile.html). This applies to the SD and NAND
+    /// roots given by path, and to save data files given by path to [`Resource`](struct.Resource.html).
+    pub fn atomic(mut self, atomic: bool) -> ResourceBuilder {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Adds all keys from a key store.
+    pub fn keys(mut self, keys: &KeyStore) -> ResourceBuilder {
+        self.keys.merge(keys);
+        self
+    }
+
+    /// Adds a key. See [`key_store`](key_store/index.html) for key names.
+    pub fn key(mut self, name: &str, value: &[u8]) -> ResourceBuilder {
+        self.keys.set(name, value);
+        self
+    }
+
+    /// Adds key Y of AES engine slot 0x2F, key X of slot 0x19 and key X of slot 0x1A,
+    /// each only if provided.
+    pub fn console_keys(
+        mut self,
         x2f_key_y: Option<[u8; 16]>,
         x19_key_x: Option<[u8; 16]>,
         x1a_key_x: Option<[u8; 16]>,
-    ) -> Result<Resource, Error> {
-        let (key_x_ncch, key_x_sign, key_x_dec, key_otp, iv_otp, otp_salt, key_y_db) =
-            if let Some(boot9) = boot9_path {
-                let mut boot9 = std::fs::File::open(boot9)?;
-                let mut key_x_ncch = [0; 16];
-                let mut key_x_sign = [0; 16];
-                let mut key_x_dec = [0; 16];
-                let mut key_otp = [0; 16];
-                let mut iv_otp = [0; 16];
-                let mut otp_salt = [0; 36];
-                let mut otp_salt_iv = [0; 16];
-                let mut otp_salt_block = [0; 64];
-                let mut key_y_db = [0; 16];
-                boot9.seek(SeekFrom::Start(0xD9D0))?;
-                boot9.read_exact(&mut key_x_ncch)?;
-                boot9.read_exact(&mut key_x_sign)?;
-                boot9.read_exact(&mut key_x_dec)?;
-                boot9.seek(SeekFrom::Start(0xD6E0))?;
-                boot9.read_exact(&mut key_otp)?;
-                boot9.read_exact(&mut iv_otp)?;
-                boot9.seek(SeekFrom::Start(0xD860))?;
-                boot9.read_exact(&mut otp_salt)?;
-                boot9.read_exact(&mut otp_salt_iv)?;
-                boot9.read_exact(&mut otp_salt_block)?;
-                boot9.seek(SeekFrom::Start(0xDAC0))?;
-                boot9.read_exact(&mut key_y_db)?;
-                (
-                    Some(key_x_ncch),
-                    Some(key_x_sign),
-                    Some(key_x_dec),
-                    Some(key_otp),
-                    Some(iv_otp),
-                    Some((otp_salt, otp_salt_iv, otp_salt_block)),
-                    Some(key_y_db),
-                )
+    ) -> ResourceBuilder {
+        for (name, key) in [
+            (key_store::KEY_Y_2F, x2f_key_y),
+            (key_store::KEY_X_19, x19_key_x),
+            (key_store::KEY_X_1A, x1a_key_x),
+        ]
+        .iter()
+        {
+            if let Some(key) = key {
+                self.keys.set(name, key);
+            }
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<Resource, Error> {
+        let atomic = self.atomic;
+        let host_file_system = |path: String| -> Rc<dyn SdNandFileSystem> {
+            if atomic {
+                Rc::new(HostFileSystem::new_atomic(&path))
             } else {
-                (None, None, None, None, None, None, None)
-            };
+                Rc::new(HostFileSystem::new(&path))
+            }
+        };
+        let mut keys = KeyStore::new();
+        if let Some(boot9) = self.boot9 {
+            let boot9 = boot9.open()?;
+            let mut data = vec![0; boot9.len()];
+            boot9.read(0, &mut data)?;
+            keys.load_boot9(&data)?;
+        }
+        keys.merge(&self.keys);
 
-        let movable = if let Some(nand_path) = &nand_path {
-            Some(PathBuf::from(nand_path).join("private").join("movable.sed"))
+        let nand = match self.nand_path {
+            Some(path) => Some(host_file_system(path)),
+            None => self.nand,
+        };
+        let movable = if let Some(movable) = self.movable {
+            Some(movable.open()?)
+        } else if let Some(nand) = &nand {
+            Some(nand.open(&["private", "movable.sed"], false)?)
         } else {
-            movable_path.map(|s| Path::new(&s).to_owned())
+            None
         };
 
-        let (cart_id_short, cart_id_long) = if let Some(priv_path) = priv_path {
-            let mut priv_header = std::fs::File::open(priv_path)?;
+        let (cart_id_short, cart_id_long) = if let Some(private_header) = self.private_header {
+            let private_header = private_header.open()?;
             let mut cart_id_short = [0; 8];
             let mut cart_id_long = [0; 0x40];
-            priv_header.read_exact(&mut cart_id_long)?;
-            priv_header.read_exact(&mut cart_id_short)?;
+            private_header.read(0, &mut cart_id_long)?;
+            private_header.read(0x40, &mut cart_id_short)?;
             (Some(cart_id_short), Some(cart_id_long))
         } else {
             (None, None)
@@ -146,9 +257,7 @@ impl Resource {
 
         let key_y = if let Some(movable) = movable {
             let mut key_y = [0; 16];
-            let mut movable = std::fs::File::open(&movable)?;
-            movable.seek(SeekFrom::Start(0x110))?;
-            movable.read_exact(&mut key_y)?;
+            movable.read(0x110, &mut key_y)?;
             Some(key_y)
         } else {
             None
@@ -156,26 +265,23 @@ impl Resource {
 
         let id0 = key_y.map(hash_movable);
 
-        let key_sign = (|| Some(scramble(key_x_sign?, key_y?)))();
-
-        let sd = if let (Some(sd), Some(x), Some(y)) = (sd_path, key_x_dec, key_y) {
-            Some(Rc::new(Sd::new(&sd, x, y)?))
-        } else {
-            None
+        let sd = match self.sd_path {
+            Some(path) => Some(host_file_system(path)),
+            None => self.sd,
         };
-
-        let nand = if let Some(nand_path) = nand_path {
-            Some(Rc::new(Nand::new(&nand_path)?))
+        let has_sd = sd.is_some();
+        let sd = if let (Some(sd), Ok(x), Some(y)) = (sd, keys.get_key(key_store::KEY_X_DEC), key_y)
+        {
+            Some(Rc::new(Sd::new(sd, self.sd_id1.as_deref(), x, y)?))
         } else {
             None
         };
 
-        let key_x_db = if let Some(otp_path) = otp_path {
-            let key_otp = key_otp.ok_or(Error::MissingBoot9)?;
-            let mut iv_otp = iv_otp.ok_or(Error::MissingBoot9)?;
-            let mut otp_file = std::fs::File::open(otp_path)?;
+        if let Some(otp_file) = self.otp {
+            let key_otp = keys.get_key(key_store::OTP_KEY)?;
+            let mut iv_otp = keys.get_key(key_store::OTP_IV)?;
             let mut otp = [0; 0x100];
-            otp_file.read_exact(&mut otp)?;
+            otp_file.open()?.read(0, &mut otp)?;
             let aes128 = Aes128::new(key_otp[..].into());
             for block in otp.chunks_exact_mut(0x10) {
                 let mut pad = [0; 16];
@@ -193,11 +299,13 @@ impl Resource {
                 return make_error(Error::BrokenOtp);
             }
 
-            let (otp_salt, mut otp_salt_iv, mut otp_salt_block) =
-                otp_salt.ok_or(Error::MissingBoot9)?;
+            let otp_salt = keys.get_bytes(key_store::OTP_SALT, 36)?;
+            let mut otp_salt_iv = keys.get_key(key_store::OTP_SALT_IV)?;
+            let mut otp_salt_block = [0; 64];
+            otp_salt_block.copy_from_slice(keys.get_bytes(key_store::OTP_SALT_BLOCK, 64)?);
             let mut hasher = Sha256::new();
             hasher.update(&otp[0x90..0xAC]);
-            hasher.update(&otp_salt[..]);
+            hasher.update(otp_salt);
             let hash = hasher.finalize();
             let mut key_x = [0; 16];
             let mut key_y = [0; 16];
@@ -214,40 +322,217 @@ impl Resource {
                 otp_salt_iv.copy_from_slice(&block);
             }
 
-            let mut key_x_db = [0; 16];
-            key_x_db.copy_from_slice(&otp_salt_block[16..32]);
-            Some(key_x_db)
-        } else {
-            None
-        };
+            keys.set(key_store::KEY_X_DB, &otp_salt_block[16..32]);
+        }
 
-        let key_db = (|| Some(scramble(key_x_db?, key_y_db?)))();
+        let game = self.game.map(FileSource::open).transpose()?;
 
         Ok(Resource {
             sd,
+            has_sd,
             nand,
-            key_x_ncch,
-            key_x_dec,
-            key_sign,
-            key_x_sign,
-            key_db,
+            keys,
+            movable_key_y: key_y,
             id0,
             cart_id_short,
             cart_id_long,
-            game_path,
-            x2f_key_y,
-            x19_key_x,
-            x1a_key_x,
+            game,
+            atomic,
         })
     }
+}
+
+/// Splits a path relative to the ID1 directory on SD into names.
+/// `.` and `..` are rejected, so that the path can't refer to anything outside of the directory.
+fn split_sd_path(path: &str) -> Result<Vec<&str>, Error> {
+    let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if path.iter().any(|&name| name == "." || name == "..") {
+        return make_error(Error::InvalidValue);
+    }
+    Ok(path)
+}
+
+/// Represents all resource associated with a 3DS console.
+/// Works as the root object to access all archives on the console.
+pub struct Resource {
+    sd: Option<Rc<Sd>>,
+    has_sd: bool,
+    nand: Option<Rc<dyn SdNandFileSystem>>,
+    keys: KeyStore,
+    movable_key_y: Option<[u8; 16]>,
+    id0: Option<String>,
+    cart_id_short: Option<[u8; 8]>,
+    cart_id_long: Option<[u8; 0x40]>,
+    game: Option<Rc<dyn RandomAccessFile>>,
+    atomic: bool,
+}
+
+impl Resource {
+    /// Initializes all resource associated with a 3DS console.
+    /// All parameters are optional. However, if any archive to open later needs
+    /// a parameter that is not provided, it will fail. The meaning of these parameters are
+    /// - `boot9_path`: the path to the ARM9 bootrom image file.
+    /// - `movable_path`: the path to the `movable.sed` file.
+    /// - `sd_path`: the path to the SD root.
+    /// - `nand_path`: the path to the NAND root.
+    /// - `otp_path`: the path to the encrypted OTP file.
+    /// - `priv_path`: the path to the private header of the cartridge.
+    /// - `game_path`: the path to the game image of the cartridge.
+    /// - `x2f_key_y`: key Y of AES engine slot 0x2F.
+    /// - `x19_key_x`: key X of AES engine slot 0x19.
+    /// - `x1a_key_x`: key X of AES engine slot 0x1A.
+    ///
+    /// This is a shorthand for [`ResourceBuilder`](struct.ResourceBuilder.html),
+    /// which also accepts these inputs from memory.
+    pub fn new(
+        boot9_path: Option<String>,
+        movable_path: Option<String>,
+        sd_path: Option<String>,
+        nand_path: Option<String>,
+        otp_path: Option<String>,
+        priv_path: Option<String>,
+        game_path: Option<String>,
+        x2f_key_y: Option<[u8; 16]>,
+        x19_key_x: Option<[u8; 16]>,
+        x1a_key_x: Option<[u8; 16]>,
+    ) -> Result<Resource, Error> {
+        let mut builder = ResourceBuilder::new().console_keys(x2f_key_y, x19_key_x, x1a_key_x);
+        if let Some(boot9_path) = boot9_path {
+            builder = builder.boot9(FileSource::Path(boot9_path));
+        }
+        // The movable.sed in NAND takes precedence for compatibility.
+        if let Some(nand_path) = nand_path {
+            builder = builder.nand(&nand_path);
+        } else if let Some(movable_path) = movable_path {
+            builder = builder.movable(FileSource::Path(movable_path));
+        }
+        if let Some(sd_path) = sd_path {
+            builder = builder.sd(&sd_path);
+        }
+        if let Some(otp_path) = otp_path {
+            builder = builder.otp(FileSource::Path(otp_path));
+        }
+        if let Some(priv_path) = priv_path {
+            builder = builder.private_header(FileSource::Path(priv_path));
+        }
+        if let Some(game_path) = game_path {
+            builder = builder.game(FileSource::Path(game_path));
+        }
+        builder.build()
+    }
+
+    /// Opens a host file holding an archive, as a `WalFile` in atomic mode.
+    fn open_host_file(&self, path: &str, write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        if self.atomic {
+            return Ok(Rc::new(WalFile::open(Path::new(path), write)?));
+        }
+        Ok(Rc::new(DiskFile::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(path)?,
+        )?))
+    }
+
+    /// Builds a host file of `len` bytes with `build` in a temporary file, and moves it to `path`
+    /// when done, so that an existing file at `path` is left intact on failure.
+    fn replace_host_file(
+        &self,
+        path: &str,
+        len: usize,
+        build: FileBuilder<'_>,
+    ) -> Result<(), Error> {
+        let temp_path = format!("{}.tmp", path);
+        let result = std::fs::File::create(&temp_path)
+            .and_then(|file| file.set_len(len as u64))
+            .map_err(Error::from)
+            .and_then(|()| {
+                let file = self.open_host_file(&temp_path, true)?;
+                build(file.clone())?;
+                file.commit()
+            });
+        if let Err(e) = result {
+            if Path::new(&temp_path).exists() {
+                std::fs::remove_file(&temp_path)?;
+            }
+            return Err(e);
+        }
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Returns the SD. If it was given but can't be decrypted, the missing key is reported
+    /// here rather than when building, so that other archives can still be opened.
+    fn sd(&self) -> Result<&Rc<Sd>, Error> {
+        if let Some(sd) = &self.sd {
+            return Ok(sd);
+        }
+        if self.has_sd {
+            self.keys.get_key(key_store::KEY_X_DEC)?;
+        }
+        make_error(Error::MissingSd)
+    }
+
+    fn key_sign(&self) -> Result<[u8; 16], Error> {
+        Ok(scramble(
+            self.keys.get_key(key_store::KEY_X_SIGN)?,
+            self.movable_key_y.ok_or(Error::MissingSd)?,
+        ))
+    }
+
+    fn key_db(&self) -> Result<[u8; 16], Error> {
+        Ok(scramble(
+            self.keys.get_key(key_store::KEY_X_DB)?,
+            self.keys.get_key(key_store::KEY_Y_DB)?,
+        ))
+    }
+
+    /// Lists all ID1 directories on SD, in alphabetical order.
+    /// More than one of them can exist if the SD has been used with multiple consoles
+    /// or the console has been formatted.
+    pub fn list_sd_id1(&self) -> Result<Vec<String>, Error> {
+        self.sd()?.list_id1()
+    }
+
+    /// Returns the name of the ID1 directory on SD in use.
+    pub fn sd_id1(&self) -> Result<String, Error> {
+        Ok(self.sd()?.id1().to_owned())
+    }
+
+    /// Opens a file under the `Nintendo 3DS/<ID0>/<ID1>` directory on SD
+    /// with transparent decryption and encryption.
+    /// - `path`: the path relative to the ID1 directory, separated by `'/'`,
+    ///   e.g. `"title/00040000/00164800/content/00000000.tmd"`.
+    ///   `.` and `..` are not allowed in the path.
+    pub fn open_sd_file(
+        &self,
+        path: &str,
+        write: bool,
+    ) -> Result<Rc<dyn random_access_file::RandomAccessFile>, Error> {
+        let path = split_sd_path(path)?;
+        self.sd()?.open(&path, write)
+    }
+
+    /// Creates a file under the `Nintendo 3DS/<ID0>/<ID1>` directory on SD with length `len`,
+    /// replacing the existing one. Use [`open_sd_file`](#method.open_sd_file) to write the content.
+    pub fn create_sd_file(&self, path: &str, len: usize) -> Result<(), Error> {
+        let path = split_sd_path(path)?;
+        self.sd()?.create(&path, len)
+    }
+
+    /// Opens the `Nintendo 3DS/<ID0>/<ID1>` directory on SD as a file system,
+    /// with all files transparently decrypted and encrypted.
+    pub fn open_sd_tree(&self, write: bool) -> Result<SdTree, Error> {
+        Ok(SdTree::new(self.sd()?.clone(), write))
+    }
 
     /// Formats an extdata on SD.
     pub fn format_sd_ext(&self, id: u64, param: &ExtDataFormatParam) -> Result<(), Error> {
         ExtData::format(
-            self.sd.as_ref().ok_or(Error::MissingSd)?.as_ref(),
+            self.sd()?.as_ref(),
             &["extdata"],
             id,
-            self.key_sign.ok_or(Error::MissingBoot9)?,
+            self.key_sign()?,
             None,
             param,
         )
@@ -256,10 +541,10 @@ impl Resource {
     /// Opens an extdata on SD.
     pub fn open_sd_ext(&self, id: u64, write: bool) -> Result<ExtData, Error> {
         ExtData::new(
-            self.sd.as_ref().ok_or(Error::MissingSd)?.clone(),
+            self.sd()?.clone(),
             &["extdata"],
             id,
-            self.key_sign.ok_or(Error::MissingBoot9)?,
+            self.key_sign()?,
             false,
             write,
         )
@@ -276,37 +561,93 @@ impl Resource {
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
         let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
 
-        let sd = self.sd.as_ref().ok_or(Error::MissingSd)?;
+        let sd = self.sd()?;
         sd.create(&sub_path, len)?;
         let file = sd.open(&sub_path, true)?;
 
-        SaveData::format(
-            file,
-            SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id),
-            &param,
-        )?;
+        SaveData::format(file, SaveDataType::Sd(self.key_sign()?, id), &param)?;
 
         Ok(())
     }
 
+    fn open_sd_save_file(&self, id: u64, write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        let id_high = format!("{:08x}", id >> 32);
+        let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
+        let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
+
+        self.sd()?.open(&sub_path, write)
+    }
+
     /// Opens a save data on SD.
     pub fn open_sd_save(&self, id: u64, write: bool) -> Result<SaveData, Error> {
+        let dec_file = self.open_sd_save_file(id, write)?;
+        SaveData::new(dec_file, SaveDataType::Sd(self.key_sign()?, id))
+    }
+
+    /// Opens the version of a save data on SD before its last commit, as read-only.
+    ///
+    /// Save data keeps two copies of everything and only switches to the new copy on commit,
+    /// so the copy of the previous version stays intact until the save data is written again.
+    /// The exception is file data of save data formatted without `duplicate_data`, which is
+    /// written in place. The content is verified, so a version that has been partially
+    /// overwritten fails to open or read with `Error::HashMismatch`.
+    pub fn open_sd_save_previous(&self, id: u64) -> Result<SaveData, Error> {
+        let dec_file = self.open_sd_save_file(id, false)?;
+        SaveData::new_previous(dec_file, SaveDataType::Sd(self.key_sign()?, id))
+    }
+
+    /// Makes the version of a save data on SD before its last commit the current one.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    /// Running this again switches back.
+    pub fn restore_sd_save(&self, id: u64) -> Result<(), Error> {
+        let dec_file = self.open_sd_save_file(id, true)?;
+        SaveData::new_previous(dec_file, SaveDataType::Sd(self.key_sign()?, id))?.restore_previous()
+    }
+
+    /// Changes the size and the format parameters of a save data on SD, keeping its content.
+    ///
+    /// The save data is rebuilt with the new parameters in a temporary file next to it, which then
+    /// replaces the original. All files and directories are copied across in the same order,
+    /// so the directory listing stays the same.
+    /// Inode numbers are not kept. If the content doesn't fit, `Error::NoSpace` is returned and
+    /// the save data is left untouched.
+    pub fn resize_sd_save(
+        &self,
+        id: u64,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<(), Error> {
+        let save_data_type = SaveDataType::Sd(self.key_sign()?, id);
+        let save = self.open_sd_save(id, false)?;
+
         let id_high = format!("{:08x}", id >> 32);
         let id_low = format!("{:08x}", id & 0xFFFF_FFFF);
         let sub_path = ["title", &id_high, &id_low, "data", "00000001.sav"];
 
-        let dec_file = self
-            .sd
-            .as_ref()
-            .ok_or(Error::MissingSd)?
-            .open(&sub_path, write)?;
-
-        SaveData::new(
-            dec_file,
-            SaveDataType::Sd(self.key_sign.ok_or(Error::MissingBoot9)?, id),
+        self.sd()?.replace(
+            &sub_path,
+            len,
+            Box::new(move |file| save.reconfigure(file, save_data_type, param)),
         )
     }
 
+    /// Rebuilds the directory and file hash tables of a save data on SD with the specified
+    /// bucket counts. See [`Stat::dir_hash`](file_system/struct.Stat.html#structfield.dir_hash)
+    /// and [`Stat::file_hash`](file_system/struct.Stat.html#structfield.file_hash)
+    /// for how entries are distributed over the current buckets.
+    ///
+    /// The tables are rebuilt in place, and entries keep their inodes.
+    /// See [`SaveData::rehash`](save_data/struct.SaveData.html#method.rehash).
+    pub fn rehash_sd_save(
+        &self,
+        id: u64,
+        dir_buckets: usize,
+        file_buckets: usize,
+    ) -> Result<(), Error> {
+        self.open_sd_save(id, true)?
+            .rehash(dir_buckets, file_buckets)
+    }
+
     /// Formats a save data on NAND.
     pub fn format_nand_save(
         &self,
@@ -326,18 +667,13 @@ impl Resource {
         nand.create(&sub_path, len)?;
         let file = nand.open(&sub_path, true)?;
 
-        SaveData::format(
-            file,
-            SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id),
-            &param,
-        )?;
+        SaveData::format(file, SaveDataType::Nand(self.key_sign()?, id), &param)?;
 
         Ok(())
     }
 
-    /// Opens a save data on NAND.
-    pub fn open_nand_save(&self, id: u32, write: bool) -> Result<SaveData, Error> {
-        let file = self.nand.as_ref().ok_or(Error::MissingNand)?.open(
+    fn open_nand_save_file(&self, id: u32, write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        self.nand.as_ref().ok_or(Error::MissingNand)?.open(
             &[
                 "data",
                 self.id0.as_ref().ok_or(Error::MissingNand)?,
@@ -346,15 +682,80 @@ impl Resource {
                 "00000000",
             ],
             write,
-        )?;
-        SaveData::new(
-            file,
-            SaveDataType::Nand(self.key_sign.ok_or(Error::MissingBoot9)?, id),
         )
     }
 
-    /// Formats an extdata on NAND.
+    /// Opens a save data on NAND.
+    pub fn open_nand_save(&self, id: u32, write: bool) -> Result<SaveData, Error> {
+        let file = self.open_nand_save_file(id, write)?;
+        SaveData::new(file, SaveDataType::Nand(self.key_sign()?, id))
+    }
+
+    /// Opens the version of a save data on NAND before its last commit, as read-only.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn open_nand_save_previous(&self, id: u32) -> Result<SaveData, Error> {
+        let file = self.open_nand_save_file(id, false)?;
+        SaveData::new_previous(file, SaveDataType::Nand(self.key_sign()?, id))
+    }
+
+    /// Makes the version of a save data on NAND before its last commit the current one.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn restore_nand_save(&self, id: u32) -> Result<(), Error> {
+        let file = self.open_nand_save_file(id, true)?;
+        SaveData::new_previous(file, SaveDataType::Nand(self.key_sign()?, id))?.restore_previous()
+    }
+
+    /// Changes the size and the format parameters of a save data on NAND, keeping its content.
+    /// See [`resize_sd_save`](#method.resize_sd_save).
+    pub fn resize_nand_save(
+        &self,
+        id: u32,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<(), Error> {
+        let save_data_type = SaveDataType::Nand(self.key_sign()?, id);
+        let save = self.open_nand_save(id, false)?;
+
+        let sub_path = [
+            "data",
+            self.id0.as_ref().ok_or(Error::MissingNand)?,
+            "sysdata",
+            &format!("{:08x}", id),
+            "00000000",
+        ];
+
+        self.nand.as_ref().ok_or(Error::MissingNand)?.replace(
+            &sub_path,
+            len,
+            Box::new(move |file| save.reconfigure(file, save_data_type, param)),
+        )
+    }
+
+    /// Rebuilds the directory and file hash tables of a save data on NAND.
+    /// See [`rehash_sd_save`](#method.rehash_sd_save).
+    pub fn rehash_nand_save(
+        &self,
+        id: u32,
+        dir_buckets: usize,
+        file_buckets: usize,
+    ) -> Result<(), Error> {
+        self.open_nand_save(id, true)?
+            .rehash(dir_buckets, file_buckets)
+    }
+
+    /// Formats an extdata on NAND, with a quota of 1024 * 1024 blocks.
     pub fn format_nand_ext(&self, id: u64, param: &ExtDataFormatParam) -> Result<(), Error> {
+        self.format_nand_ext_with_capacity(id, param, 1024 * 1024)
+    }
+
+    /// Formats an extdata on NAND, with a quota of `capacity` blocks.
+    /// See [`ExtData::quota`](ext_data/struct.ExtData.html#method.quota).
+    pub fn format_nand_ext_with_capacity(
+        &self,
+        id: u64,
+        param: &ExtDataFormatParam,
+        capacity: u32,
+    ) -> Result<(), Error> {
         ExtData::format(
             self.nand.as_ref().ok_or(Error::MissingNand)?.as_ref(),
             &[
@@ -363,8 +764,8 @@ impl Resource {
                 "extdata",
             ],
             id,
-            self.key_sign.ok_or(Error::MissingBoot9)?,
-            Some(1024 * 1024),
+            self.key_sign()?,
+            Some(capacity),
             param,
         )
     }
@@ -379,7 +780,7 @@ impl Resource {
                 "extdata",
             ],
             id,
-            self.key_sign.ok_or(Error::MissingBoot9)?,
+            self.key_sign()?,
             true,
             write,
         )
@@ -399,13 +800,18 @@ impl Resource {
     ) -> Result<(), Error> {
         std::fs::File::create(path)?.set_len(len as u64)?;
 
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, true)?;
 
+        self.format_bare_save_file(file, param)
+    }
+
+    /// Same as [`format_bare_save`](#method.format_bare_save), but on the given file.
+    /// The whole file is used for the save data.
+    pub fn format_bare_save_file(
+        &self,
+        file: Rc<dyn RandomAccessFile>,
+        param: &SaveDataFormatParam,
+    ) -> Result<(), Error> {
         SaveData::format(file, SaveDataType::Bare, &param)?;
 
         Ok(())
@@ -418,24 +824,63 @@ impl Resource {
     /// fixed using other tools to be usable on 3DS. Because of this limitation, this function is
     /// mostly for test purpose.
     pub fn open_bare_save(&self, path: &str, write: bool) -> Result<SaveData, Error> {
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, write)?;
 
+        self.open_bare_save_file(file)
+    }
+
+    /// Opens a stand-alone save data from the given file.
+    pub fn open_bare_save_file(&self, file: Rc<dyn RandomAccessFile>) -> Result<SaveData, Error> {
         SaveData::new(file, SaveDataType::Bare)
     }
 
+    /// Opens the version of a stand-alone save data before its last commit, as read-only.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn open_bare_save_previous(&self, path: &str) -> Result<SaveData, Error> {
+        let file = self.open_host_file(path, false)?;
+        SaveData::new_previous(file, SaveDataType::Bare)
+    }
+
+    /// Makes the version of a stand-alone save data before its last commit the current one.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn restore_bare_save(&self, path: &str) -> Result<(), Error> {
+        let file = self.open_host_file(path, true)?;
+        SaveData::new_previous(file, SaveDataType::Bare)?.restore_previous()
+    }
+
+    /// Changes the size and the format parameters of a stand-alone save data,
+    /// keeping its content. See [`resize_sd_save`](#method.resize_sd_save).
+    pub fn resize_bare_save(
+        &self,
+        path: &str,
+        param: &SaveDataFormatParam,
+        len: usize,
+    ) -> Result<(), Error> {
+        let save = self.open_bare_save(path, false)?;
+        self.replace_host_file(
+            path,
+            len,
+            Box::new(move |file| save.reconfigure(file, SaveDataType::Bare, param)),
+        )
+    }
+
+    /// Rebuilds the directory and file hash tables of a stand-alone save data.
+    /// See [`rehash_sd_save`](#method.rehash_sd_save).
+    pub fn rehash_bare_save(
+        &self,
+        path: &str,
+        dir_buckets: usize,
+        file_buckets: usize,
+    ) -> Result<(), Error> {
+        self.open_bare_save(path, true)?
+            .rehash(dir_buckets, file_buckets)
+    }
+
     fn get_cart_format(&self) -> Result<CartFormat, Error> {
-        let game = disk_file::DiskFile::new(std::fs::File::open(
-            self.game_path.as_ref().ok_or(Error::MissingGame)?,
-        )?)?;
+        let game = self.game.clone().ok_or(Error::MissingGame)?;
 
         use byte_struct_common::*;
-        use random_access_file::*;
-        if read_struct::<Magic>(&game, 0x100)?.v != *b"NCSD" {
+        if read_struct::<Magic>(game.as_ref(), 0x100)?.v != *b"NCSD" {
             return Err(Error::BrokenGame);
         }
 
@@ -448,9 +893,9 @@ impl Resource {
             _ => return Err(Error::BrokenGame),
         };
 
-        let cxi_offset = read_struct::<U32le>(&game, 0x120)?.v * 0x200;
-        let cxi_len = read_struct::<U32le>(&game, 0x124)?.v * 0x200;
-        let cxi = sub_file::SubFile::new(Rc::new(game), cxi_offset as usize, cxi_len as usize)?;
+        let cxi_offset = read_struct::<U32le>(game.as_ref(), 0x120)?.v * 0x200;
+        let cxi_len = read_struct::<U32le>(game.as_ref(), 0x124)?.v * 0x200;
+        let cxi = sub_file::SubFile::new(game, cxi_offset as usize, cxi_len as usize)?;
 
         if read_struct::<Magic>(&cxi, 0x100)?.v != *b"NCCH" {
             return Err(Error::BrokenGame);
@@ -461,8 +906,7 @@ impl Resource {
 
         let mut key_y_ncch = [0; 16];
         cxi.read(0, &mut key_y_ncch)?;
-        let ncch_key =
-            key_engine::scramble(self.key_x_ncch.ok_or(Error::MissingBoot9)?, key_y_ncch);
+        let ncch_key = key_engine::scramble(self.keys.get_key(key_store::KEY_X_NCCH)?, key_y_ncch);
 
         let ncch_version = read_struct::<U16le>(&cxi, 0x112)?.v;
         let exefs_offset = read_struct::<U32le>(&cxi, 0x1A0)?.v * 0x200;
@@ -567,8 +1011,8 @@ impl Resource {
 
                 // Yup this one use the same key x as ncch
                 let cmac_key = key_engine::scramble(
-                    self.key_x_ncch.ok_or(Error::MissingBoot9)?,
-                    self.x2f_key_y.ok_or(Error::MissingKeyY2F)?,
+                    self.keys.get_key(key_store::KEY_X_NCCH)?,
+                    self.keys.get_key(key_store::KEY_Y_2F)?,
                 );
 
                 use cmac::*;
@@ -580,15 +1024,15 @@ impl Resource {
         }
 
         let key_x = if crypto_version == 9 {
-            self.x1a_key_x.ok_or(Error::MissingKeyX1A)?
+            self.keys.get_key(key_store::KEY_X_1A)?
         } else {
-            self.key_x_dec.ok_or(Error::MissingBoot9)?
+            self.keys.get_key(key_store::KEY_X_DEC)?
         };
 
         let key_x_cmac = if crypto_version == 9 {
-            self.x19_key_x.ok_or(Error::MissingKeyX19)?
+            self.keys.get_key(key_store::KEY_X_19)?
         } else {
-            self.key_x_sign.ok_or(Error::MissingBoot9)?
+            self.keys.get_key(key_store::KEY_X_SIGN)?
         };
 
         let key = key_engine::scramble(key_x, key_y);
@@ -610,13 +1054,17 @@ impl Resource {
     ) -> Result<(), Error> {
         std::fs::File::create(path)?.set_len(len as u64)?;
 
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, true)?;
+
+        self.format_cart_save_file(file, param)
+    }
 
+    /// Same as [`format_cart_save`](#method.format_cart_save), but on the given file.
+    pub fn format_cart_save_file(
+        &self,
+        file: Rc<dyn RandomAccessFile>,
+        param: &SaveDataFormatParam,
+    ) -> Result<(), Error> {
         CartSaveData::format(file, &self.get_cart_format()?, &param)?;
 
         Ok(())
@@ -624,70 +1072,362 @@ impl Resource {
 
     /// Opens a save data on cartridge.
     pub fn open_cart_save(&self, path: &str, write: bool) -> Result<CartSaveData, Error> {
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(path)?,
-        )?);
+        let file = self.open_host_file(path, write)?;
+
+        self.open_cart_save_file(file)
+    }
 
+    /// Rebuilds the directory and file hash tables of a save data on cartridge.
+    /// See [`rehash_sd_save`](#method.rehash_sd_save).
+    pub fn rehash_cart_save(
+        &self,
+        path: &str,
+        dir_buckets: usize,
+        file_buckets: usize,
+    ) -> Result<(), Error> {
+        self.open_cart_save(path, true)?
+            .rehash(dir_buckets, file_buckets)
+    }
+
+    /// Opens a save data on cartridge from the given file.
+    pub fn open_cart_save_file(
+        &self,
+        file: Rc<dyn RandomAccessFile>,
+    ) -> Result<CartSaveData, Error> {
         CartSaveData::new(file, &self.get_cart_format()?)
     }
 
+    /// Opens the version of a save data on cartridge before its last commit, as read-only.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn open_cart_save_previous(&self, path: &str) -> Result<CartSaveData, Error> {
+        let file = self.open_host_file(path, false)?;
+        CartSaveData::new_previous(file, &self.get_cart_format()?)
+    }
+
+    /// Makes the version of a save data on cartridge before its last commit the current one.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn restore_cart_save(&self, path: &str) -> Result<(), Error> {
+        let file = self.open_host_file(path, true)?;
+        CartSaveData::new_previous(file, &self.get_cart_format()?)?.restore_previous()
+    }
+
+    /// Reads the wear leveling structures of a Card1 cartridge save, for diagnosing broken saves.
+    /// No key is needed for this.
+    pub fn inspect_cart_wear_leveling(&self, path: &str) -> Result<WearLevelingInfo, Error> {
+        let file = Rc::new(DiskFile::new(std::fs::File::open(path)?)?);
+        CartSaveData::inspect_wear_leveling(file)
+    }
+
+    /// Folds the wear leveling journal of a Card1 cartridge save into its block map.
+    /// No key is needed for this.
+    pub fn compact_cart_wear_leveling(&self, path: &str) -> Result<(), Error> {
+        let file = self.open_host_file(path, true)?;
+        CartSaveData::compact_wear_leveling(file)
+    }
+
+    /// Converts a Card1 cartridge save into a plain image without wear leveling,
+    /// which is used by some emulators and flashcarts.
+    /// The image stays encrypted and signed, so no key is needed for this.
+    /// It is written to a temporary file first, so an existing file at `dst_path`
+    /// is left intact on failure.
+    pub fn unwrap_cart_save(&self, src_path: &str, dst_path: &str) -> Result<(), Error> {
+        let file: Rc<dyn RandomAccessFile> =
+            Rc::new(DiskFile::new(std::fs::File::open(src_path)?)?);
+        let len = CartSaveData::unwrapped_len(file.clone())?;
+        self.replace_host_file(
+            dst_path,
+            len,
+            Box::new(|plain| CartSaveData::unwrap_wear_leveling(file, plain.as_ref())),
+        )
+    }
+
+    /// Converts a plain cartridge save image into a Card1 cartridge save with wear leveling.
+    /// See [`unwrap_cart_save`](#method.unwrap_cart_save) for how the output is written.
+    /// - `len`: the size of the cartridge chip. If `None`, the smallest chip that can hold the image is chosen.
+    pub fn wrap_cart_save(
+        &self,
+        src_path: &str,
+        dst_path: &str,
+        len: Option<usize>,
+    ) -> Result<(), Error> {
+        let file = DiskFile::new(std::fs::File::open(src_path)?)?;
+        let len = match len {
+            Some(len) => len,
+            None => CartSaveData::wrapped_len(file.len())?,
+        };
+        self.replace_host_file(
+            dst_path,
+            len,
+            Box::new(|chip| CartSaveData::wrap_wear_leveling(&file, chip)),
+        )
+    }
+
     /// Opens a title database.
     pub fn open_db(&self, db_type: DbType, write: bool) -> Result<Db, Error> {
-        let (file, key) = match db_type {
+        let (file, key) = self.open_db_file(db_type, write)?;
+        Db::new(file, db_type, key)
+    }
+
+    /// Opens the version of a title database before its last commit, as read-only.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn open_db_previous(&self, db_type: DbType) -> Result<Db, Error> {
+        let (file, key) = self.open_db_file(db_type, false)?;
+        Db::new_previous(file, db_type, key)
+    }
+
+    /// Makes the version of a title database before its last commit the current one.
+    /// See [`open_sd_save_previous`](#method.open_sd_save_previous).
+    pub fn restore_db(&self, db_type: DbType) -> Result<(), Error> {
+        let (file, key) = self.open_db_file(db_type, true)?;
+        Db::new_previous(file, db_type, key)?.restore_previous()
+    }
+
+    fn open_db_file(
+        &self,
+        db_type: DbType,
+        write: bool,
+    ) -> Result<(Rc<dyn RandomAccessFile>, [u8; 16]), Error> {
+        Ok(match db_type {
             DbType::NandTitle => (
                 self.nand
                     .as_ref()
                     .ok_or(Error::MissingNand)?
                     .open(&["dbs", "title.db"], write)?,
-                self.key_db.ok_or(Error::MissingOtp)?,
+                self.key_db()?,
             ),
             DbType::NandImport => (
                 self.nand
                     .as_ref()
                     .ok_or(Error::MissingNand)?
                     .open(&["dbs", "import.db"], write)?,
-                self.key_db.ok_or(Error::MissingOtp)?,
+                self.key_db()?,
             ),
             DbType::TmpTitle => (
                 self.nand
                     .as_ref()
                     .ok_or(Error::MissingNand)?
                     .open(&["dbs", "tmp_t.db"], write)?,
-                self.key_db.ok_or(Error::MissingOtp)?,
+                self.key_db()?,
             ),
             DbType::TmpImport => (
                 self.nand
                     .as_ref()
                     .ok_or(Error::MissingNand)?
                     .open(&["dbs", "tmp_i.db"], write)?,
-                self.key_db.ok_or(Error::MissingOtp)?,
+                self.key_db()?,
             ),
             DbType::Ticket => (
                 self.nand
                     .as_ref()
                     .ok_or(Error::MissingNand)?
                     .open(&["dbs", "ticket.db"], write)?,
-                self.key_db.ok_or(Error::MissingOtp)?,
+                self.key_db()?,
             ),
             DbType::SdTitle => (
-                self.sd
-                    .as_ref()
-                    .ok_or(Error::MissingSd)?
-                    .open(&["dbs", "title.db"], write)?,
-                self.key_sign.ok_or(Error::MissingSd)?,
+                self.sd()?.open(&["dbs", "title.db"], write)?,
+                self.key_sign()?,
             ),
             DbType::SdImport => (
-                self.sd
-                    .as_ref()
-                    .ok_or(Error::MissingSd)?
-                    .open(&["dbs", "import.db"], write)?,
-                self.key_sign.ok_or(Error::MissingSd)?,
+                self.sd()?.open(&["dbs", "import.db"], write)?,
+                self.key_sign()?,
             ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::file_system::*;
+    use crate::memory_file_system::MemoryFileSystem;
+    use crate::*;
+
+    fn sd_resource() -> (Resource, Rc<dyn SdNandFileSystem>, String) {
+        let mut movable = vec![0; 0x140];
+        movable[0x110..0x120].copy_from_slice(&[0x55; 16]);
+        let id0 = hash_movable([0x55; 16]);
+        let sd: Rc<dyn SdNandFileSystem> = Rc::new(MemoryFileSystem::new());
+        sd.create(&["Nintendo 3DS", &id0, "id1", "x"], 0).unwrap();
+        sd.create(&["Nintendo 3DS", "x"], 0).unwrap();
+        let resource = ResourceBuilder::new()
+            .key(key_store::KEY_X_DEC, &[0x33; 16])
+            .key(key_store::KEY_X_SIGN, &[0x44; 16])
+            .movable(FileSource::Bytes(movable))
+            .sd_file_system(sd.clone())
+            .build()
+            .unwrap();
+        (resource, sd, id0)
+    }
+
+    #[test]
+    fn missing_sd_key() {
+        let mut movable = vec![0; 0x140];
+        movable[0x110..0x120].copy_from_slice(&[0x55; 16]);
+        let sd: Rc<dyn SdNandFileSystem> = Rc::new(MemoryFileSystem::new());
+        let resource = ResourceBuilder::new()
+            .key(key_store::KEY_X_SIGN, &[0x44; 16])
+            .movable(FileSource::Bytes(movable))
+            .sd_file_system(sd.clone())
+            .build()
+            .unwrap();
+        match resource.open_sd_save(0x1_2345, false) {
+            Err(Error::MissingKey(name)) => assert_eq!(name, key_store::KEY_X_DEC),
+            _ => panic!(),
+        }
+
+        // Archives not on SD can still be opened
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let file = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+        resource
+            .format_bare_save_file(file.clone(), &param)
+            .unwrap();
+        resource.open_bare_save_file(file).unwrap();
+
+        let resource = ResourceBuilder::new()
+            .key(key_store::KEY_X_DEC, &[0x33; 16])
+            .sd_file_system(sd)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            resource.open_sd_tree(false),
+            Err(Error::MissingSd)
+        ));
+    }
+
+    #[test]
+    fn sd_file_path() {
+        let (resource, sd, id0) = sd_resource();
+
+        resource.create_sd_file("/a/b", 0x10).unwrap();
+        resource.open_sd_file("a/b", true).unwrap();
+        assert!(sd.is_file(&["Nintendo 3DS", &id0, "id1", "a", "b"]));
+
+        for path in &["../../x", "a/../../../x", "./x", "a/./b", ".."] {
+            match resource.create_sd_file(path, 0x10) {
+                Err(Error::InvalidValue) => (),
+                _ => panic!(),
+            }
+            match resource.open_sd_file(path, true) {
+                Err(Error::InvalidValue) => (),
+                _ => panic!(),
+            }
+        }
+        assert_eq!(sd.list_dir(&["Nintendo 3DS"]).unwrap().1, ["x"]);
+    }
+
+    #[test]
+    fn rehash_sd_save() {
+        let (resource, sd, id0) = sd_resource();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 1,
+            max_file: 10,
+            file_buckets: 1,
+            duplicate_data: true,
         };
+        resource
+            .format_sd_save(0x1_2345, &param, 0x10_0000)
+            .unwrap();
+        let save = resource.open_sd_save(0x1_2345, true).unwrap();
+        save.open_root()
+            .unwrap()
+            .new_sub_file([1; 16], 3)
+            .unwrap()
+            .write(0, &[5; 3])
+            .unwrap();
+        save.commit().unwrap();
+        drop(save);
+
+        match resource.rehash_sd_save(0x1_2345, 0, 3) {
+            Err(Error::InvalidValue) => (),
+            _ => panic!(),
+        }
+        resource.rehash_sd_save(0x1_2345, 3, 5).unwrap();
+
+        let save = resource.open_sd_save(0x1_2345, false).unwrap();
+        let stat = save.stat().unwrap();
+        assert_eq!(stat.dir_hash.unwrap().buckets, 3);
+        assert_eq!(stat.file_hash.unwrap().buckets, 5);
+        let mut buf = [0; 3];
+        save.open_root()
+            .unwrap()
+            .open_sub_file([1; 16])
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [5; 3]);
+        let data_path = [
+            "Nintendo 3DS",
+            &id0,
+            "id1",
+            "title",
+            "00000000",
+            "00012345",
+            "data",
+        ];
+        assert_eq!(sd.list_dir(&data_path).unwrap().1, ["00000001.sav"]);
+    }
 
-        Db::new(file, db_type, key)
+    #[test]
+    fn resize_sd_save() {
+        let (resource, sd, id0) = sd_resource();
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        resource
+            .format_sd_save(0x1_2345, &param, 0x10_0000)
+            .unwrap();
+        let save = resource.open_sd_save(0x1_2345, true).unwrap();
+        save.open_root()
+            .unwrap()
+            .new_sub_file([1; 16], 0x4000)
+            .unwrap()
+            .write(0, &[5; 0x4000])
+            .unwrap();
+        save.commit().unwrap();
+        drop(save);
+
+        let data_path = [
+            "Nintendo 3DS",
+            &id0,
+            "id1",
+            "title",
+            "00000000",
+            "00012345",
+            "data",
+        ];
+        assert!(resource.resize_sd_save(0x1_2345, &param, 0x8000).is_err());
+        assert_eq!(sd.list_dir(&data_path).unwrap().1, ["00000001.sav"]);
+        assert_eq!(
+            resource.open_sd_save(0x1_2345, false).unwrap().image_len(),
+            0x10_0000
+        );
+
+        resource
+            .resize_sd_save(0x1_2345, &param, 0x20_0000)
+            .unwrap();
+        assert_eq!(sd.list_dir(&data_path).unwrap().1, ["00000001.sav"]);
+        let save = resource.open_sd_save(0x1_2345, false).unwrap();
+        assert_eq!(save.image_len(), 0x20_0000);
+        let mut buf = vec![0; 0x4000];
+        save.open_root()
+            .unwrap()
+            .open_sub_file([1; 16])
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, vec![5; 0x4000]);
     }
 }
@@ -22,6 +22,11 @@ impl MemoryFile {
             data: RefCell::new(data),
         })
     }
+
+    /// Changes the length of the file. New bytes are filled with 0.
+    pub fn resize(&self, len: usize) {
+        self.data.borrow_mut().resize(len, 0);
+    }
 }
 
 impl RandomAccessFile for MemoryFile {
@@ -0,0 +1,171 @@
+use crate::error::*;
+use crate::memory_file::MemoryFile;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+
+fn to_owned_path(path: &[&str]) -> Vec<String> {
+    path.iter().map(|&s| s.to_owned()).collect()
+}
+
+/// Implements [`SdNandFileSystem`](../sd_nand_common/trait.SdNandFileSystem.html) in memory.
+///
+/// Directories are created implicitly for every file. Files opened multiple times share
+/// the same content.
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    files: RefCell<HashMap<Vec<String>, Rc<MemoryFile>>>,
+    dirs: RefCell<HashSet<Vec<String>>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Removes a file from the file system and returns it.
+    pub(crate) fn take(&self, path: &[&str]) -> Result<Rc<MemoryFile>, Error> {
+        self.files
+            .borrow_mut()
+            .remove(&to_owned_path(path))
+            .ok_or(Error::NotFound)
+    }
+}
+
+impl SdNandFileSystem for MemoryFileSystem {
+    fn open(&self, path: &[&str], _write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        let file = self
+            .files
+            .borrow()
+            .get(&to_owned_path(path))
+            .cloned()
+            .ok_or(Error::NotFound)?;
+        Ok(file)
+    }
+
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.files
+            .borrow_mut()
+            .insert(to_owned_path(path), Rc::new(MemoryFile::new(vec![0; len])));
+        Ok(())
+    }
+
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        self.take(path)?;
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        let path = to_owned_path(path);
+        self.files.borrow_mut().retain(|p, _| !p.starts_with(&path));
+        self.dirs.borrow_mut().retain(|p| !p.starts_with(&path));
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error> {
+        if !self.is_dir(path) {
+            return make_error(Error::NotFound);
+        }
+        let path = to_owned_path(path);
+        let mut dirs = BTreeSet::new();
+        let mut files = vec![];
+        for p in self.files.borrow().keys() {
+            if p.len() > path.len() && p.starts_with(&path) {
+                if p.len() == path.len() + 1 {
+                    files.push(p[path.len()].clone());
+                } else {
+                    dirs.insert(p[path.len()].clone());
+                }
+            }
+        }
+        for p in self.dirs.borrow().iter() {
+            if p.len() > path.len() && p.starts_with(&path) {
+                dirs.insert(p[path.len()].clone());
+            }
+        }
+        Ok((dirs.into_iter().collect(), files))
+    }
+
+    fn is_dir(&self, path: &[&str]) -> bool {
+        let path = to_owned_path(path);
+        path.is_empty()
+            || self.dirs.borrow().iter().any(|p| p.starts_with(&path))
+            || self
+                .files
+                .borrow()
+                .keys()
+                .any(|p| p.len() > path.len() && p.starts_with(&path))
+    }
+
+    fn is_file(&self, path: &[&str]) -> bool {
+        self.files.borrow().contains_key(&to_owned_path(path))
+    }
+
+    fn create_dir(&self, path: &[&str]) -> Result<(), Error> {
+        if self.is_dir(path) || self.is_file(path) {
+            return make_error(Error::AlreadyExist);
+        }
+        self.dirs.borrow_mut().insert(to_owned_path(path));
+        Ok(())
+    }
+
+    fn resize(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.files
+            .borrow()
+            .get(&to_owned_path(path))
+            .ok_or(Error::NotFound)?
+            .resize(len);
+        Ok(())
+    }
+
+    fn rename(&self, from: &[&str], to: &[&str]) -> Result<(), Error> {
+        let file = self.take(from)?;
+        self.files.borrow_mut().insert(to_owned_path(to), file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file_system::*;
+
+    #[test]
+    fn tree() {
+        let fs = MemoryFileSystem::new();
+        fs.create(&["a", "b", "c"], 4).unwrap();
+        fs.create(&["a", "d"], 2).unwrap();
+        fs.create_dir(&["e"]).unwrap();
+        assert!(fs.create_dir(&["a"]).is_err());
+        assert!(fs.is_dir(&["a", "b"]));
+        assert!(fs.is_file(&["a", "d"]));
+        assert!(!fs.is_dir(&["a", "d"]));
+        assert_eq!(
+            fs.list_dir(&[]).unwrap(),
+            (vec!["a".to_owned(), "e".to_owned()], vec![])
+        );
+        assert_eq!(
+            fs.list_dir(&["a"]).unwrap(),
+            (vec!["b".to_owned()], vec!["d".to_owned()])
+        );
+        assert!(fs.list_dir(&["f"]).is_err());
+
+        let file = fs.open(&["a", "b", "c"], true).unwrap();
+        file.write(0, &[1, 2, 3, 4]).unwrap();
+        fs.resize(&["a", "b", "c"], 6).unwrap();
+        let mut buf = [0xFF; 6];
+        fs.open(&["a", "b", "c"], false)
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 0, 0]);
+        assert_eq!(file.len(), 6);
+
+        assert!(matches!(fs.remove_empty_dir(&["a"]), Err(Error::NotEmpty)));
+        fs.remove_dir(&["a"]).unwrap();
+        assert!(!fs.is_dir(&["a"]));
+        fs.remove_empty_dir(&["e"]).unwrap();
+        assert_eq!(fs.list_dir(&[]).unwrap(), (vec![], vec![]));
+    }
+}
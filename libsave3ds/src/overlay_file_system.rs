@@ -0,0 +1,418 @@
+use crate::error::*;
+use crate::memory_file_system::MemoryFileSystem;
+use crate::random_access_file::*;
+use crate::sd_nand_common::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::rc::Rc;
+
+fn to_owned_path(path: &[&str]) -> Vec<String> {
+    path.iter().map(|&s| s.to_owned()).collect()
+}
+
+fn as_str_path(path: &[String]) -> Vec<&str> {
+    path.iter().map(|s| s.as_str()).collect()
+}
+
+/// Changes made in an [`OverlayFileSystem`](struct.OverlayFileSystem.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverlayDelta {
+    /// Files and directories removed from the base, in alphabetical order.
+    pub removed: Vec<Vec<String>>,
+
+    /// Files created or modified, in alphabetical order.
+    pub changed: Vec<Vec<String>>,
+}
+
+/// Implements [`SdNandFileSystem`](../sd_nand_common/trait.SdNandFileSystem.html)
+/// as a copy-on-write layer over another one.
+///
+/// Reads go to the base until a file is opened for writing, at which point the file is copied
+/// to the scratch storage and all further access goes there. Removals are recorded in memory.
+/// The base is never modified until [`apply`](#method.apply) is called.
+///
+/// A file opened read-only before it gets copied doesn't see later changes.
+pub struct OverlayFileSystem {
+    base: Rc<dyn SdNandFileSystem>,
+    upper: Rc<dyn SdNandFileSystem>,
+    removed_files: RefCell<HashSet<Vec<String>>>,
+    removed_dirs: RefCell<HashSet<Vec<String>>>,
+}
+
+impl OverlayFileSystem {
+    /// Creates an overlay over `base` that keeps changes in memory.
+    pub fn new(base: Rc<dyn SdNandFileSystem>) -> OverlayFileSystem {
+        OverlayFileSystem::with_scratch(base, Rc::new(MemoryFileSystem::new()))
+    }
+
+    /// Creates an overlay over `base` that keeps changes in `scratch`, for example a
+    /// [`HostFileSystem`](../host_file_system/struct.HostFileSystem.html) on an empty directory.
+    /// Removals are still only recorded in memory.
+    pub fn with_scratch(
+        base: Rc<dyn SdNandFileSystem>,
+        scratch: Rc<dyn SdNandFileSystem>,
+    ) -> OverlayFileSystem {
+        OverlayFileSystem {
+            base,
+            upper: scratch,
+            removed_files: RefCell::new(HashSet::new()),
+            removed_dirs: RefCell::new(HashSet::new()),
+        }
+    }
+
+    fn base_dir_visible(&self, path: &[String]) -> bool {
+        !self
+            .removed_dirs
+            .borrow()
+            .iter()
+            .any(|d| path.starts_with(d))
+    }
+
+    fn base_file_visible(&self, path: &[String]) -> bool {
+        self.base_dir_visible(path) && !self.removed_files.borrow().contains(path)
+    }
+
+    fn copy_up(&self, path: &[&str]) -> Result<(), Error> {
+        if !self.base_file_visible(&to_owned_path(path)) {
+            return make_error(Error::NotFound);
+        }
+        let src = self.base.open(path, false)?;
+        self.upper.create(path, src.len())?;
+        let dst = self.upper.open(path, true)?;
+        copy_file(src.as_ref(), dst.as_ref())?;
+        dst.commit()
+    }
+
+    /// Collects all directories and files in the scratch storage under `path`.
+    fn walk_upper(
+        &self,
+        path: &mut Vec<String>,
+        dirs: &mut Vec<Vec<String>>,
+        files: &mut Vec<Vec<String>>,
+    ) -> Result<(), Error> {
+        let (sub_dirs, sub_files) = self.upper.list_dir(&as_str_path(path))?;
+        for name in sub_files {
+            path.push(name);
+            files.push(path.clone());
+            path.pop();
+        }
+        for name in sub_dirs {
+            path.push(name);
+            dirs.push(path.clone());
+            self.walk_upper(path, dirs, files)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// Returns the changes made so far.
+    pub fn delta(&self) -> Result<OverlayDelta, Error> {
+        let mut removed: Vec<Vec<String>> = self
+            .removed_dirs
+            .borrow()
+            .iter()
+            .chain(self.removed_files.borrow().iter())
+            .cloned()
+            .collect();
+        removed.sort();
+        let mut changed = vec![];
+        self.walk_upper(&mut vec![], &mut vec![], &mut changed)?;
+        changed.sort();
+        Ok(OverlayDelta { removed, changed })
+    }
+
+    /// Copies all created or modified files to `target`, and returns the changes made so far.
+    /// Removals are only reported in the returned value.
+    pub fn export_delta(&self, target: &dyn SdNandFileSystem) -> Result<OverlayDelta, Error> {
+        let delta = self.delta()?;
+        for path in delta.changed.iter() {
+            let path = as_str_path(path);
+            let src = self.upper.open(&path, false)?;
+            target.create(&path, src.len())?;
+            let dst = target.open(&path, true)?;
+            copy_file(src.as_ref(), dst.as_ref())?;
+            dst.commit()?;
+        }
+        Ok(delta)
+    }
+
+    /// Writes all changes to the base, and clears the scratch storage.
+    /// Files opened from the overlay should be dropped before this.
+    pub fn apply(&self) -> Result<(), Error> {
+        for path in self.removed_dirs.borrow().iter() {
+            self.base.remove_dir(&as_str_path(path))?;
+        }
+        for path in self.removed_files.borrow().iter() {
+            let path = as_str_path(path);
+            if self.base.is_file(&path) {
+                self.base.remove(&path)?;
+            }
+        }
+
+        let mut dirs = vec![];
+        let mut files = vec![];
+        self.walk_upper(&mut vec![], &mut dirs, &mut files)?;
+        for path in dirs {
+            let path = as_str_path(&path);
+            if !self.base.is_dir(&path) {
+                self.base.create_dir(&path)?;
+            }
+        }
+        for path in files {
+            let path = as_str_path(&path);
+            let src = self.upper.open(&path, false)?;
+            self.base.create(&path, src.len())?;
+            let dst = self.base.open(&path, true)?;
+            copy_file(src.as_ref(), dst.as_ref())?;
+            dst.commit()?;
+        }
+
+        let (dirs, files) = self.upper.list_dir(&[])?;
+        for name in dirs {
+            self.upper.remove_dir(&[&name])?;
+        }
+        for name in files {
+            self.upper.remove(&[&name])?;
+        }
+        self.removed_dirs.borrow_mut().clear();
+        self.removed_files.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+impl SdNandFileSystem for OverlayFileSystem {
+    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        if self.upper.is_file(path) {
+            return self.upper.open(path, write);
+        }
+        if write {
+            self.copy_up(path)?;
+            return self.upper.open(path, true);
+        }
+        if !self.base_file_visible(&to_owned_path(path)) {
+            return make_error(Error::NotFound);
+        }
+        self.base.open(path, false)
+    }
+
+    fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.upper.create(path, len)
+    }
+
+    fn remove(&self, path: &[&str]) -> Result<(), Error> {
+        let mut found = false;
+        if self.upper.is_file(path) {
+            self.upper.remove(path)?;
+            found = true;
+        }
+        let owned_path = to_owned_path(path);
+        if self.base_file_visible(&owned_path) && self.base.is_file(path) {
+            self.removed_files.borrow_mut().insert(owned_path);
+            found = true;
+        }
+        if !found {
+            return make_error(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+        self.upper.remove_dir(path)?;
+        let path = to_owned_path(path);
+        self.removed_files
+            .borrow_mut()
+            .retain(|p| !p.starts_with(&path));
+        self.removed_dirs
+            .borrow_mut()
+            .retain(|p| !p.starts_with(&path));
+        self.removed_dirs.borrow_mut().insert(path);
+        Ok(())
+    }
+
+    fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error> {
+        if !self.is_dir(path) {
+            return make_error(Error::NotFound);
+        }
+        let mut dirs = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        if self.upper.is_dir(path) {
+            let (sub_dirs, sub_files) = self.upper.list_dir(path)?;
+            dirs.extend(sub_dirs);
+            files.extend(sub_files);
+        }
+        let mut owned_path = to_owned_path(path);
+        if self.base_dir_visible(&owned_path) && self.base.is_dir(path) {
+            let (sub_dirs, sub_files) = self.base.list_dir(path)?;
+            for name in sub_dirs {
+                owned_path.push(name);
+                if self.base_dir_visible(&owned_path) {
+                    dirs.insert(owned_path.pop().unwrap());
+                } else {
+                    owned_path.pop();
+                }
+            }
+            for name in sub_files {
+                owned_path.push(name);
+                if self.base_file_visible(&owned_path) {
+                    files.insert(owned_path.pop().unwrap());
+                } else {
+                    owned_path.pop();
+                }
+            }
+        }
+        Ok((dirs.into_iter().collect(), files.into_iter().collect()))
+    }
+
+    fn is_dir(&self, path: &[&str]) -> bool {
+        self.upper.is_dir(path)
+            || (self.base_dir_visible(&to_owned_path(path)) && self.base.is_dir(path))
+    }
+
+    fn is_file(&self, path: &[&str]) -> bool {
+        self.upper.is_file(path)
+            || (self.base_file_visible(&to_owned_path(path)) && self.base.is_file(path))
+    }
+
+    fn create_dir(&self, path: &[&str]) -> Result<(), Error> {
+        if self.is_dir(path) || self.is_file(path) {
+            return make_error(Error::AlreadyExist);
+        }
+        for i in 1..=path.len() {
+            if !self.upper.is_dir(&path[0..i]) {
+                self.upper.create_dir(&path[0..i])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resize(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        if !self.upper.is_file(path) {
+            self.copy_up(path)?;
+        }
+        self.upper.resize(path, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ext_data::*;
+    use crate::file_system::*;
+    use crate::memory_file_system::MemoryFileSystem;
+    use crate::overlay_file_system::*;
+
+    fn read_all(fs: &dyn SdNandFileSystem, path: &[&str]) -> Vec<u8> {
+        let file = fs.open(path, false).unwrap();
+        let mut buf = vec![0; file.len()];
+        file.read(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let base = Rc::new(MemoryFileSystem::new());
+        base.create(&["a", "x"], 2).unwrap();
+        base.create(&["a", "y"], 2).unwrap();
+        base.create(&["b", "z"], 2).unwrap();
+        base.open(&["a", "x"], true)
+            .unwrap()
+            .write(0, &[1, 2])
+            .unwrap();
+
+        let overlay = OverlayFileSystem::new(base.clone());
+        overlay
+            .open(&["a", "x"], true)
+            .unwrap()
+            .write(0, &[3])
+            .unwrap();
+        overlay.remove(&["a", "y"]).unwrap();
+        overlay.remove_dir(&["b"]).unwrap();
+        overlay.create(&["c"], 1).unwrap();
+        overlay.create_dir(&["d", "e"]).unwrap();
+
+        assert_eq!(read_all(&overlay, &["a", "x"]), [3, 2]);
+        assert_eq!(read_all(base.as_ref(), &["a", "x"]), [1, 2]);
+        assert!(base.is_file(&["a", "y"]));
+        assert!(overlay.open(&["a", "y"], false).is_err());
+        assert!(overlay.remove(&["a", "y"]).is_err());
+        assert_eq!(
+            overlay.list_dir(&[]).unwrap(),
+            (vec!["a".to_owned(), "d".to_owned()], vec!["c".to_owned()])
+        );
+        assert_eq!(
+            overlay.list_dir(&["a"]).unwrap(),
+            (vec![], vec!["x".to_owned()])
+        );
+        assert!(!overlay.is_dir(&["b"]));
+        overlay.create(&["b", "w"], 1).unwrap();
+        assert_eq!(
+            overlay.list_dir(&["b"]).unwrap(),
+            (vec![], vec!["w".to_owned()])
+        );
+
+        let delta = overlay.delta().unwrap();
+        assert_eq!(
+            delta.removed,
+            vec![vec!["a".to_owned(), "y".to_owned()], vec!["b".to_owned()]]
+        );
+        assert_eq!(
+            delta.changed,
+            vec![
+                vec!["a".to_owned(), "x".to_owned()],
+                vec!["b".to_owned(), "w".to_owned()],
+                vec!["c".to_owned()]
+            ]
+        );
+
+        let target = MemoryFileSystem::new();
+        assert_eq!(overlay.export_delta(&target).unwrap(), delta);
+        assert_eq!(read_all(&target, &["a", "x"]), [3, 2]);
+
+        overlay.apply().unwrap();
+        assert_eq!(read_all(base.as_ref(), &["a", "x"]), [3, 2]);
+        assert!(!base.is_file(&["a", "y"]));
+        assert!(!base.is_file(&["b", "z"]));
+        assert!(base.is_file(&["b", "w"]));
+        assert!(base.is_dir(&["d", "e"]));
+        assert_eq!(
+            overlay.delta().unwrap(),
+            OverlayDelta {
+                removed: vec![],
+                changed: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn ext_data_on_overlay() {
+        let base = Rc::new(MemoryFileSystem::new());
+        let param = ExtDataFormatParam {
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+        };
+        ExtData::format(base.as_ref(), &[], 0, [0; 16], None, &param).unwrap();
+
+        let overlay = Rc::new(OverlayFileSystem::new(base.clone()));
+        let ext_data = ExtData::new(overlay.clone(), &[], 0, [0; 16], false, true).unwrap();
+        let root = ext_data.open_root().unwrap();
+        let file = root.new_sub_file([1; 16], 100).unwrap();
+        file.write(0, &[7; 100]).unwrap();
+        file.commit().unwrap();
+        drop(file);
+        drop(root);
+        ext_data.commit().unwrap();
+        drop(ext_data);
+
+        let ext_data = ExtData::new(base.clone(), &[], 0, [0; 16], false, false).unwrap();
+        assert!(ext_data.open_root().unwrap().open_sub_file([1; 16]).is_err());
+        drop(ext_data);
+
+        overlay.apply().unwrap();
+        let ext_data = ExtData::new(base, &[], 0, [0; 16], false, false).unwrap();
+        let file = ext_data.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+        let mut buf = [0; 100];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [7; 100]);
+    }
+}
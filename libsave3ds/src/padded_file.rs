@@ -0,0 +1,84 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use std::rc::Rc;
+
+/// Implements `RandomAccessFile` layer that extends a truncated flash dump to its full size.
+///
+/// The missing tail is presented as erased flash, i.e. filled with 0xFF.
+/// Writing anything other than 0xFF to the missing tail is refused,
+/// so the parent file always keeps its original length.
+pub struct PaddedFile {
+    parent: Rc<dyn RandomAccessFile>,
+    len: usize,
+}
+
+impl PaddedFile {
+    pub fn new(parent: Rc<dyn RandomAccessFile>, len: usize) -> Result<PaddedFile, Error> {
+        if len < parent.len() {
+            return make_error(Error::SizeMismatch);
+        }
+        Ok(PaddedFile { parent, len })
+    }
+}
+
+impl RandomAccessFile for PaddedFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let parent_len = self.parent.len();
+        let split = std::cmp::min(std::cmp::max(pos, parent_len), pos + buf.len()) - pos;
+        let (head, tail) = buf.split_at_mut(split);
+        if !head.is_empty() {
+            self.parent.read(pos, head)?;
+        }
+        for b in tail {
+            *b = 0xFF;
+        }
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let parent_len = self.parent.len();
+        let split = std::cmp::min(std::cmp::max(pos, parent_len), pos + buf.len()) - pos;
+        let (head, tail) = buf.split_at(split);
+        if tail.iter().any(|&b| b != 0xFF) {
+            return make_error(Error::TruncatedImage);
+        }
+        if !head.is_empty() {
+            self.parent.write(pos, head)?;
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::padded_file::*;
+
+    #[test]
+    fn padded_read_write() {
+        let parent = Rc::new(MemoryFile::new(vec![0; 8]));
+        let file = PaddedFile::new(parent.clone(), 16).unwrap();
+        let mut buf = [0; 12];
+        file.read(2, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        file.write(6, &[1, 2, 0xFF, 0xFF]).unwrap();
+        file.read(4, &mut buf).unwrap();
+        assert_eq!(
+            buf,
+            [0, 0, 1, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert!(file.write(7, &[3, 4]).is_err());
+        assert_eq!(parent.len(), 8);
+    }
+}
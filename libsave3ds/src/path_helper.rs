@@ -0,0 +1,475 @@
+//! Path-based operations on top of the inode-based [`FileSystem`](../file_system/trait.FileSystem.html).
+//!
+//! Paths are strings of names separated by `/`, such as `/a/b`. The leading `/` is optional,
+//! and empty names are skipped, so `/` and the empty string both refer to the root directory.
+//! Each name is converted to the name type of the archive by [`NameConvert`](trait.NameConvert.html).
+
+use crate::error::*;
+use crate::file_system::*;
+use crate::random_access_file::copy_chunks;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const CHUNK_LEN: usize = 0x10_0000;
+
+/// Conversion between archive names and strings usable as path components.
+pub trait NameConvert {
+    /// Converts an archive name to a string that contains no `/`.
+    fn name_3ds_to_str(name: &Self) -> String;
+
+    /// Converts a string back to an archive name. Returns `None` if the string is not valid.
+    fn name_str_to_3ds(name: &str) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+/// Title IDs in title databases, as 16 hex digits.
+impl NameConvert for u64 {
+    fn name_3ds_to_str(name: &u64) -> String {
+        format!("{:016x}", name)
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<u64> {
+        u64::from_str_radix(name, 16).ok()
+    }
+}
+
+fn is_legal_char(c: u8) -> bool {
+    (32..127).contains(&c) && c != b'/' && c != b'\\'
+}
+
+/// Names in save data and extdata. Trailing zeros are dropped,
+/// and bytes that are not printable ASCII, `/` or `\` are escaped as `\xHH`.
+impl NameConvert for [u8; 16] {
+    fn name_3ds_to_str(name: &[u8; 16]) -> String {
+        let mut last_char = 15;
+        loop {
+            if name[last_char] != 0 || last_char == 0 {
+                break;
+            }
+            last_char -= 1;
+        }
+
+        name[0..=last_char]
+            .iter()
+            .map(|x| {
+                if is_legal_char(*x) {
+                    String::from_utf8(vec![*x]).unwrap()
+                } else {
+                    format!("\\x{:02x}", *x)
+                }
+            })
+            .fold("".to_owned(), |mut x, y| {
+                x.push_str(&y);
+                x
+            })
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
+        let mut name_converted = [0; 16];
+        let bytes = name.as_bytes();
+        let mut out_i = 0;
+        let mut in_i = 0;
+        loop {
+            if in_i == bytes.len() {
+                break;
+            }
+            if out_i == name_converted.len() {
+                return None;
+            }
+
+            if bytes[in_i] != b'\\' {
+                name_converted[out_i] = bytes[in_i];
+                out_i += 1;
+                in_i += 1;
+            } else {
+                in_i += 1;
+                if *bytes.get(in_i)? != b'x' {
+                    return None;
+                }
+                in_i += 1;
+                name_converted[out_i] =
+                    u8::from_str_radix(std::str::from_utf8(bytes.get(in_i..in_i + 2)?).ok()?, 16)
+                        .ok()?;
+                out_i += 1;
+                in_i += 2;
+            }
+        }
+        Some(name_converted)
+    }
+}
+
+/// Names in the SD directory tree, as they are.
+impl NameConvert for String {
+    fn name_3ds_to_str(name: &String) -> String {
+        name.clone()
+    }
+
+    fn name_str_to_3ds(name: &str) -> Option<String> {
+        Some(name.to_owned())
+    }
+}
+
+/// A file or a directory opened by path.
+pub enum PathEntry<T: FileSystem> {
+    Dir(T::DirType),
+    File(T::FileType),
+}
+
+fn split<N: NameConvert>(path: &str) -> Result<Vec<N>, Error> {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .map(|name| N::name_str_to_3ds(name).ok_or(Error::InvalidValue))
+        .collect()
+}
+
+fn join(parent: &str, name: &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
+}
+
+fn open_dir_names<T: FileSystem>(fs: &T, names: Vec<T::NameType>) -> Result<T::DirType, Error> {
+    let mut dir = fs.open_root()?;
+    for name in names {
+        dir = dir.open_sub_dir(name)?;
+    }
+    Ok(dir)
+}
+
+/// Opens the file or directory at `path`.
+pub fn open_path<T: FileSystem>(fs: &T, path: &str) -> Result<PathEntry<T>, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut names = split::<T::NameType>(path)?;
+    let name = match names.pop() {
+        None => return Ok(PathEntry::Dir(fs.open_root()?)),
+        Some(name) => name,
+    };
+    let parent = open_dir_names(fs, names)?;
+    match parent.open_sub_dir(name.clone()) {
+        Ok(dir) => Ok(PathEntry::Dir(dir)),
+        Err(Error::NotFound) => Ok(PathEntry::File(parent.open_sub_file(name)?)),
+        Err(e) => Err(e),
+    }
+}
+
+fn create_dir_names<T: FileSystem>(fs: &T, names: Vec<T::NameType>) -> Result<T::DirType, Error>
+where
+    T::NameType: Clone,
+{
+    let mut dir = fs.open_root()?;
+    for name in names {
+        dir = match dir.open_sub_dir(name.clone()) {
+            Ok(sub_dir) => sub_dir,
+            Err(Error::NotFound) => dir.new_sub_dir(name)?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(dir)
+}
+
+/// Opens the directory at `path`, creating it and all missing parent directories.
+pub fn create_all<T: FileSystem>(fs: &T, path: &str) -> Result<T::DirType, Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    create_dir_names(fs, split(path)?)
+}
+
+fn remove_dir_content<T: FileSystem>(fs: &T, dir: &T::DirType) -> Result<(), Error> {
+    for (_, ino) in dir.list_sub_dir()? {
+        let sub_dir = fs.open_dir(ino)?;
+        remove_dir_content(fs, &sub_dir)?;
+        sub_dir.delete()?;
+    }
+    for (_, ino) in dir.list_sub_file()? {
+        fs.open_file(ino)?.delete()?;
+    }
+    Ok(())
+}
+
+/// Removes the file or directory at `path`, with everything in it.
+/// For the root directory, only the content is removed.
+pub fn remove_all<T: FileSystem>(fs: &T, path: &str) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let is_root = split::<T::NameType>(path)?.is_empty();
+    match open_path(fs, path)? {
+        PathEntry::Dir(dir) => {
+            remove_dir_content(fs, &dir)?;
+            if !is_root {
+                dir.delete()?;
+            }
+            Ok(())
+        }
+        PathEntry::File(file) => file.delete(),
+    }
+}
+
+fn walk_dir<T: FileSystem>(
+    fs: &T,
+    dir: &T::DirType,
+    path: &str,
+    f: &mut impl FnMut(&str, &PathEntry<T>) -> Result<(), Error>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert,
+{
+    for (name, ino) in dir.list_sub_dir()? {
+        let sub_path = join(path, &T::NameType::name_3ds_to_str(&name));
+        let sub_dir = PathEntry::Dir(fs.open_dir(ino)?);
+        f(&sub_path, &sub_dir)?;
+        if let PathEntry::Dir(sub_dir) = sub_dir {
+            walk_dir(fs, &sub_dir, &sub_path, f)?;
+        }
+    }
+    for (name, ino) in dir.list_sub_file()? {
+        let sub_path = join(path, &T::NameType::name_3ds_to_str(&name));
+        f(&sub_path, &PathEntry::File(fs.open_file(ino)?))?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with the path of every file and directory under the directory at `path`,
+/// each directory before its content, in the order the archive lists them.
+pub fn walk<T: FileSystem>(
+    fs: &T,
+    path: &str,
+    mut f: impl FnMut(&str, &PathEntry<T>) -> Result<(), Error>,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let dir = open_dir_names(fs, split::<T::NameType>(path)?)?;
+    walk_dir(fs, &dir, path, &mut f)
+}
+
+/// Copies a host file, or a host directory with everything in it, to `path`.
+/// Missing parent directories are created, and existing directories are merged into.
+pub fn copy_from_host<T: FileSystem>(fs: &T, host_path: &Path, path: &str) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    if host_path.is_dir() {
+        create_all(fs, path)?;
+        for entry in std::fs::read_dir(host_path)? {
+            let entry = entry?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| Error::InvalidValue)?;
+            copy_from_host(fs, &entry.path(), &join(path, &name))?;
+        }
+        return Ok(());
+    }
+
+    let mut names = split::<T::NameType>(path)?;
+    let name = names.pop().ok_or(Error::InvalidValue)?;
+    let parent = create_dir_names(fs, names)?;
+
+    let mut host_file = std::fs::File::open(host_path)?;
+    let len = host_file.metadata()?.len() as usize;
+    let file = parent.new_sub_file(name, len)?;
+    copy_chunks(
+        len,
+        CHUNK_LEN,
+        |_, buf| Ok(host_file.read_exact(buf)?),
+        |pos, buf| file.write(pos, buf),
+    )?;
+    file.commit()
+}
+
+/// Copies the file, or the directory with everything in it, at `path` to the host.
+/// Regions in files that fail the hash check are copied as 0xDD or zeros.
+pub fn copy_to_host<T: FileSystem>(fs: &T, path: &str, host_path: &Path) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    match open_path(fs, path)? {
+        PathEntry::Dir(dir) => {
+            if !host_path.exists() {
+                std::fs::create_dir(host_path)?;
+            }
+            for (name, _) in dir.list_sub_dir()?.into_iter().chain(dir.list_sub_file()?) {
+                let name = T::NameType::name_3ds_to_str(&name);
+                copy_to_host(fs, &join(path, &name), &host_path.join(&name))?;
+            }
+            Ok(())
+        }
+        PathEntry::File(file) => copy_file_to_host(&file, host_path, CHUNK_LEN),
+    }
+}
+
+fn copy_file_to_host(
+    file: &impl FileSystemFile,
+    host_path: &Path,
+    chunk_len: usize,
+) -> Result<(), Error> {
+    let mut host_file = std::fs::File::create(host_path)?;
+    copy_chunks(
+        file.len(),
+        chunk_len,
+        |pos, buf| {
+            // A read stops at the first uninitialized block,
+            // so the rest of the chunk must not keep the previous one
+            buf.iter_mut().for_each(|b| *b = 0);
+            match file.read(pos, buf) {
+                Ok(()) | Err(Error::HashMismatch) => Ok(()),
+                Err(e) => Err(e),
+            }
+        },
+        |_, buf| Ok(host_file.write_all(buf)?),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file::MemoryFile;
+    use crate::path_helper::*;
+    use crate::random_access_file::*;
+    use crate::save_data::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn name_convert() {
+        let mut name = [0; 16];
+        name[0..6].copy_from_slice(b"a/b\\c\x01");
+        let s = <[u8; 16]>::name_3ds_to_str(&name);
+        assert_eq!(s, "a\\x2fb\\x5cc\\x01");
+        assert_eq!(<[u8; 16]>::name_str_to_3ds(&s), Some(name));
+        assert_eq!(<[u8; 16]>::name_str_to_3ds("\\y00"), None);
+        assert_eq!(<[u8; 16]>::name_str_to_3ds("12345678901234567"), None);
+        assert_eq!(
+            u64::name_str_to_3ds(&u64::name_3ds_to_str(&0x1234)),
+            Some(0x1234)
+        );
+    }
+
+    #[test]
+    fn path_operations() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+        SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(disa_raw, SaveDataType::Bare).unwrap();
+
+        let dir = create_all(&save, "/a/b").unwrap();
+        dir.new_sub_file(*b"f\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0", 3)
+            .unwrap()
+            .write(0, &[1, 2, 3])
+            .unwrap();
+        assert_eq!(create_all(&save, "a//b/").unwrap().get_ino(), dir.get_ino());
+        match open_path(&save, "a/b/f").unwrap() {
+            PathEntry::File(file) => assert_eq!(file.len(), 3),
+            PathEntry::Dir(_) => panic!(),
+        }
+        assert!(matches!(open_path(&save, "/a").unwrap(), PathEntry::Dir(_)));
+        assert!(matches!(open_path(&save, "/c"), Err(Error::NotFound)));
+        assert!(matches!(
+            open_path(&save, "/12345678901234567"),
+            Err(Error::InvalidValue)
+        ));
+
+        let host = std::env::temp_dir().join(format!("save3ds_path_test_{}", std::process::id()));
+        copy_to_host(&save, "/a", &host).unwrap();
+        assert_eq!(
+            std::fs::read(host.join("b").join("f")).unwrap(),
+            vec![1, 2, 3]
+        );
+        copy_from_host(&save, &host, "/c/d").unwrap();
+        std::fs::remove_dir_all(&host).unwrap();
+
+        let mut visited = vec![];
+        walk(&save, "/", |path, entry| {
+            visited.push((path.to_owned(), matches!(entry, PathEntry::Dir(_))));
+            Ok(())
+        })
+        .unwrap();
+        visited.sort();
+        let expected: Vec<(String, bool)> = vec![
+            ("/a", true),
+            ("/a/b", true),
+            ("/a/b/f", false),
+            ("/c", true),
+            ("/c/d", true),
+            ("/c/d/b", true),
+            ("/c/d/b/f", false),
+        ]
+        .into_iter()
+        .map(|(path, is_dir)| (path.to_owned(), is_dir))
+        .collect();
+        assert_eq!(visited, expected);
+
+        match open_path(&save, "/c/d/b/f").unwrap() {
+            PathEntry::File(file) => {
+                let mut buf = [0; 3];
+                file.read(0, &mut buf).unwrap();
+                assert_eq!(buf, [1, 2, 3]);
+            }
+            PathEntry::Dir(_) => panic!(),
+        }
+
+        remove_all(&save, "/c").unwrap();
+        assert!(matches!(open_path(&save, "/c"), Err(Error::NotFound)));
+        assert!(matches!(remove_all(&save, "/"), Err(Error::Busy)));
+        drop(dir);
+        remove_all(&save, "/").unwrap();
+        assert!(save.open_root().unwrap().list_sub_dir().unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_broken_to_host() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Large,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x80000]));
+        SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+        let root = save.open_root().unwrap();
+        let mut file = root.new_sub_file([1; 16], 0x2000).unwrap();
+        root.new_sub_file([2; 16], 0x1000)
+            .unwrap()
+            .write(0, &[0xFF; 0x1000])
+            .unwrap();
+        file.resize(0x4000).unwrap();
+        for i in 0..4 {
+            file.write(i * 0x1000, &[i as u8 + 1; 0x1000]).unwrap();
+        }
+        drop(file);
+        drop(root);
+        save.commit().unwrap();
+        drop(save);
+
+        // Break the third block of the file, which is in its second fragment
+        let mut raw = vec![0; disa_raw.len()];
+        disa_raw.read(0, &mut raw).unwrap();
+        for pos in (0..raw.len()).step_by(0x1000) {
+            if raw[pos..pos + 0x1000] == [3; 0x1000][..] {
+                disa_raw.write(pos, &[0]).unwrap();
+            }
+        }
+
+        let save = SaveData::new(disa_raw, SaveDataType::Bare).unwrap();
+        let file = save.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+        let host = std::env::temp_dir().join(format!("save3ds_copy_test_{}", std::process::id()));
+        copy_file_to_host(&file, &host, 0x2000).unwrap();
+        let mut expected = vec![1; 0x1000];
+        expected.extend(&[2; 0x1000][..]);
+        expected.extend(&[0xDD; 0x1000][..]);
+        expected.extend(&[0; 0x1000][..]);
+        assert_eq!(std::fs::read(&host).unwrap(), expected);
+        std::fs::remove_file(&host).unwrap();
+    }
+}
@@ -22,6 +22,11 @@ pub trait RandomAccessFile {
     /// Returns the length of this file.
     fn len(&self) -> usize;
 
+    /// Returns whether this file has zero length.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Flushes all changes made to the file,
     /// so that when the same file is opened after dropping this one,
     /// all data can be fully recovered.
@@ -53,6 +58,42 @@ pub fn write_struct<T: ByteStruct>(
     Ok(())
 }
 
+/// Copies `len` bytes in chunks of at most `chunk_len` bytes. For each chunk,
+/// `read` is called with its position and a buffer to fill, then `write` with the filled buffer.
+pub fn copy_chunks(
+    len: usize,
+    chunk_len: usize,
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<(), Error>,
+    mut write: impl FnMut(usize, &[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if chunk_len == 0 {
+        return make_error(Error::InvalidValue);
+    }
+    let mut buf = vec![0; std::cmp::min(chunk_len, len)];
+    let mut pos = 0;
+    while pos < len {
+        let chunk_len = std::cmp::min(chunk_len, len - pos);
+        read(pos, &mut buf[0..chunk_len])?;
+        write(pos, &buf[0..chunk_len])?;
+        pos += chunk_len;
+    }
+    Ok(())
+}
+
+/// Copies all data from `src` to `dst`, which must be at least as long as `src`.
+pub(crate) fn copy_file(
+    src: &dyn RandomAccessFile,
+    dst: &dyn RandomAccessFile,
+) -> Result<(), Error> {
+    const CHUNK_LEN: usize = 0x10_0000;
+    copy_chunks(
+        src.len(),
+        CHUNK_LEN,
+        |pos, buf| src.read(pos, buf),
+        |pos, buf| dst.write(pos, buf),
+    )
+}
+
 /// Driver for fuzz test an implementation for `RandomAccessFile`.
 ///
 /// - `subject`: the object that contains the `RandomAccessFile` implementation to test.
@@ -3,7 +3,7 @@ use crate::disa::Disa;
 use crate::error::*;
 use crate::fat::*;
 use crate::file_system::*;
-use crate::fs_meta::{self, FileInfo, FsInfo, OffsetOrFatFile};
+use crate::fs_meta::{self, FatFileInfo, FileInfo, FsInfo, OffsetOrFatFile};
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::save_ext_common::*;
@@ -32,6 +32,15 @@ impl FileInfo for SaveFile {
     }
 }
 
+impl FatFileInfo for SaveFile {
+    fn set_block(&mut self, block: u32) {
+        self.block = block;
+    }
+    fn get_block(&self) -> u32 {
+        self.block
+    }
+}
+
 type FsMeta = fs_meta::FsMeta<SaveExtKey, SaveExtDir, SaveExtKey, SaveFile>;
 type DirMeta = fs_meta::DirMeta<SaveExtKey, SaveExtDir, SaveExtKey, SaveFile>;
 type FileMeta = fs_meta::FileMeta<SaveExtKey, SaveExtDir, SaveExtKey, SaveFile>;
@@ -96,8 +105,11 @@ struct SaveDataInner {
     disa: Rc<Disa>,
     fat: Rc<Fat>,
     fs: Rc<FsMeta>,
+    fs_info: Rc<dyn RandomAccessFile>,
     block_len: usize,
     block_count: usize,
+    param: SaveDataFormatParam,
+    len: usize,
 }
 
 /// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for game save data.
@@ -422,7 +434,35 @@ impl SaveData {
         file: Rc<dyn RandomAccessFile>,
         save_data_type: SaveDataType,
     ) -> Result<SaveData, Error> {
-        let disa = Rc::new(Disa::new(file, SaveData::get_signer(save_data_type))?);
+        SaveData::open(file, save_data_type, false)
+    }
+
+    /// Opens the version of the save data before the last commit. See
+    /// [`Resource::open_sd_save_previous`](../struct.Resource.html#method.open_sd_save_previous).
+    pub(crate) fn new_previous(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+    ) -> Result<SaveData, Error> {
+        SaveData::open(file, save_data_type, true)
+    }
+
+    /// Makes the version opened by `new_previous` the current one.
+    pub(crate) fn restore_previous(&self) -> Result<(), Error> {
+        self.center.disa.restore_previous()
+    }
+
+    fn open(
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        previous: bool,
+    ) -> Result<SaveData, Error> {
+        let len = file.len();
+        let signer = SaveData::get_signer(save_data_type);
+        let disa = Rc::new(if previous {
+            Disa::new_previous(file, signer)?
+        } else {
+            Disa::new(file, signer)?
+        });
         let header: SaveHeader = read_struct(disa[0].as_ref(), 0)?;
         if header.magic != *b"SAVE" || header.version != 0x40000 {
             error!(
@@ -431,7 +471,12 @@ impl SaveData {
             );
             return make_error(Error::MagicMismatch);
         }
-        let fs_info: FsInfo = read_struct(disa[0].as_ref(), header.fs_info_offset as usize)?;
+        let fs_info_file = Rc::new(SubFile::new(
+            disa[0].clone(),
+            header.fs_info_offset as usize,
+            FsInfo::BYTE_LEN,
+        )?);
+        let fs_info: FsInfo = read_struct(fs_info_file.as_ref(), 0)?;
         if fs_info.data_block_count != fs_info.fat_size {
             error!(
                 "Unexpected data_block_count={}, fat_size={}",
@@ -494,16 +539,148 @@ impl SaveData {
 
         let fs = FsMeta::new(dir_hash, dir_table, file_hash, file_table)?;
 
+        let param = SaveDataFormatParam {
+            block_type: if fs_info.block_len == 512 {
+                SaveDataBlockType::Small
+            } else {
+                SaveDataBlockType::Large
+            },
+            max_dir: fs_info.max_dir as usize,
+            dir_buckets: fs_info.dir_buckets as usize,
+            max_file: fs_info.max_file as usize,
+            file_buckets: fs_info.file_buckets as usize,
+            duplicate_data: disa.partition_count() == 1,
+        };
+
         Ok(SaveData {
             center: Rc::new(SaveDataInner {
                 disa,
                 fat,
                 fs,
+                fs_info: fs_info_file,
                 block_len: fs_info.block_len as usize,
                 block_count: fs_info.data_block_count as usize,
+                param,
+                len,
             }),
         })
     }
+
+    /// Returns the parameters the save data was formatted with.
+    pub fn format_param(&self) -> SaveDataFormatParam {
+        self.center.param
+    }
+
+    /// Returns the size in bytes of the save data image.
+    pub fn image_len(&self) -> usize {
+        self.center.len
+    }
+
+    /// Formats `file` with `param` and copies all files and directories into it.
+    ///
+    /// Entries are created in the same order they are listed in each directory,
+    /// but inode numbers are not kept. Uninitialized file data stays uninitialized.
+    pub(crate) fn reconfigure(
+        &self,
+        file: Rc<dyn RandomAccessFile>,
+        save_data_type: SaveDataType,
+        param: &SaveDataFormatParam,
+    ) -> Result<(), Error> {
+        SaveData::format(file.clone(), save_data_type.clone(), param)?;
+        let save = SaveData::new(file, save_data_type)?;
+        SaveData::copy_dir(&self.open_root()?, &save.open_root()?)?;
+        save.commit()
+    }
+
+    /// Returns how fragmented the blocks of the save data are.
+    ///
+    /// This walks the whole directory tree and every allocation chain,
+    /// so it is not part of [`stat`](../file_system/trait.FileSystem.html#tymethod.stat).
+    pub fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        fs_meta::fat_fragmentation(
+            &self.center.fs,
+            &self.center.fat,
+            self.center.fs_info.as_ref(),
+            self.center.disa.partition_count() == 1,
+        )
+    }
+
+    /// Moves every file to a contiguous run of blocks, and all free blocks to one run after them.
+    /// See [`fragmentation`](#method.fragmentation) for how fragmented the save data is.
+    ///
+    /// Blocks are moved in place and the new allocation table only takes effect on the commit,
+    /// so this is only crash-safe if data is duplicated. Otherwise `Error::Unsupported` is
+    /// returned and the save data is left untouched.
+    ///
+    /// No file or directory of the save data can be open, otherwise `Error::Busy` is returned.
+    /// The save data is committed and closed afterwards.
+    pub fn defragment(self) -> Result<(), Error> {
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        if self.center.disa.partition_count() != 1 {
+            return make_error(Error::Unsupported);
+        }
+        fs_meta::defragment_fat(
+            &self.center.fs,
+            &self.center.fat,
+            self.center.fs_info.as_ref(),
+            true,
+        )?;
+        self.center.disa.commit()
+    }
+
+    /// Rebuilds the directory and file hash tables with the specified bucket counts.
+    /// See [`Stat::dir_hash`](../file_system/struct.Stat.html#structfield.dir_hash) and
+    /// [`Stat::file_hash`](../file_system/struct.Stat.html#structfield.file_hash)
+    /// for how entries are distributed over the current buckets.
+    ///
+    /// The allocation table and the directory and file tables are moved to fit the new hash
+    /// tables, and entries keep their inodes. If data is duplicated, the blocks in use are also
+    /// moved, and the number of blocks changes with the size of the hash tables, as in
+    /// [`Db::rehash`](../db/struct.Db.html#method.rehash). Otherwise the hash tables can only
+    /// grow into the padding before the end of the metadata partition. If the content doesn't fit,
+    /// `Error::NoSpace` is returned and the save data is left untouched.
+    ///
+    /// No file or directory of the save data can be open, otherwise `Error::Busy` is returned.
+    /// The save data is committed and closed afterwards.
+    pub fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        if Rc::strong_count(&self.center) != 1 {
+            return make_error(Error::Busy);
+        }
+        fs_meta::rehash_in_place(
+            &self.center.fs,
+            &self.center.fat,
+            self.center.disa[0].clone(),
+            self.center.fs_info.as_ref(),
+            self.center.disa.partition_count() == 1,
+            dir_buckets,
+            file_buckets,
+        )?;
+        self.center.disa.commit()
+    }
+
+    fn copy_dir(from: &Dir, to: &Dir) -> Result<(), Error> {
+        // New entries are inserted at the head of the list, so we create them from the tail
+        for (name, _) in from.list_sub_dir()?.into_iter().rev() {
+            SaveData::copy_dir(&from.open_sub_dir(name)?, &to.new_sub_dir(name)?)?;
+        }
+        for (name, _) in from.list_sub_file()?.into_iter().rev() {
+            let from_file = from.open_sub_file(name)?;
+            let to_file = to.new_sub_file(name, from_file.len())?;
+            let mut buf = vec![0; from.center.block_len];
+            for pos in (0..from_file.len()).step_by(buf.len()) {
+                let end = std::cmp::min(pos + buf.len(), from_file.len());
+                let buf = &mut buf[0..end - pos];
+                match from_file.read(pos, buf) {
+                    Ok(()) => to_file.write(pos, buf)?,
+                    Err(Error::HashMismatch) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for save data file.
@@ -776,6 +953,8 @@ impl FileSystem for SaveData {
             free_files: meta_stat.files.free,
             total_dirs: meta_stat.dirs.total,
             free_dirs: meta_stat.dirs.free,
+            dir_hash: Some(meta_stat.dirs.chains),
+            file_hash: Some(meta_stat.files.chains),
         })
     }
 }
@@ -808,6 +987,279 @@ mod test {
         }
     }
 
+    #[test]
+    fn previous_version() {
+        for &duplicate_data in [false, true].iter() {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+
+            let read = |save: &SaveData| {
+                let file = save.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+                let mut buf = vec![0; file.len()];
+                file.read(0, &mut buf).unwrap();
+                buf
+            };
+
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let file = save.open_root().unwrap().new_sub_file([1; 16], 3).unwrap();
+            file.write(0, &[1; 3]).unwrap();
+            drop(file);
+            save.commit().unwrap();
+            let file = save.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+            file.write(0, &[2; 3]).unwrap();
+            drop(file);
+            save.commit().unwrap();
+            drop(save);
+
+            let save = SaveData::new_previous(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            if !duplicate_data {
+                // file data is not duplicated, so the previous version has been overwritten
+                let file = save.open_root().unwrap().open_sub_file([1; 16]).unwrap();
+                assert!(matches!(
+                    file.read(0, &mut [0; 3]),
+                    Err(Error::HashMismatch)
+                ));
+                continue;
+            }
+            assert_eq!(read(&save), vec![1; 3]);
+            assert!(save.commit().is_err());
+            save.restore_previous().unwrap();
+            drop(save);
+
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            assert_eq!(read(&save), vec![1; 3]);
+            drop(save);
+            let save = SaveData::new_previous(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            assert_eq!(read(&save), vec![2; 3]);
+            assert!(SaveData::new(disa_raw.clone(), SaveDataType::Bare)
+                .unwrap()
+                .restore_previous()
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn reconfigure() {
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Small,
+            max_dir: 5,
+            dir_buckets: 5,
+            max_file: 5,
+            file_buckets: 5,
+            duplicate_data: false,
+        };
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+        SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+        let save = SaveData::new(disa_raw, SaveDataType::Bare).unwrap();
+        assert_eq!(save.format_param(), param);
+        let root = save.open_root().unwrap();
+        let dir = root.new_sub_dir([1; 16]).unwrap();
+        root.new_sub_dir([2; 16]).unwrap();
+        for i in 0..4 {
+            let file = dir.new_sub_file([i; 16], 4000).unwrap();
+            file.write(0, &[i; 4000]).unwrap();
+        }
+        // leave the data uninitialized
+        root.new_sub_file([3; 16], 10).unwrap();
+
+        let new_param = SaveDataFormatParam {
+            max_file: 10,
+            duplicate_data: true,
+            ..param
+        };
+        let too_small = Rc::new(MemoryFile::new(vec![0; 0x8000]));
+        assert!(matches!(
+            save.reconfigure(too_small, SaveDataType::Bare, &new_param),
+            Err(Error::NoSpace)
+        ));
+
+        let new_raw = Rc::new(MemoryFile::new(vec![0; 0x40000]));
+        save.reconfigure(new_raw.clone(), SaveDataType::Bare, &new_param)
+            .unwrap();
+        let new_save = SaveData::new(new_raw, SaveDataType::Bare).unwrap();
+        assert_eq!(new_save.format_param(), new_param);
+        assert_eq!(new_save.image_len(), 0x40000);
+        assert_eq!(new_save.stat().unwrap().total_files, 10);
+
+        let new_root = new_save.open_root().unwrap();
+        let names = |list: Vec<([u8; 16], u32)>| -> Vec<[u8; 16]> {
+            list.into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(
+            names(new_root.list_sub_dir().unwrap()),
+            names(root.list_sub_dir().unwrap())
+        );
+        assert_eq!(
+            names(new_root.list_sub_file().unwrap()),
+            names(root.list_sub_file().unwrap())
+        );
+        let new_dir = new_root.open_sub_dir([1; 16]).unwrap();
+        assert_eq!(
+            names(new_dir.list_sub_file().unwrap()),
+            names(dir.list_sub_file().unwrap())
+        );
+        for i in 0..4 {
+            let file = new_dir.open_sub_file([i; 16]).unwrap();
+            let mut buf = vec![0; file.len()];
+            file.read(0, &mut buf).unwrap();
+            assert_eq!(buf, vec![i; 4000]);
+        }
+        assert_eq!(new_root.open_sub_file([3; 16]).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn rehash() {
+        for &duplicate_data in [false, true].iter() {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 5,
+                dir_buckets: 1,
+                max_file: 5,
+                file_buckets: 1,
+                duplicate_data,
+            };
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let root = save.open_root().unwrap();
+            let mut inos = vec![];
+            for i in 0..4 {
+                let file = root.new_sub_file([i; 16], 100).unwrap();
+                file.write(0, &[i; 100]).unwrap();
+                inos.push(file.get_ino());
+            }
+            save.commit().unwrap();
+            let stat = save.stat().unwrap();
+            assert_eq!(stat.file_hash.unwrap().longest_chain, 4);
+            let save = match save.rehash(3, 7) {
+                Err(Error::Busy) => SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap(),
+                _ => panic!(),
+            };
+            drop(root);
+
+            let save = match save.rehash(0x10000, 7) {
+                Err(Error::NoSpace) => SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap(),
+                _ => panic!(),
+            };
+            assert_eq!(save.format_param(), param);
+
+            save.rehash(3, 7).unwrap();
+            let save = SaveData::new(disa_raw, SaveDataType::Bare).unwrap();
+            assert_eq!(
+                save.format_param(),
+                SaveDataFormatParam {
+                    dir_buckets: 3,
+                    file_buckets: 7,
+                    ..param
+                }
+            );
+            let stat = save.stat().unwrap();
+            assert_eq!(stat.dir_hash.unwrap().buckets, 3);
+            let file_hash = stat.file_hash.unwrap();
+            assert_eq!((file_hash.buckets, file_hash.entries), (7, 4));
+            let root = save.open_root().unwrap();
+            for i in 0..4 {
+                let file = root.open_sub_file([i; 16]).unwrap();
+                assert_eq!(file.get_ino(), inos[i as usize]);
+                let mut buf = vec![0; file.len()];
+                file.read(0, &mut buf).unwrap();
+                assert_eq!(buf, vec![i; 100]);
+            }
+            root.new_sub_file([9; 16], 100).unwrap();
+
+            // the unused part of the tables is uninitialized right after formatting
+            let param = SaveDataFormatParam {
+                max_dir: 100,
+                max_file: 100,
+                ..param
+            };
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x80000]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            save.rehash(3, 7).unwrap();
+            let save = SaveData::new(disa_raw, SaveDataType::Bare).unwrap();
+            save.open_root()
+                .unwrap()
+                .new_sub_file([1; 16], 100)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn defragment() {
+        for &duplicate_data in [false, true].iter() {
+            let param = SaveDataFormatParam {
+                block_type: SaveDataBlockType::Small,
+                max_dir: 10,
+                dir_buckets: 10,
+                max_file: 10,
+                file_buckets: 10,
+                duplicate_data,
+            };
+            let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x20000]));
+            SaveData::format(disa_raw.clone(), SaveDataType::Bare, &param).unwrap();
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let root = save.open_root().unwrap();
+            let dir = root.new_sub_dir([1; 16]).unwrap();
+            let mut files = vec![
+                root.new_sub_file([2; 16], 1).unwrap(),
+                dir.new_sub_file([3; 16], 1).unwrap(),
+            ];
+            for len in 2..6 {
+                for file in files.iter_mut() {
+                    file.resize(len * 512).unwrap();
+                }
+            }
+            for (i, file) in files.iter().enumerate() {
+                file.write(0, &vec![i as u8; file.len()]).unwrap();
+            }
+            let fragmentation = save.fragmentation().unwrap();
+            assert_eq!(fragmentation.max_file_runs, 5);
+
+            save.commit().unwrap();
+            assert!(matches!(save.defragment(), Err(Error::Busy)));
+            drop(files);
+            drop(dir);
+            drop(root);
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            if !duplicate_data {
+                assert!(matches!(save.defragment(), Err(Error::Unsupported)));
+                continue;
+            }
+            let free_blocks = save.stat().unwrap().free_blocks;
+            save.defragment().unwrap();
+
+            let save = SaveData::new(disa_raw.clone(), SaveDataType::Bare).unwrap();
+            let stat = save.stat().unwrap();
+            assert_eq!(stat.free_blocks, free_blocks);
+            let fragmentation = save.fragmentation().unwrap();
+            assert_eq!(fragmentation.max_file_runs, 1);
+            assert_eq!(fragmentation.free_runs, 1);
+            assert_eq!(fragmentation.largest_free_run, free_blocks);
+            let root = save.open_root().unwrap();
+            let files = [
+                root.open_sub_file([2; 16]).unwrap(),
+                root.open_sub_dir([1; 16])
+                    .unwrap()
+                    .open_sub_file([3; 16])
+                    .unwrap(),
+            ];
+            for (i, file) in files.iter().enumerate() {
+                let mut buf = vec![0; file.len()];
+                file.read(0, &mut buf).unwrap();
+                assert_eq!(buf, vec![i as u8; 5 * 512]);
+            }
+        }
+    }
+
     #[test]
     fn fs_fuzz() {
         use rand::prelude::*;
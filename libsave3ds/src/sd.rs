@@ -1,49 +1,76 @@
 use crate::aes_ctr_file::AesCtrFile;
-use crate::disk_file::DiskFile;
 use crate::error::*;
 use crate::key_engine::*;
 use crate::misc::*;
 use crate::random_access_file::*;
 use crate::sd_nand_common::*;
 use sha2::*;
-use std::path::*;
 use std::rc::Rc;
 
+/// The `Nintendo 3DS/<ID0>/<ID1>` directory on SD, with transparent encryption.
 pub struct Sd {
-    path: PathBuf,
+    root: Rc<dyn SdNandFileSystem>,
+    id0: String,
+    id1: String,
     key: [u8; 16],
 }
 
 impl Sd {
-    pub fn new(sd_path: &str, key_x: [u8; 16], key_y: [u8; 16]) -> Result<Sd, Error> {
-        let path = std::fs::read_dir(
-            PathBuf::from(sd_path)
-                .join("Nintendo 3DS")
-                .join(hash_movable(key_y)),
-        )?
-        .find(|a| {
-            a.as_ref()
-                .map(|a| a.file_type().map(|a| a.is_dir()).unwrap_or(false))
-                .unwrap_or(false)
-        })
-        .ok_or(Error::BrokenSd)??
-        .path();
+    /// Opens the `Nintendo 3DS/<ID0>/<ID1>` directory on SD, with `root` being the SD root.
+    /// If `id1` is not specified, the first ID1 directory in alphabetical order is used.
+    pub fn new(
+        root: Rc<dyn SdNandFileSystem>,
+        id1: Option<&str>,
+        key_x: [u8; 16],
+        key_y: [u8; 16],
+    ) -> Result<Sd, Error> {
+        let id0 = hash_movable(key_y);
+        let id1_list = list_id1(root.as_ref(), &id0)?;
+        let id1 = if let Some(id1) = id1 {
+            if !id1_list.iter().any(|a| a == id1) {
+                return make_error(Error::NotFound);
+            }
+            id1.to_owned()
+        } else {
+            id1_list.into_iter().next().ok_or(Error::BrokenSd)?
+        };
         let key = scramble(key_x, key_y);
-        Ok(Sd { path, key })
+        Ok(Sd {
+            root,
+            id0,
+            id1,
+            key,
+        })
     }
-}
 
-impl SdNandFileSystem for Sd {
-    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
-        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        let file = Rc::new(DiskFile::new(
-            std::fs::OpenOptions::new()
-                .read(true)
-                .write(write)
-                .open(file_path)?,
-        )?);
-
-        let hash_path: Vec<u8> = path
+    /// Lists all ID1 directories under the ID0 directory, in alphabetical order.
+    pub fn list_id1(&self) -> Result<Vec<String>, Error> {
+        list_id1(self.root.as_ref(), &self.id0)
+    }
+
+    /// Returns the name of the ID1 directory in use.
+    pub fn id1(&self) -> &str {
+        &self.id1
+    }
+
+    fn root_path<'a>(&'a self, path: &[&'a str]) -> Vec<&'a str> {
+        ["Nintendo 3DS", &self.id0, &self.id1]
+            .iter()
+            .chain(path.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Opens the file at `path`, encrypted as if it were at `crypto_path`.
+    fn open_as(
+        &self,
+        path: &[&str],
+        crypto_path: &[&str],
+        write: bool,
+    ) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        let file = self.root.open(&self.root_path(path), write)?;
+
+        let hash_path: Vec<u8> = crypto_path
             .iter()
             .map(|s| std::iter::once(b'/').chain(s.bytes()))
             .flatten()
@@ -62,26 +89,121 @@ impl SdNandFileSystem for Sd {
 
         Ok(Rc::new(AesCtrFile::new(file, self.key, ctr, false)))
     }
+}
+
+fn list_id1(root: &dyn SdNandFileSystem, id0: &str) -> Result<Vec<String>, Error> {
+    let (mut id1_list, _) = root.list_dir(&["Nintendo 3DS", id0])?;
+    id1_list.sort();
+    Ok(id1_list)
+}
+
+impl SdNandFileSystem for Sd {
+    fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        self.open_as(path, path, write)
+    }
 
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
-        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        std::fs::create_dir_all(file_path.parent().unwrap())?;
-        let f = std::fs::File::create(file_path)?;
-        f.set_len(len as u64)?;
-        Ok(())
+        self.root.create(&self.root_path(path), len)
     }
 
     fn remove(&self, path: &[&str]) -> Result<(), Error> {
-        let file_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        std::fs::remove_file(file_path)?;
-        Ok(())
+        self.root.remove(&self.root_path(path))
     }
 
     fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
-        let dir_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
-        if dir_path.exists() {
-            std::fs::remove_dir_all(dir_path)?;
+        self.root.remove_dir(&self.root_path(path))
+    }
+
+    fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error> {
+        self.root.list_dir(&self.root_path(path))
+    }
+
+    fn is_dir(&self, path: &[&str]) -> bool {
+        self.root.is_dir(&self.root_path(path))
+    }
+
+    fn is_file(&self, path: &[&str]) -> bool {
+        self.root.is_file(&self.root_path(path))
+    }
+
+    fn create_dir(&self, path: &[&str]) -> Result<(), Error> {
+        self.root.create_dir(&self.root_path(path))
+    }
+
+    fn remove_empty_dir(&self, path: &[&str]) -> Result<(), Error> {
+        self.root.remove_empty_dir(&self.root_path(path))
+    }
+
+    /// The content within the new length is kept,
+    /// as the encryption only depends on the path and the position.
+    fn resize(&self, path: &[&str], len: usize) -> Result<(), Error> {
+        self.root.resize(&self.root_path(path), len)
+    }
+
+    /// The temporary file is encrypted for the final path, as the encryption depends on it.
+    fn replace(&self, path: &[&str], len: usize, build: FileBuilder<'_>) -> Result<(), Error> {
+        let temp_name = temp_file_name(path)?;
+        let temp_path = temp_path(path, &temp_name);
+        let result = self
+            .root
+            .create(&self.root_path(&temp_path), len)
+            .and_then(|()| {
+                let file = self.open_as(&temp_path, path, true)?;
+                build(file.clone())?;
+                file.commit()
+            });
+        if let Err(e) = result {
+            if self.is_file(&temp_path) {
+                self.remove(&temp_path)?;
+            }
+            return Err(e);
         }
-        Ok(())
+        self.root
+            .rename(&self.root_path(&temp_path), &self.root_path(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file_system::MemoryFileSystem;
+    use crate::sd::*;
+
+    #[test]
+    fn replace() {
+        let root: Rc<dyn SdNandFileSystem> = Rc::new(MemoryFileSystem::new());
+        let id0 = hash_movable([0x55; 16]);
+        root.create_dir(&["Nintendo 3DS", &id0, "id1"]).unwrap();
+        let sd = Sd::new(root.clone(), None, [0x33; 16], [0x55; 16]).unwrap();
+
+        sd.create(&["a", "b"], 4).unwrap();
+        sd.open(&["a", "b"], true)
+            .unwrap()
+            .write(0, &[1; 4])
+            .unwrap();
+        assert!(matches!(
+            sd.replace(
+                &["a", "b"],
+                6,
+                Box::new(|file| {
+                    file.write(0, &[9; 6])?;
+                    make_error(Error::NoSpace)
+                })
+            ),
+            Err(Error::NoSpace)
+        ));
+        assert_eq!(sd.list_dir(&["a"]).unwrap().1, ["b"]);
+        sd.replace(
+            &["a", "b"],
+            6,
+            Box::new(|file| file.write(0, &[2, 3, 4, 5, 6, 7])),
+        )
+        .unwrap();
+
+        let file = sd.open(&["a", "b"], false).unwrap();
+        assert_eq!(file.len(), 6);
+        let mut buf = [0; 6];
+        file.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3, 4, 5, 6, 7]);
+        assert_eq!(sd.list_dir(&["a"]).unwrap().1, ["b"]);
     }
 }
@@ -2,57 +2,157 @@ use crate::error::*;
 use crate::random_access_file::*;
 use std::rc::Rc;
 
+/// Writes the content of a new file, given the file opened for writing.
+pub type FileBuilder<'a> = Box<dyn FnOnce(Rc<dyn RandomAccessFile>) -> Result<(), Error> + 'a>;
+
+/// The storage that SD and NAND archives live in, organized as a tree of files.
+///
+/// Paths are given as a list of names relative to the root. Implement this to keep archives
+/// somewhere other than a host directory, such as in memory or in an archive file.
+/// Only the first five methods are needed for opening save data, extdata and title databases,
+/// where `list_dir` is used for finding the ID1 directory on SD. The others have default
+/// implementations that are built on `list_dir` or report `Error::Unsupported`.
 pub trait SdNandFileSystem {
+    /// Opens a file.
     fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error>;
+
+    /// Creates a file with the specified length, replacing any existing one.
+    /// Parent directories are created as needed.
     fn create(&self, path: &[&str], len: usize) -> Result<(), Error>;
+
+    /// Removes a file.
     fn remove(&self, path: &[&str]) -> Result<(), Error>;
+
+    /// Removes a directory with everything in it. It is not an error if the directory doesn't exist.
     fn remove_dir(&self, path: &[&str]) -> Result<(), Error>;
+
+    /// Lists names of sub directories and sub files in a directory.
+    fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error>;
+
+    /// Returns whether the path is a directory.
+    fn is_dir(&self, path: &[&str]) -> bool {
+        match path.split_last() {
+            None => true,
+            Some((name, parent)) => self
+                .list_dir(parent)
+                .map(|(dirs, _)| dirs.iter().any(|d| d == name))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Returns whether the path is a file.
+    fn is_file(&self, path: &[&str]) -> bool {
+        match path.split_last() {
+            None => false,
+            Some((name, parent)) => self
+                .list_dir(parent)
+                .map(|(_, files)| files.iter().any(|f| f == name))
+                .unwrap_or(false),
+        }
+    }
+
+    /// Creates an empty directory.
+    fn create_dir(&self, _path: &[&str]) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+
+    /// Removes a directory, which must be empty.
+    fn remove_empty_dir(&self, path: &[&str]) -> Result<(), Error> {
+        let (dirs, files) = self.list_dir(path)?;
+        if !dirs.is_empty() || !files.is_empty() {
+            return make_error(Error::NotEmpty);
+        }
+        self.remove_dir(path)
+    }
+
+    /// Changes the length of a file, keeping the content within the new length.
+    fn resize(&self, _path: &[&str], _len: usize) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+
+    /// Moves a file, replacing any existing file at `to`.
+    fn rename(&self, _from: &[&str], _to: &[&str]) -> Result<(), Error> {
+        make_error(Error::Unsupported)
+    }
+
+    /// Replaces a file with a new one of length `len`, creating it if it doesn't exist.
+    /// The content is written by `build` to a temporary file next to it, which is then renamed
+    /// over the original, so the original is left intact if this fails halfway.
+    /// The temporary file is removed on failure.
+    fn replace(&self, path: &[&str], len: usize, build: FileBuilder<'_>) -> Result<(), Error> {
+        let temp_name = temp_file_name(path)?;
+        let temp_path = temp_path(path, &temp_name);
+        let result = self.create(&temp_path, len).and_then(|()| {
+            let file = self.open(&temp_path, true)?;
+            build(file.clone())?;
+            file.commit()
+        });
+        if let Err(e) = result {
+            if self.is_file(&temp_path) {
+                self.remove(&temp_path)?;
+            }
+            return Err(e);
+        }
+        self.rename(&temp_path, path)
+    }
+}
+
+/// Returns the name of the temporary file used by
+/// [`replace`](trait.SdNandFileSystem.html#method.replace).
+pub(crate) fn temp_file_name(path: &[&str]) -> Result<String, Error> {
+    Ok(format!("{}.tmp", path.last().ok_or(Error::InvalidValue)?))
+}
+
+pub(crate) fn temp_path<'a>(path: &[&'a str], temp_name: &'a str) -> Vec<&'a str> {
+    let mut temp_path = path.to_vec();
+    *temp_path.last_mut().unwrap() = temp_name;
+    temp_path
 }
 
 #[cfg(test)]
-pub mod test {
+pub(crate) mod test {
     use super::*;
-    use crate::memory_file::*;
-    use std::cell::*;
-    use std::collections::HashMap;
-    use std::rc::Rc;
+    use crate::memory_file_system::MemoryFileSystem;
 
+    /// A `MemoryFileSystem` that also checks no file is still in use when removed or resized.
     pub struct VirtualFileSystem {
-        files: RefCell<HashMap<Vec<String>, Rc<dyn RandomAccessFile>>>,
+        inner: MemoryFileSystem,
     }
 
     impl VirtualFileSystem {
         pub fn new() -> VirtualFileSystem {
             VirtualFileSystem {
-                files: RefCell::new(HashMap::new()),
+                inner: MemoryFileSystem::new(),
             }
         }
     }
 
     impl SdNandFileSystem for VirtualFileSystem {
-        fn open(&self, path: &[&str], _write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
-            let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
-            self.files
-                .borrow()
-                .get(&path)
-                .cloned()
-                .ok_or(Error::NotFound)
+        fn open(&self, path: &[&str], write: bool) -> Result<Rc<dyn RandomAccessFile>, Error> {
+            self.inner.open(path, write)
         }
         fn create(&self, path: &[&str], len: usize) -> Result<(), Error> {
-            let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
-            self.files
-                .borrow_mut()
-                .insert(path, Rc::new(MemoryFile::new(vec![0; len])));
-            Ok(())
+            self.inner.create(path, len)
         }
         fn remove(&self, path: &[&str]) -> Result<(), Error> {
-            let path: Vec<_> = path.iter().map(|&s| s.to_string()).collect();
-            let file = self.files.borrow_mut().remove(&path);
-            assert!(Rc::strong_count(&file.unwrap()) == 1);
+            let file = self.inner.take(path).unwrap();
+            assert!(Rc::strong_count(&file) == 1);
             Ok(())
         }
-        fn remove_dir(&self, _path: &[&str]) -> Result<(), Error> {
-            Ok(())
+        fn remove_dir(&self, path: &[&str]) -> Result<(), Error> {
+            self.inner.remove_dir(path)
+        }
+        fn list_dir(&self, path: &[&str]) -> Result<(Vec<String>, Vec<String>), Error> {
+            self.inner.list_dir(path)
+        }
+        fn resize(&self, path: &[&str], len: usize) -> Result<(), Error> {
+            let file = self.inner.open(path, false)?;
+            assert!(Rc::strong_count(&file) == 2);
+            drop(file);
+            self.inner.resize(path, len)
+        }
+        fn rename(&self, from: &[&str], to: &[&str]) -> Result<(), Error> {
+            self.inner.rename(from, to)
         }
     }
 }
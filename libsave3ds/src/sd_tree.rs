@@ -0,0 +1,497 @@
+use crate::error::*;
+use crate::file_system::*;
+use crate::misc::*;
+use crate::random_access_file::*;
+use crate::sd::Sd;
+use crate::sd_nand_common::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Assigns inodes to host paths on demand. Inodes are only valid for the lifetime of the
+/// [`SdTree`](struct.SdTree.html) that assigned them.
+struct InodeTable {
+    paths: HashMap<u32, Vec<String>>,
+    inos: HashMap<Vec<String>, u32>,
+    next: u32,
+}
+
+impl InodeTable {
+    fn new() -> InodeTable {
+        let mut paths = HashMap::new();
+        let mut inos = HashMap::new();
+        paths.insert(1, vec![]);
+        inos.insert(vec![], 1);
+        InodeTable {
+            paths,
+            inos,
+            next: 2,
+        }
+    }
+
+    fn get_ino(&mut self, path: &[String]) -> u32 {
+        if let Some(ino) = self.inos.get(path) {
+            return *ino;
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.paths.insert(ino, path.to_vec());
+        self.inos.insert(path.to_vec(), ino);
+        ino
+    }
+
+    fn get_path(&self, ino: u32) -> Option<Vec<String>> {
+        self.paths.get(&ino).cloned()
+    }
+
+    fn remove(&mut self, path: &[String]) {
+        if let Some(ino) = self.inos.remove(path) {
+            self.paths.remove(&ino);
+        }
+    }
+
+    /// Moves `from` and everything under it to `to`, keeping the inodes.
+    fn move_tree(&mut self, from: &[String], to: &[String]) {
+        let moved: Vec<(Vec<String>, u32)> = self
+            .inos
+            .iter()
+            .filter(|(path, _)| path.starts_with(from))
+            .map(|(path, ino)| (path.clone(), *ino))
+            .collect();
+        for (path, _) in moved.iter() {
+            self.inos.remove(path);
+        }
+        for (path, ino) in moved {
+            let new_path: Vec<String> = to
+                .iter()
+                .chain(path[from.len()..].iter())
+                .cloned()
+                .collect();
+            self.paths.insert(ino, new_path.clone());
+            self.inos.insert(new_path, ino);
+        }
+    }
+}
+
+struct SdTreeInner {
+    sd: Rc<Sd>,
+    write: bool,
+    inodes: RefCell<InodeTable>,
+}
+
+fn as_str_path(path: &[String]) -> Vec<&str> {
+    path.iter().map(|s| s.as_str()).collect()
+}
+
+fn join(path: &[String], name: String) -> Vec<String> {
+    path.iter().cloned().chain(std::iter::once(name)).collect()
+}
+
+impl SdTreeInner {
+    fn exists(&self, path: &[String]) -> bool {
+        let path = as_str_path(path);
+        self.sd.is_file(&path) || self.sd.is_dir(&path)
+    }
+
+    fn open_data(&self, path: &[String]) -> Result<Rc<dyn RandomAccessFile>, Error> {
+        self.sd.open(&as_str_path(path), self.write)
+    }
+
+    /// Re-encrypts a file to a new path. The CTR depends on the path,
+    /// so the host file can't be simply renamed.
+    fn move_file(&self, from: &[String], to: &[String]) -> Result<(), Error> {
+        let src = self.open_data(from)?;
+        let len = src.len();
+        self.sd.create(&as_str_path(to), len)?;
+        let dst = self.sd.open(&as_str_path(to), true)?;
+        copy_file(src.as_ref(), dst.as_ref())?;
+        dst.commit()?;
+        self.sd.remove(&as_str_path(from))
+    }
+
+    fn move_dir(&self, from: &[String], to: &[String]) -> Result<(), Error> {
+        self.sd.create_dir(&as_str_path(to))?;
+        let (dirs, files) = self.sd.list_dir(&as_str_path(from))?;
+        for name in dirs {
+            self.move_dir(&join(from, name.clone()), &join(to, name))?;
+        }
+        for name in files {
+            self.move_file(&join(from, name.clone()), &join(to, name))?;
+        }
+        self.sd.remove_empty_dir(&as_str_path(from))
+    }
+
+    /// Adds the blocks, files and directories under `path` to `stat`.
+    fn count(&self, path: &[String], stat: &mut Stat) -> Result<(), Error> {
+        let (dirs, files) = self.sd.list_dir(&as_str_path(path))?;
+        for name in files {
+            let len = self.sd.open(&as_str_path(&join(path, name)), false)?.len();
+            stat.total_blocks += divide_up(len, stat.block_len);
+            stat.total_files += 1;
+        }
+        for name in dirs {
+            stat.total_dirs += 1;
+            self.count(&join(path, name), stat)?;
+        }
+        Ok(())
+    }
+}
+
+/// Implements [`FileSystem`](../file_system/trait.FileSystem.html) for the decrypted view of the
+/// `Nintendo 3DS/<ID0>/<ID1>` directory on SD.
+///
+/// Files are decrypted on read and encrypted on write. Renaming a file re-encrypts its content
+/// for the new path, so renaming a large directory can take a while.
+pub struct SdTree {
+    center: Rc<SdTreeInner>,
+}
+
+impl SdTree {
+    pub(crate) fn new(sd: Rc<Sd>, write: bool) -> SdTree {
+        SdTree {
+            center: Rc::new(SdTreeInner {
+                sd,
+                write,
+                inodes: RefCell::new(InodeTable::new()),
+            }),
+        }
+    }
+}
+
+/// Implements [`FileSystemFile`](../file_system/trait.FileSystemFile.html) for a file on SD.
+pub struct File {
+    center: Rc<SdTreeInner>,
+    path: Vec<String>,
+    ino: u32,
+    data: Rc<dyn RandomAccessFile>,
+}
+
+impl File {
+    fn open(center: Rc<SdTreeInner>, path: Vec<String>) -> Result<File, Error> {
+        let data = center.open_data(&path)?;
+        let ino = center.inodes.borrow_mut().get_ino(&path);
+        Ok(File {
+            center,
+            path,
+            ino,
+            data,
+        })
+    }
+}
+
+impl FileSystemFile for File {
+    type NameType = String;
+    type DirType = Dir;
+
+    fn rename(&mut self, parent: &Self::DirType, name: String) -> Result<(), Error> {
+        let new_path = join(&parent.path, name);
+        if self.center.exists(&new_path) {
+            return make_error(Error::AlreadyExist);
+        }
+        self.center.move_file(&self.path, &new_path)?;
+        self.center
+            .inodes
+            .borrow_mut()
+            .move_tree(&self.path, &new_path);
+        self.data = self.center.open_data(&new_path)?;
+        self.path = new_path;
+        Ok(())
+    }
+
+    fn get_parent_ino(&self) -> Result<u32, Error> {
+        let parent = &self.path[0..self.path.len() - 1];
+        Ok(self.center.inodes.borrow_mut().get_ino(parent))
+    }
+
+    fn get_ino(&self) -> u32 {
+        self.ino
+    }
+
+    fn delete(self) -> Result<(), Error> {
+        self.center.sd.remove(&as_str_path(&self.path))?;
+        self.center.inodes.borrow_mut().remove(&self.path);
+        Ok(())
+    }
+
+    fn resize(&mut self, len: usize) -> Result<(), Error> {
+        self.data.commit()?;
+        self.center.sd.resize(&as_str_path(&self.path), len)?;
+        self.data = self.center.open_data(&self.path)?;
+        Ok(())
+    }
+
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.data.read(pos, buf)
+    }
+
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        self.data.write(pos, buf)
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn commit(&self) -> Result<(), Error> {
+        self.data.commit()
+    }
+}
+
+/// Implements [`FileSystemDir`](../file_system/trait.FileSystemDir.html) for a directory on SD.
+pub struct Dir {
+    center: Rc<SdTreeInner>,
+    path: Vec<String>,
+    ino: u32,
+}
+
+impl Dir {
+    fn open(center: Rc<SdTreeInner>, path: Vec<String>) -> Dir {
+        let ino = center.inodes.borrow_mut().get_ino(&path);
+        Dir { center, path, ino }
+    }
+
+    fn list(&self, dirs: bool) -> Result<Vec<(String, u32)>, Error> {
+        let (sub_dirs, sub_files) = self.center.sd.list_dir(&as_str_path(&self.path))?;
+        let names = if dirs { sub_dirs } else { sub_files };
+        let mut inodes = self.center.inodes.borrow_mut();
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let ino = inodes.get_ino(&join(&self.path, name.clone()));
+                (name, ino)
+            })
+            .collect())
+    }
+}
+
+impl FileSystemDir for Dir {
+    type NameType = String;
+    type FileType = File;
+
+    fn rename(&mut self, parent: &Self, name: String) -> Result<(), Error> {
+        let new_path = join(&parent.path, name);
+        if new_path.starts_with(&self.path) {
+            return make_error(Error::InvalidValue);
+        }
+        if self.center.exists(&new_path) {
+            return make_error(Error::AlreadyExist);
+        }
+        self.center.move_dir(&self.path, &new_path)?;
+        self.center
+            .inodes
+            .borrow_mut()
+            .move_tree(&self.path, &new_path);
+        self.path = new_path;
+        Ok(())
+    }
+
+    fn get_parent_ino(&self) -> Result<u32, Error> {
+        if self.path.is_empty() {
+            return Ok(0);
+        }
+        let parent = &self.path[0..self.path.len() - 1];
+        Ok(self.center.inodes.borrow_mut().get_ino(parent))
+    }
+
+    fn get_ino(&self) -> u32 {
+        self.ino
+    }
+
+    fn open_sub_dir(&self, name: String) -> Result<Self, Error> {
+        let path = join(&self.path, name);
+        if !self.center.sd.is_dir(&as_str_path(&path)) {
+            return make_error(Error::NotFound);
+        }
+        Ok(Dir::open(self.center.clone(), path))
+    }
+
+    fn open_sub_file(&self, name: String) -> Result<Self::FileType, Error> {
+        let path = join(&self.path, name);
+        if !self.center.sd.is_file(&as_str_path(&path)) {
+            return make_error(Error::NotFound);
+        }
+        File::open(self.center.clone(), path)
+    }
+
+    fn list_sub_dir(&self) -> Result<Vec<(String, u32)>, Error> {
+        self.list(true)
+    }
+
+    fn list_sub_file(&self) -> Result<Vec<(String, u32)>, Error> {
+        self.list(false)
+    }
+
+    fn new_sub_dir(&self, name: String) -> Result<Self, Error> {
+        let path = join(&self.path, name);
+        if self.center.exists(&path) {
+            return make_error(Error::AlreadyExist);
+        }
+        self.center.sd.create_dir(&as_str_path(&path))?;
+        Ok(Dir::open(self.center.clone(), path))
+    }
+
+    fn new_sub_file(&self, name: String, len: usize) -> Result<Self::FileType, Error> {
+        let path = join(&self.path, name);
+        if self.center.exists(&path) {
+            return make_error(Error::AlreadyExist);
+        }
+        self.center.sd.create(&as_str_path(&path), len)?;
+        File::open(self.center.clone(), path)
+    }
+
+    fn delete(self) -> Result<(), Error> {
+        if self.path.is_empty() {
+            return make_error(Error::DeletingRoot);
+        }
+        self.center.sd.remove_empty_dir(&as_str_path(&self.path))?;
+        self.center.inodes.borrow_mut().remove(&self.path);
+        Ok(())
+    }
+}
+
+impl FileSystem for SdTree {
+    type FileType = File;
+    type DirType = Dir;
+
+    /// Names are host file names on SD.
+    type NameType = String;
+
+    fn open_file(&self, ino: u32) -> Result<Self::FileType, Error> {
+        let path = self
+            .center
+            .inodes
+            .borrow()
+            .get_path(ino)
+            .ok_or(Error::NotFound)?;
+        if !self.center.sd.is_file(&as_str_path(&path)) {
+            return make_error(Error::NotFound);
+        }
+        File::open(self.center.clone(), path)
+    }
+
+    fn open_dir(&self, ino: u32) -> Result<Self::DirType, Error> {
+        let path = self
+            .center
+            .inodes
+            .borrow()
+            .get_path(ino)
+            .ok_or(Error::NotFound)?;
+        if !self.center.sd.is_dir(&as_str_path(&path)) {
+            return make_error(Error::NotFound);
+        }
+        Ok(Dir::open(self.center.clone(), path))
+    }
+
+    /// Files are written through to SD directly, so there is nothing to flush here.
+    fn commit(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// SD space is managed by the host, so the totals only count what is in use,
+    /// and nothing is reported as free.
+    fn stat(&self) -> Result<Stat, Error> {
+        let mut stat = Stat {
+            block_len: 0x1000,
+            total_blocks: 0,
+            free_blocks: 0,
+            total_files: 0,
+            free_files: 0,
+            total_dirs: 0,
+            free_dirs: 0,
+            dir_hash: None,
+            file_hash: None,
+        };
+        self.center.count(&[], &mut stat)?;
+        Ok(stat)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory_file_system::MemoryFileSystem;
+    use crate::sd::*;
+    use crate::sd_tree::*;
+
+    fn sd_tree() -> (Rc<dyn SdNandFileSystem>, SdTree) {
+        let root: Rc<dyn SdNandFileSystem> = Rc::new(MemoryFileSystem::new());
+        let id0 = hash_movable([0x55; 16]);
+        root.create_dir(&["Nintendo 3DS", &id0, "id1"]).unwrap();
+        let sd = Sd::new(root.clone(), None, [0x33; 16], [0x55; 16]).unwrap();
+        (root, SdTree::new(Rc::new(sd), true))
+    }
+
+    fn read_all(file: &File) -> Vec<u8> {
+        let mut buf = vec![0; file.len()];
+        file.read(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn file_ops() {
+        let (root, tree) = sd_tree();
+        let tree_root = tree.open_dir(1).unwrap();
+        let a = tree_root.new_sub_dir("a".to_owned()).unwrap();
+        let b = tree_root.new_sub_dir("b".to_owned()).unwrap();
+        assert!(matches!(
+            tree_root.new_sub_dir("a".to_owned()),
+            Err(Error::AlreadyExist)
+        ));
+
+        let file = a.new_sub_file("f".to_owned(), 0x1800).unwrap();
+        file.write(0, &[1; 0x1800]).unwrap();
+        file.commit().unwrap();
+        let ino = file.get_ino();
+        assert_eq!(file.get_parent_ino().unwrap(), a.get_ino());
+        drop(file);
+        let file = a.open_sub_file("f".to_owned()).unwrap();
+        assert_eq!(read_all(&file), vec![1; 0x1800]);
+
+        // The content is encrypted for its path on SD
+        let id0 = hash_movable([0x55; 16]);
+        let raw = root
+            .open(&["Nintendo 3DS", &id0, "id1", "a", "f"], false)
+            .unwrap();
+        let mut buf = vec![0; raw.len()];
+        raw.read(0, &mut buf).unwrap();
+        assert_ne!(buf, vec![1; 0x1800]);
+
+        let stat = tree.stat().unwrap();
+        assert_eq!(
+            (stat.total_blocks, stat.total_files, stat.total_dirs),
+            (2, 1, 2)
+        );
+
+        let mut file = file;
+        file.rename(&b, "g".to_owned()).unwrap();
+        assert_eq!(file.get_ino(), ino);
+        assert_eq!(read_all(&file), vec![1; 0x1800]);
+        assert!(matches!(
+            a.open_sub_file("f".to_owned()),
+            Err(Error::NotFound)
+        ));
+        let file = tree.open_file(ino).unwrap();
+        assert_eq!(file.get_parent_ino().unwrap(), b.get_ino());
+        assert_eq!(read_all(&file), vec![1; 0x1800]);
+        assert_eq!(b.list_sub_file().unwrap(), vec![("g".to_owned(), ino)]);
+        assert!(a.list_sub_file().unwrap().is_empty());
+
+        let mut b = b;
+        b.rename(&a, "c".to_owned()).unwrap();
+        assert_eq!(b.get_parent_ino().unwrap(), a.get_ino());
+        let file = tree.open_file(ino).unwrap();
+        assert_eq!(read_all(&file), vec![1; 0x1800]);
+        assert!(tree.open_dir(a.get_ino()).unwrap().delete().is_err());
+
+        file.delete().unwrap();
+        assert!(matches!(tree.open_file(ino), Err(Error::NotFound)));
+        b.delete().unwrap();
+        a.delete().unwrap();
+        assert!(tree_root.list_sub_dir().unwrap().is_empty());
+        assert!(matches!(tree_root.delete(), Err(Error::DeletingRoot)));
+        let stat = tree.stat().unwrap();
+        assert_eq!(
+            (stat.total_blocks, stat.total_files, stat.total_dirs),
+            (0, 0, 0)
+        );
+    }
+}
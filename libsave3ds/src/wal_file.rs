@@ -0,0 +1,278 @@
+use crate::error::*;
+use crate::random_access_file::*;
+use sha2::*;
+use std::cell::RefCell;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+const PAGE_LEN: usize = 0x1000;
+const WAL_MAGIC: [u8; 8] = *b"S3DSWAL0";
+
+fn wal_path(path: &Path) -> PathBuf {
+    let mut wal_path = path.as_os_str().to_owned();
+    wal_path.push(".wal");
+    PathBuf::from(wal_path)
+}
+
+fn read_u64(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(data.get(*pos..*pos + 8)?);
+    *pos += 8;
+    Some(u64::from_le_bytes(buf))
+}
+
+/// Changes recorded in a write-ahead log, as (position, data).
+type WalEntries<'a> = Vec<(usize, &'a [u8])>;
+
+/// Parses a write-ahead log into the file length and the changes.
+/// Returns `None` if the log is incomplete or corrupted.
+fn parse_wal(data: &[u8]) -> Option<(usize, WalEntries<'_>)> {
+    if data.len() < WAL_MAGIC.len() + 32 || data[0..8] != WAL_MAGIC {
+        return None;
+    }
+    let (body, hash) = data.split_at(data.len() - 32);
+    let mut hasher = Sha256::new();
+    hasher.update(body);
+    if hasher.finalize()[..] != *hash {
+        return None;
+    }
+
+    let mut pos = WAL_MAGIC.len();
+    let len = read_u64(body, &mut pos)? as usize;
+    let count = read_u64(body, &mut pos)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let offset = read_u64(body, &mut pos)? as usize;
+        let entry_len = read_u64(body, &mut pos)? as usize;
+        entries.push((offset, body.get(pos..pos + entry_len)?));
+        pos += entry_len;
+    }
+    Some((len, entries))
+}
+
+/// Implements `RandomAccessFile` for a host file with crash-safe commit.
+///
+/// Writes are kept in memory until `commit`, which first writes them to a log file next to the
+/// file (with the extra extension `.wal`), then to the file itself, and finally removes the log.
+/// If the program stops halfway, the log is replayed the next time the file is opened for writing,
+/// so the file always ends up with either all or none of the changes of a commit.
+/// Changes not committed are dropped with this object.
+pub struct WalFile {
+    file: RefCell<File>,
+    wal_path: PathBuf,
+    pages: RefCell<BTreeMap<usize, Vec<u8>>>,
+    len: usize,
+}
+
+impl WalFile {
+    /// Opens the file at `path`. If opened for writing, the log left by an unfinished commit
+    /// is replayed. Otherwise the file is left untouched, and `Error::Busy` is returned
+    /// if there is a complete log waiting to be replayed.
+    pub fn open(path: &Path, write: bool) -> Result<WalFile, Error> {
+        if write {
+            WalFile::recover(path)?;
+        } else if WalFile::pending(path)? {
+            return make_error(Error::Busy);
+        }
+        let file = OpenOptions::new().read(true).write(write).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        Ok(WalFile {
+            file: RefCell::new(file),
+            wal_path: wal_path(path),
+            pages: RefCell::new(BTreeMap::new()),
+            len,
+        })
+    }
+
+    /// Returns whether the file at `path` has a complete log left by an unfinished commit.
+    pub fn pending(path: &Path) -> Result<bool, Error> {
+        let wal_path = wal_path(path);
+        if !wal_path.exists() {
+            return Ok(false);
+        }
+        Ok(parse_wal(&std::fs::read(&wal_path)?).is_some())
+    }
+
+    /// Replays or discards the log left by an unfinished commit to the file at `path`.
+    /// Returns whether a log was replayed.
+    pub fn recover(path: &Path) -> Result<bool, Error> {
+        let wal_path = wal_path(path);
+        if !wal_path.exists() {
+            return Ok(false);
+        }
+        let wal = std::fs::read(&wal_path)?;
+        let replayed = if let Some((len, entries)) = parse_wal(&wal) {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            for (pos, data) in entries {
+                file.seek(SeekFrom::Start(pos as u64))?;
+                file.write_all(data)?;
+            }
+            file.set_len(len as u64)?;
+            file.sync_all()?;
+            true
+        } else {
+            // The log itself was not completely written, so the file has not been touched.
+            false
+        };
+        std::fs::remove_file(&wal_path)?;
+        Ok(replayed)
+    }
+
+    fn page<'a>(
+        &self,
+        pages: &'a mut BTreeMap<usize, Vec<u8>>,
+        index: usize,
+    ) -> Result<&'a mut Vec<u8>, Error> {
+        match pages.entry(index) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let begin = index * PAGE_LEN;
+                let end = std::cmp::min(begin + PAGE_LEN, self.len);
+                let mut data = vec![0; end - begin];
+                let mut file = self.file.borrow_mut();
+                file.seek(SeekFrom::Start(begin as u64))?;
+                file.read_exact(&mut data)?;
+                Ok(entry.insert(data))
+            }
+        }
+    }
+
+    /// Writes all pending changes to the log.
+    fn write_wal(&self) -> Result<(), Error> {
+        let pages = self.pages.borrow();
+        let mut wal = vec![];
+        wal.extend_from_slice(&WAL_MAGIC);
+        wal.extend_from_slice(&(self.len as u64).to_le_bytes());
+        wal.extend_from_slice(&(pages.len() as u64).to_le_bytes());
+        for (index, data) in pages.iter() {
+            wal.extend_from_slice(&((index * PAGE_LEN) as u64).to_le_bytes());
+            wal.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            wal.extend_from_slice(data);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&wal);
+        wal.extend_from_slice(&hasher.finalize());
+
+        let mut wal_file = File::create(&self.wal_path)?;
+        wal_file.write_all(&wal)?;
+        wal_file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes all pending changes to the file and removes the log.
+    fn apply_wal(&self) -> Result<(), Error> {
+        let mut file = self.file.borrow_mut();
+        for (index, data) in self.pages.borrow().iter() {
+            file.seek(SeekFrom::Start((index * PAGE_LEN) as u64))?;
+            file.write_all(data)?;
+        }
+        file.sync_all()?;
+        std::fs::remove_file(&self.wal_path)?;
+        self.pages.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for WalFile {
+    fn read(&self, pos: usize, buf: &mut [u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let pages = self.pages.borrow();
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = pos + done;
+            let index = cur / PAGE_LEN;
+            let offset = cur % PAGE_LEN;
+            let chunk_len = std::cmp::min(PAGE_LEN - offset, buf.len() - done);
+            let chunk = &mut buf[done..done + chunk_len];
+            if let Some(page) = pages.get(&index) {
+                chunk.copy_from_slice(&page[offset..offset + chunk_len]);
+            } else {
+                let mut file = self.file.borrow_mut();
+                file.seek(SeekFrom::Start(cur as u64))?;
+                file.read_exact(chunk)?;
+            }
+            done += chunk_len;
+        }
+        Ok(())
+    }
+    fn write(&self, pos: usize, buf: &[u8]) -> Result<(), Error> {
+        if pos + buf.len() > self.len() {
+            return make_error(Error::OutOfBound);
+        }
+        let mut pages = self.pages.borrow_mut();
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = pos + done;
+            let offset = cur % PAGE_LEN;
+            let chunk_len = std::cmp::min(PAGE_LEN - offset, buf.len() - done);
+            let page = self.page(&mut pages, cur / PAGE_LEN)?;
+            page[offset..offset + chunk_len].copy_from_slice(&buf[done..done + chunk_len]);
+            done += chunk_len;
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn commit(&self) -> Result<(), Error> {
+        if self.pages.borrow().is_empty() {
+            return Ok(());
+        }
+        self.write_wal()?;
+        self.apply_wal()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wal_file::*;
+
+    #[test]
+    fn crash_recovery() {
+        let path = std::env::temp_dir().join(format!("save3ds_wal_test_{}", std::process::id()));
+        std::fs::write(&path, vec![1; 0x2100]).unwrap();
+
+        let file = WalFile::open(&path, true).unwrap();
+        file.write(0xFFE, &[2; 4]).unwrap();
+        let mut buf = [0; 6];
+        file.read(0xFFD, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 2, 2, 2, 1]);
+        assert_eq!(std::fs::read(&path).unwrap()[0xFFE], 1);
+        file.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[0xFFE..0x1002], [2; 4]);
+        assert!(!wal_path(&path).exists());
+
+        // crash after the log is written
+        file.write(0x20FF, &[3]).unwrap();
+        file.write_wal().unwrap();
+        drop(file);
+        assert_eq!(std::fs::read(&path).unwrap()[0x20FF], 1);
+        assert!(WalFile::pending(&path).unwrap());
+        assert!(matches!(WalFile::open(&path, false), Err(Error::Busy)));
+        assert_eq!(std::fs::read(&path).unwrap()[0x20FF], 1);
+        let file = WalFile::open(&path, true).unwrap();
+        let mut buf = [0; 1];
+        file.read(0x20FF, &mut buf).unwrap();
+        assert_eq!(buf, [3]);
+        assert!(!wal_path(&path).exists());
+        drop(file);
+
+        // crash while the log is written
+        let file = WalFile::open(&path, true).unwrap();
+        file.write(0, &[4]).unwrap();
+        file.write_wal().unwrap();
+        drop(file);
+        let wal = std::fs::read(wal_path(&path)).unwrap();
+        std::fs::write(wal_path(&path), &wal[0..wal.len() - 1]).unwrap();
+        assert!(!WalFile::recover(&path).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap()[0], 1);
+        assert!(!wal_path(&path).exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -157,6 +157,218 @@ impl<F0: RandomAccessFile, F1: RandomAccessFile> RandomAccessFile for MirroredFi
     }
 }
 
+/// Physical sizes of the cartridge flash chips that can be formatted with wear leveling.
+pub const WEAR_LEVELING_LEN: [usize; 4] = [0x20_000, 0x40_000, 0x80_000, 0x100_000];
+
+/// Describes where the block map and the journal are placed for a chip size.
+///
+/// Chips up to 512 KiB use 10-byte block map entries that carry CRC tickets,
+/// while the 1 MiB chip uses 2-byte entries and a fixed-size block map.
+struct Layout {
+    large_save: bool,
+    physical_block_count: usize,
+    block_map_len: usize,
+}
+
+impl Layout {
+    fn new(len: usize) -> Result<Layout, Error> {
+        if !WEAR_LEVELING_LEN.contains(&len) {
+            return Err(Error::SizeMismatch);
+        }
+        let large_save = len == 0x100_000;
+        let physical_block_count = len / 0x1000;
+        let block_map_len = if large_save {
+            0x3FE
+        } else {
+            8 + (physical_block_count - 1) * 10
+        };
+        Ok(Layout {
+            large_save,
+            physical_block_count,
+            block_map_len,
+        })
+    }
+
+    fn virtual_block_count(&self) -> usize {
+        self.physical_block_count - 1
+    }
+
+    fn item_len(&self) -> usize {
+        if self.large_save {
+            2
+        } else {
+            10
+        }
+    }
+}
+
+/// An item in the block map, describing where a virtual block is stored.
+#[derive(Clone, Debug)]
+pub struct BlockMapEntry {
+    pub physical_block: u8,
+    pub allocate_count: u8,
+    pub initialized: bool,
+    /// XOR-folded CRC16 of each 0x200-byte chunk in the block. Not present on 1 MiB chips.
+    pub crc_ticket: Option<[u8; 8]>,
+}
+
+fn read_block_map(
+    block_map: &dyn RandomAccessFile,
+    layout: &Layout,
+) -> Result<Vec<BlockMapEntry>, Error> {
+    let item_len = layout.item_len();
+    let mut blocks = vec![];
+    for i in 0..layout.virtual_block_count() {
+        let mut buf = [0; 10];
+        block_map.read(8 + i * item_len, &mut buf[0..item_len])?;
+        let initialized = buf[0] & 0x80 != 0;
+        blocks.push(if layout.large_save {
+            BlockMapEntry {
+                physical_block: buf[1],
+                allocate_count: buf[0] & 0x7F,
+                initialized,
+                crc_ticket: None,
+            }
+        } else {
+            let mut crc_ticket = [0; 8];
+            crc_ticket.copy_from_slice(&buf[2..10]);
+            BlockMapEntry {
+                physical_block: buf[0] & 0x7F,
+                allocate_count: buf[1],
+                initialized,
+                crc_ticket: Some(crc_ticket),
+            }
+        });
+    }
+    Ok(blocks)
+}
+
+/// A record in the journal. Each record moves a virtual block to the physical block
+/// previously used by another uninitialized virtual block.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    pub virtual_block: u8,
+    pub virtual_block_prev: u8,
+    pub physical_block: u8,
+    pub physical_block_prev: u8,
+    pub allocate_count: u8,
+    pub allocate_count_prev: u8,
+    pub crc_ticket: [u8; 8],
+    /// Whether the two mirrored copies of the record are identical.
+    pub mirrored: bool,
+}
+
+impl JournalEntry {
+    fn from_bytes(buf: &[u8; 14], mirrored: bool) -> JournalEntry {
+        let mut crc_ticket = [0; 8];
+        crc_ticket.copy_from_slice(&buf[6..14]);
+        JournalEntry {
+            virtual_block: buf[0],
+            virtual_block_prev: buf[1],
+            physical_block: buf[2],
+            physical_block_prev: buf[3],
+            allocate_count: buf[4],
+            allocate_count_prev: buf[5],
+            crc_ticket,
+            mirrored,
+        }
+    }
+
+    fn apply(&self, blocks: &mut [BlockMapEntry], layout: &Layout) -> Result<(), Error> {
+        let virtual_block = self.virtual_block as usize;
+        let virtual_block_prev = self.virtual_block_prev as usize;
+        let physical_block_count = layout.physical_block_count;
+
+        if virtual_block >= blocks.len() {
+            return Err(Error::InvalidValue);
+        }
+        if virtual_block_prev >= blocks.len() {
+            return Err(Error::InvalidValue);
+        }
+        if self.physical_block as usize >= physical_block_count || self.physical_block == 0 {
+            return Err(Error::InvalidValue);
+        }
+        if self.physical_block_prev as usize >= physical_block_count
+            || self.physical_block_prev == 0
+        {
+            return Err(Error::InvalidValue);
+        }
+
+        if blocks[virtual_block].physical_block != self.physical_block_prev {
+            return Err(Error::InvalidValue);
+        }
+
+        if blocks[virtual_block_prev].physical_block != self.physical_block {
+            return Err(Error::InvalidValue);
+        }
+
+        if blocks[virtual_block_prev].initialized {
+            return Err(Error::InvalidValue);
+        }
+
+        if blocks[virtual_block].allocate_count != self.allocate_count_prev {
+            return Err(Error::InvalidValue);
+        }
+
+        // Wrapping???
+        if blocks[virtual_block_prev].allocate_count != self.allocate_count.wrapping_sub(1) {
+            return Err(Error::InvalidValue);
+        }
+
+        let crc_ticket = |crc_ticket| {
+            if layout.large_save {
+                None
+            } else {
+                Some(crc_ticket)
+            }
+        };
+
+        blocks[virtual_block_prev] = BlockMapEntry {
+            physical_block: self.physical_block_prev,
+            allocate_count: self.allocate_count_prev,
+            initialized: false,
+            crc_ticket: crc_ticket([0; 8]),
+        };
+        blocks[virtual_block] = BlockMapEntry {
+            physical_block: self.physical_block,
+            allocate_count: self.allocate_count,
+            initialized: true,
+            crc_ticket: crc_ticket(self.crc_ticket),
+        };
+        Ok(())
+    }
+}
+
+/// CRC status of a 0x200-byte chunk in a virtual block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCrcStatus {
+    Valid,
+    Mismatch,
+    /// The block is not initialized, so there is nothing to check.
+    Uninitialized,
+    /// The chip layout doesn't store CRC for chunks, or the block map entry is invalid.
+    NotChecked,
+}
+
+/// Describes the wear leveling structures of a cartridge save chip.
+#[derive(Clone, Debug)]
+pub struct WearLevelingInfo {
+    pub chip_len: usize,
+    pub block_map_crc_valid: bool,
+    /// Whether every physical block is used by exactly one virtual block in the stored block map.
+    pub block_map_valid: bool,
+    /// The block map as stored, before applying the journal.
+    pub block_map: Vec<BlockMapEntry>,
+    pub journal: Vec<JournalEntry>,
+    pub free_journal_slots: usize,
+    /// Whether all journal records can be applied to the block map.
+    pub journal_valid: bool,
+    /// The block map after applying the journal, or the valid part of it.
+    pub blocks: Vec<BlockMapEntry>,
+    /// CRC status of each chunk in each virtual block, according to `blocks`.
+    pub chunk_crc: Vec<[ChunkCrcStatus; 8]>,
+}
+
 struct WearLevelingBlock {
     physical_block: u8,
     allocate_count: u8,
@@ -175,19 +387,10 @@ pub struct WearLeveling {
 
 impl WearLeveling {
     pub fn format(parent: Rc<dyn RandomAccessFile>) -> Result<(), Error> {
-        let len = parent.len();
-        if len != 0x20_000 && len != 0x80_000 && len != 0x100_000 {
-            return Err(Error::SizeMismatch);
-        }
-        let large_save = len == 0x100_000;
-
-        let virtual_block_count = len / 0x1000 - 1;
-
-        let block_map_len = if large_save {
-            0x3FE
-        } else {
-            8 + virtual_block_count * 10
-        };
+        let layout = Layout::new(parent.len())?;
+        let large_save = layout.large_save;
+        let virtual_block_count = layout.virtual_block_count();
+        let block_map_len = layout.block_map_len;
 
         let block_map = Rc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
         let block_map_crc = Rc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
@@ -199,7 +402,7 @@ impl WearLeveling {
 
         block_map.write(0, &[0; 8])?;
 
-        let item_len = if large_save { 2 } else { 10 };
+        let item_len = layout.item_len();
         for i in 0..virtual_block_count {
             if large_save {
                 block_map.write(8 + i * item_len, &[0])?;
@@ -231,19 +434,10 @@ impl WearLeveling {
     }
 
     pub fn new(parent: Rc<dyn RandomAccessFile>) -> Result<WearLeveling, Error> {
-        let len = parent.len();
-        if len != 0x20_000 && len != 0x80_000 && len != 0x100_000 {
-            return Err(Error::SizeMismatch);
-        }
-        let large_save = len == 0x100_000;
-        let physical_block_count = len / 0x1000;
-        let virtual_block_count = physical_block_count - 1;
-
-        let block_map_len = if large_save {
-            0x3FE
-        } else {
-            8 + virtual_block_count * 10
-        };
+        let layout = Layout::new(parent.len())?;
+        let large_save = layout.large_save;
+        let physical_block_count = layout.physical_block_count;
+        let block_map_len = layout.block_map_len;
 
         let block_map = Rc::new(SubFile::new(parent.clone(), 0, block_map_len)?);
         let block_map_crc = Rc::new(SubFile::new(parent.clone(), block_map_len, 2)?);
@@ -253,40 +447,7 @@ impl WearLeveling {
             true,
         )?);
 
-        struct Block {
-            physical_block: u8,
-            allocate_count: u8,
-            initialized: bool,
-            crc_ticket: Option<MemoryFile>,
-        };
-
-        let mut blocks = vec![];
-        let item_len = if large_save { 2 } else { 10 };
-        for i in 0..virtual_block_count {
-            let offset = i * item_len + 8;
-            let mut buf = [0; 2];
-            block_map.read(offset, &mut buf)?;
-            let initialized = buf[0] & 0x80 != 0;
-            let crc_ticket = if large_save {
-                None
-            } else if initialized {
-                Some(MemoryFile::from_file(&SubFile::new(
-                    block_map.clone(),
-                    offset + 2,
-                    8,
-                )?)?)
-            } else {
-                Some(MemoryFile::new(vec![0; 8]))
-            };
-            let physical_block = if large_save { buf[1] } else { buf[0] & 0x7F };
-            let allocate_count = if large_save { buf[0] & 0x7F } else { buf[1] };
-            blocks.push(Block {
-                physical_block,
-                allocate_count,
-                initialized,
-                crc_ticket,
-            });
-        }
+        let mut blocks = read_block_map(block_map.as_ref(), &layout)?;
 
         let mut physical_block_set: HashSet<_> = (1..physical_block_count).collect();
         for block in blocks.iter() {
@@ -306,66 +467,13 @@ impl WearLeveling {
             let journal0 = Rc::new(SubFile::new(journal_list.clone(), offset, 14)?);
             let journal1 = Rc::new(SubFile::new(journal_list.clone(), offset + 14, 14)?);
             let journal = MirroredFile::new(journal0, journal1)?;
-            let mut buf = [0; 6];
+            let mut buf = [0; 14];
             journal.read(0, &mut buf)?;
-            let virtual_block = buf[0] as usize;
-            let virtual_block_prev = buf[1] as usize;
-            let physical_block = buf[2];
-            let physical_block_prev = buf[3];
-            let allocate_count = buf[4];
-            let allocate_count_prev = buf[5];
-            if virtual_block == 0xFF {
+            let entry = JournalEntry::from_bytes(&buf, true);
+            if entry.virtual_block == 0xFF {
                 break;
             }
-
-            if virtual_block >= virtual_block_count {
-                return Err(Error::InvalidValue);
-            }
-            if virtual_block_prev >= virtual_block_count {
-                return Err(Error::InvalidValue);
-            }
-            if physical_block as usize >= physical_block_count || physical_block == 0 {
-                return Err(Error::InvalidValue);
-            }
-            if physical_block_prev as usize >= physical_block_count || physical_block_prev == 0 {
-                return Err(Error::InvalidValue);
-            }
-
-            if blocks[virtual_block].physical_block != physical_block_prev {
-                return Err(Error::InvalidValue);
-            }
-
-            if blocks[virtual_block_prev].physical_block != physical_block {
-                return Err(Error::InvalidValue);
-            }
-
-            if blocks[virtual_block_prev].initialized {
-                return Err(Error::InvalidValue);
-            }
-
-            if blocks[virtual_block].allocate_count != allocate_count_prev {
-                return Err(Error::InvalidValue);
-            }
-
-            // Wrapping???
-            if blocks[virtual_block_prev].allocate_count != allocate_count - 1 {
-                return Err(Error::InvalidValue);
-            }
-
-            blocks[virtual_block_prev].allocate_count = allocate_count_prev;
-            blocks[virtual_block_prev].physical_block = physical_block_prev;
-            blocks[virtual_block_prev].initialized = false;
-            if !large_save {
-                blocks[virtual_block_prev].crc_ticket = Some(MemoryFile::new(vec![0; 8]));
-            }
-            blocks[virtual_block].allocate_count = allocate_count;
-            blocks[virtual_block].physical_block = physical_block;
-            blocks[virtual_block].initialized = true;
-            if !large_save {
-                blocks[virtual_block].crc_ticket = Some(MemoryFile::from_file(
-                    &(SubFile::new(Rc::new(journal), 6, 8)?),
-                )?);
-            }
+            entry.apply(&mut blocks, &layout)?;
         }
 
         if blocks.last().unwrap().initialized {
@@ -375,7 +483,14 @@ impl WearLeveling {
         let mut final_blocks = vec![];
         for block in blocks {
             let mut data_list: Vec<Box<dyn RandomAccessFile>> = vec![];
-            let crc_ticket = block.crc_ticket.map(Rc::new);
+            let crc_ticket = block.crc_ticket.map(|crc_ticket| {
+                let crc_ticket = if block.initialized {
+                    crc_ticket.to_vec()
+                } else {
+                    vec![0; 8]
+                };
+                Rc::new(MemoryFile::new(crc_ticket))
+            });
             for i in 0..8 {
                 let offset = i * 0x200 + block.physical_block as usize * 0x1000;
                 let data = SubFile::new(parent.clone(), offset, 0x200)?;
@@ -410,6 +525,92 @@ impl WearLeveling {
     }
 }
 
+impl WearLeveling {
+    /// Reads the wear leveling structures without modifying the chip.
+    ///
+    /// Unlike `new`, CRC mismatch and inconsistent journal don't cause an error.
+    /// They are reported in the returned information instead.
+    pub fn inspect(parent: Rc<dyn RandomAccessFile>) -> Result<WearLevelingInfo, Error> {
+        let layout = Layout::new(parent.len())?;
+        let physical_block_count = layout.physical_block_count;
+
+        let block_map_len = layout.block_map_len;
+
+        let block_map = MemoryFile::from_file(&SubFile::new(parent.clone(), 0, block_map_len)?)?;
+        let block_map_crc =
+            SimpleCrcStub::new(Rc::new(SubFile::new(parent.clone(), block_map_len, 2)?))?;
+        let mut buf = vec![0; block_map_len];
+        block_map.read(0, &mut buf)?;
+        let block_map_crc_valid = block_map_crc.verify(crc16_ninty(&buf))?;
+        let block_map = read_block_map(&block_map, &layout)?;
+
+        let mut physical_block_set: HashSet<_> = (1..physical_block_count).collect();
+        let block_map_valid = block_map
+            .iter()
+            .all(|block| physical_block_set.remove(&(block.physical_block as usize)));
+
+        let journal_start = block_map_len + 2;
+        let journal_slots = (0x1000 - journal_start) / 0x20;
+        let mut journal = vec![];
+        for slot in 0..journal_slots {
+            let mut buf0 = [0; 14];
+            let mut buf1 = [0; 14];
+            parent.read(journal_start + slot * 0x20, &mut buf0)?;
+            parent.read(journal_start + slot * 0x20 + 14, &mut buf1)?;
+            let entry = JournalEntry::from_bytes(&buf0, buf0 == buf1);
+            if entry.virtual_block == 0xFF {
+                break;
+            }
+            journal.push(entry);
+        }
+        let free_journal_slots = journal_slots - journal.len();
+
+        let mut blocks = block_map.clone();
+        let mut journal_valid = true;
+        for entry in journal.iter() {
+            if entry.apply(&mut blocks, &layout).is_err() {
+                journal_valid = false;
+                break;
+            }
+        }
+
+        let mut chunk_crc = vec![];
+        for block in blocks.iter() {
+            let physical_block = block.physical_block as usize;
+            let mut status = [ChunkCrcStatus::NotChecked; 8];
+            if !block.initialized {
+                status = [ChunkCrcStatus::Uninitialized; 8];
+            } else if physical_block != 0 && physical_block < physical_block_count {
+                if let Some(crc_ticket) = block.crc_ticket {
+                    for (i, status) in status.iter_mut().enumerate() {
+                        let mut chunk = [0; 0x200];
+                        parent.read(physical_block * 0x1000 + i * 0x200, &mut chunk)?;
+                        let crc = crc16_ninty(&chunk).to_le_bytes();
+                        *status = if crc[0] ^ crc[1] == crc_ticket[i] {
+                            ChunkCrcStatus::Valid
+                        } else {
+                            ChunkCrcStatus::Mismatch
+                        };
+                    }
+                }
+            }
+            chunk_crc.push(status);
+        }
+
+        Ok(WearLevelingInfo {
+            chip_len: parent.len(),
+            block_map_crc_valid,
+            block_map_valid,
+            block_map,
+            journal,
+            free_journal_slots,
+            journal_valid,
+            blocks,
+            chunk_crc,
+        })
+    }
+}
+
 const CHUNK_INIT: [u8; 0x200] = [0xFF; 0x200];
 
 impl RandomAccessFile for WearLeveling {
@@ -618,7 +819,7 @@ pub mod test {
     fn fuzz_wear_leveling_small() {
         let mut rng = rand::thread_rng();
         for i in 0..10 {
-            let len = if rng.gen() { 0x20_000 } else { 0x80_000 };
+            let len = [0x20_000, 0x40_000, 0x80_000][rng.gen_range(0, 3)];
             let virtual_block_count = len / 0x1000 - 1;
             let init = Rc::new(MemoryFile::new(vec![0xFF; len]));
             let plain = MemoryFile::new(vec![0xFF; len - 0x2000]);
@@ -704,4 +905,63 @@ pub mod test {
             );
         }
     }
+
+    #[test]
+    fn journal_inspect_compact() {
+        let rng = rand::thread_rng();
+        let len = 0x20_000;
+        let virtual_block_count = len / 0x1000 - 1;
+        let last = virtual_block_count as u8 - 1;
+        let init = Rc::new(MemoryFile::new(vec![0xFF; len]));
+        WearLeveling::format(init.clone()).unwrap();
+
+        // Moves virtual block 0 to the physical block of the last virtual block
+        let data: Vec<u8> = rng.sample_iter(&Standard).take(0x1000).collect();
+        let physical_block = last + 1;
+        init.write(physical_block as usize * 0x1000, &data).unwrap();
+        let mut crc_ticket = [0; 8];
+        for (i, crc_ticket) in crc_ticket.iter_mut().enumerate() {
+            let crc = crc16_ninty(&data[i * 0x200..(i + 1) * 0x200]).to_le_bytes();
+            *crc_ticket = crc[0] ^ crc[1];
+        }
+        let mut journal = vec![0, last, physical_block, 1, 1, 0];
+        journal.extend_from_slice(&crc_ticket);
+        let journal_start = 8 + virtual_block_count * 10 + 2;
+        init.write(journal_start, &journal).unwrap();
+        init.write(journal_start + 14, &journal).unwrap();
+
+        let info = WearLeveling::inspect(init.clone()).unwrap();
+        assert!(info.block_map_crc_valid);
+        assert!(info.block_map_valid);
+        assert!(info.journal_valid);
+        assert_eq!(info.journal.len(), 1);
+        assert!(info.journal[0].mirrored);
+        assert_eq!(info.free_journal_slots, (0x1000 - journal_start) / 0x20 - 1);
+        assert_eq!(info.block_map[0].physical_block, 1);
+        assert_eq!(info.blocks[0].physical_block, physical_block);
+        assert_eq!(info.chunk_crc[0], [ChunkCrcStatus::Valid; 8]);
+        assert_eq!(info.chunk_crc[1], [ChunkCrcStatus::Uninitialized; 8]);
+
+        let mut buf = vec![0; 0x1000];
+        WearLeveling::new(init.clone())
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        WearLeveling::new(init.clone()).unwrap().commit().unwrap();
+        let info = WearLeveling::inspect(init.clone()).unwrap();
+        assert!(info.journal.is_empty());
+        assert_eq!(info.block_map[0].physical_block, physical_block);
+        WearLeveling::new(init.clone())
+            .unwrap()
+            .read(0, &mut buf)
+            .unwrap();
+        assert_eq!(buf, data);
+
+        init.write(physical_block as usize * 0x1000 + 0x300, &[!data[0x300]])
+            .unwrap();
+        let info = WearLeveling::inspect(init).unwrap();
+        assert_eq!(info.chunk_crc[0][1], ChunkCrcStatus::Mismatch);
+    }
 }
@@ -1,13 +1,20 @@
+mod manifest;
+
 use getopts::Options;
+use libsave3ds::cart_save_data::CartSaveData;
 use libsave3ds::db::*;
 use libsave3ds::error::*;
 use libsave3ds::ext_data::*;
 use libsave3ds::file_system::{self, *};
+use libsave3ds::key_store::KeyStore;
+use libsave3ds::path_helper::{self, NameConvert};
+use libsave3ds::random_access_file::copy_chunks;
 use libsave3ds::save_data::*;
-use libsave3ds::Resource;
+use libsave3ds::{FileSource, Resource, ResourceBuilder};
+use manifest::*;
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::Read;
+use std::io::{Read, Write};
 
 #[cfg(all(unix, feature = "unixfuse"))]
 use {
@@ -21,95 +28,18 @@ use {
 
 enum FileSystemOperation {
     Mount(bool),
-    Extract,
-    Import,
+    Extract(Option<std::path::PathBuf>, usize),
+    Import(Option<std::path::PathBuf>, usize),
     Touch,
 }
 
-fn is_legal_char(c: u8) -> bool {
-    c >= 32 && c < 127 && c != 47 && c != 92
-}
-
-trait NameConvert {
-    fn name_3ds_to_str(name: &Self) -> String;
-    fn name_str_to_3ds(name: &str) -> Option<Self>
-    where
-        Self: Sized;
-}
-
-impl NameConvert for u64 {
-    fn name_3ds_to_str(name: &u64) -> String {
-        format!("{:016x}", name)
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<u64> {
-        u64::from_str_radix(name, 16).ok()
-    }
-}
-
-impl NameConvert for [u8; 16] {
-    fn name_3ds_to_str(name: &[u8; 16]) -> String {
-        let mut last_char = 15;
-        loop {
-            if name[last_char] != 0 || last_char == 0 {
-                break;
-            }
-            last_char -= 1;
-        }
-
-        name[0..=last_char]
-            .iter()
-            .map(|x| {
-                if is_legal_char(*x) {
-                    String::from_utf8(vec![*x]).unwrap()
-                } else {
-                    format!("\\x{:02x}", *x)
-                }
-            })
-            .fold("".to_owned(), |mut x, y| {
-                x.push_str(&y);
-                x
-            })
-    }
-
-    fn name_str_to_3ds(name: &str) -> Option<[u8; 16]> {
-        let mut name_converted = [0; 16];
-        let bytes = name.as_bytes();
-        let mut out_i = 0;
-        let mut in_i = 0;
-        loop {
-            if in_i == bytes.len() {
-                break;
-            }
-            if out_i == name_converted.len() {
-                return None;
-            }
-
-            if bytes[in_i] != b'\\' {
-                name_converted[out_i] = bytes[in_i];
-                out_i += 1;
-                in_i += 1;
-            } else {
-                in_i += 1;
-                if *bytes.get(in_i)? != b'x' {
-                    return None;
-                }
-                in_i += 1;
-                name_converted[out_i] =
-                    u8::from_str_radix(std::str::from_utf8(bytes.get(in_i..in_i + 2)?).ok()?, 16)
-                        .ok()?;
-                out_i += 1;
-                in_i += 2;
-            }
-        }
-        Some(name_converted)
-    }
-}
-
-fn extract_impl<T: file_system::FileSystem>(
+fn extract_impl<T: ManifestArchive>(
     save: &T,
     dir: T::DirType,
     path: &std::path::Path,
+    archive_path: &str,
+    manifest: &mut Manifest,
+    chunk_len: usize,
     indent: u32,
 ) -> Result<(), Error>
 where
@@ -126,7 +56,17 @@ where
         }
         println!("+{}", &name);
         let dir = save.open_dir(ino)?;
-        extract_impl(save, dir, &path.join(name), indent + 1)?;
+        let sub_path = format!("{}/{}", archive_path, name);
+        manifest.add_dir(&sub_path, ino);
+        extract_impl(
+            save,
+            dir,
+            &path.join(name),
+            &sub_path,
+            manifest,
+            chunk_len,
+            indent + 1,
+        )?;
     }
 
     for (name, ino) in dir.list_sub_file()? {
@@ -136,81 +76,91 @@ where
         }
         println!("-{}", &name);
         let file = save.open_file(ino)?;
-        let mut buffer = vec![0; file.len()];
-        match file.read(0, &mut buffer) {
-            Ok(()) | Err(Error::HashMismatch) => (),
-            e => return e,
-        }
-        std::fs::write(&path.join(name), &buffer)?;
+        let sub_path = format!("{}/{}", archive_path, name);
+        manifest.add_file(&sub_path, ino);
+        T::save_file(manifest, &sub_path, &file)?;
+        let mut host_file = std::fs::File::create(&path.join(name))?;
+        copy_chunks(
+            file.len(),
+            chunk_len,
+            |pos, buf| {
+                // A read stops at the first uninitialized block,
+                // so the rest of the chunk must not keep the previous one
+                buf.iter_mut().for_each(|b| *b = 0);
+                match file.read(pos, buf) {
+                    Ok(()) | Err(Error::HashMismatch) => Ok(()),
+                    e => e,
+                }
+            },
+            |_, buf| Ok(host_file.write_all(buf)?),
+        )?;
     }
 
     Ok(())
 }
 
-fn extract<T: file_system::FileSystem>(save: T, mountpoint: &std::path::Path) -> Result<(), Error>
+fn extract<T: ManifestArchive>(
+    save: T,
+    mountpoint: &std::path::Path,
+    manifest_path: Option<&std::path::Path>,
+    chunk_len: usize,
+) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
 {
     println!("Extracting...");
     let root = save.open_root()?;
-    extract_impl(&save, root, mountpoint, 0)?;
+    let mut manifest = Manifest::default();
+    save.save_archive(&mut manifest)?;
+    extract_impl(&save, root, mountpoint, "", &mut manifest, chunk_len, 0)?;
+    if let Some(manifest_path) = manifest_path {
+        manifest.save(manifest_path)?;
+    }
     println!("Finished");
     Ok(())
 }
 
-fn clear_impl<T: file_system::FileSystem>(save: &T, dir: &T::DirType) -> Result<(), Error>
-where
-    T::NameType: NameConvert + Clone,
-{
-    for (_, ino) in dir.list_sub_dir()? {
-        let dir = save.open_dir(ino)?;
-        clear_impl(save, &dir)?;
-        dir.delete()?;
-    }
-
-    for (_, ino) in dir.list_sub_file()? {
-        let file = save.open_file(ino)?;
-        file.delete()?;
-    }
-
-    Ok(())
+/// An entry found in the host directory to import.
+struct HostEntry<N> {
+    name: N,
+    host_path: std::path::PathBuf,
+    path: String,
+    parent: String,
 }
 
-fn import_impl<T: file_system::FileSystem>(
-    save: &T,
-    dir: &T::DirType,
+fn scan_host<N: NameConvert>(
     path: &std::path::Path,
-) -> Result<(), Error>
-where
-    T::NameType: NameConvert + Clone,
-{
+    archive_path: &str,
+    dirs: &mut Vec<HostEntry<N>>,
+    files: &mut Vec<HostEntry<N>>,
+) -> Result<(), Error> {
     for entry in std::fs::read_dir(&path)? {
         let entry = entry?;
-        println!("{:?}", entry.path());
         let name = if let Some(name) = entry
             .path()
             .file_name()
             .and_then(OsStr::to_str)
-            .and_then(T::NameType::name_str_to_3ds)
+            .and_then(N::name_str_to_3ds)
         {
             name
         } else {
             println!("Name not valid: {:?}", entry.path());
             continue;
         };
+        let host_entry = HostEntry {
+            path: format!("{}/{}", archive_path, N::name_3ds_to_str(&name)),
+            parent: archive_path.to_owned(),
+            name,
+            host_path: entry.path(),
+        };
 
         let file_type = entry.file_type()?;
         if file_type.is_dir() {
-            let dir = dir.new_sub_dir(name)?;
-            import_impl(save, &dir, &entry.path())?
+            let sub_path = host_entry.path.clone();
+            dirs.push(host_entry);
+            scan_host(&entry.path(), &sub_path, dirs, files)?;
         } else if file_type.is_file() {
-            let mut host_file = std::fs::File::open(&entry.path())?;
-            let len = host_file.metadata()?.len() as usize;
-            let file = dir.new_sub_file(name, len)?;
-            let mut buffer = vec![0; len];
-            host_file.read_exact(&mut buffer)?;
-            file.write(0, &buffer)?;
-            file.commit()?;
+            files.push(host_entry);
         } else {
             println!("Unrecognized file type: {:?}", entry.path());
         }
@@ -219,15 +169,75 @@ where
     Ok(())
 }
 
-fn import<T: file_system::FileSystem>(save: T, mountpoint: &std::path::Path) -> Result<(), Error>
+fn import_impl<T: ManifestArchive>(
+    save: &T,
+    root: &T::DirType,
+    path: &std::path::Path,
+    manifest: &Manifest,
+    chunk_len: usize,
+) -> Result<(), Error>
+where
+    T::NameType: NameConvert + Clone,
+{
+    let mut dirs = vec![];
+    let mut files = vec![];
+    scan_host::<T::NameType>(path, "", &mut dirs, &mut files)?;
+    let paths = |entries: &[HostEntry<T::NameType>]| -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|entry| (entry.path.clone(), entry.parent.clone()))
+            .collect()
+    };
+
+    let mut dir_inos = HashMap::new();
+    dir_inos.insert(String::new(), root.get_ino());
+    for i in manifest.creation_order(true, &paths(&dirs)) {
+        let entry = &dirs[i];
+        println!("{:?}", entry.host_path);
+        let parent = save.open_dir(dir_inos[&entry.parent])?;
+        let dir = parent.new_sub_dir(entry.name.clone())?;
+        dir_inos.insert(entry.path.clone(), dir.get_ino());
+    }
+
+    for i in manifest.creation_order(false, &paths(&files)) {
+        let entry = &files[i];
+        println!("{:?}", entry.host_path);
+        let parent = save.open_dir(dir_inos[&entry.parent])?;
+        let mut host_file = std::fs::File::open(&entry.host_path)?;
+        let len = host_file.metadata()?.len() as usize;
+        let mut file = parent.new_sub_file(entry.name.clone(), len)?;
+        T::load_file(manifest, &entry.path, &mut file)?;
+        copy_chunks(
+            len,
+            chunk_len,
+            |_, buf| Ok(host_file.read_exact(buf)?),
+            |pos, buf| file.write(pos, buf),
+        )?;
+        file.commit()?;
+    }
+
+    Ok(())
+}
+
+fn import<T: ManifestArchive>(
+    save: T,
+    mountpoint: &std::path::Path,
+    manifest_path: Option<&std::path::Path>,
+    chunk_len: usize,
+) -> Result<(), Error>
 where
     T::NameType: NameConvert + Clone,
 {
+    let manifest = manifest_path
+        .map(Manifest::load)
+        .transpose()?
+        .unwrap_or_default();
     println!("Clearing the original contents...");
+    path_helper::remove_all(&save, "/")?;
     let root = save.open_root()?;
-    clear_impl(&save, &root)?;
     println!("Importing new contents...");
-    import_impl(&save, &root, mountpoint)?;
+    import_impl(&save, &root, mountpoint, &manifest, chunk_len)?;
+    save.load_archive(&manifest)?;
     save.commit()?;
     println!("Finished");
     Ok(())
@@ -251,7 +261,7 @@ where
     Ok(())
 }
 
-fn start<T: file_system::FileSystem>(
+fn start<T: ManifestArchive>(
     save: T,
     operation: FileSystemOperation,
     mountpoint: &std::path::Path,
@@ -261,14 +271,159 @@ where
 {
     match operation {
         FileSystemOperation::Mount(read_only) => do_mount(save, read_only, mountpoint)?,
-        FileSystemOperation::Extract => extract(save, mountpoint)?,
-        FileSystemOperation::Import => import(save, mountpoint)?,
+        FileSystemOperation::Extract(manifest_path, chunk_len) => {
+            extract(save, mountpoint, manifest_path.as_deref(), chunk_len)?
+        }
+        FileSystemOperation::Import(manifest_path, chunk_len) => {
+            import(save, mountpoint, manifest_path.as_deref(), chunk_len)?
+        }
         FileSystemOperation::Touch => println!("Touched"),
     }
 
     Ok(())
 }
 
+/// An archive built on save data, which can be defragmented, rehashed,
+/// and opened or restored at its version before the last commit.
+trait SaveArchive: ManifestArchive + Sized {
+    fn fragmentation(&self) -> Result<Fragmentation, Error>;
+    fn defragment(self) -> Result<(), Error>;
+    fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error>;
+
+    /// Returns the format parameters and the image length, if the archive can be resized.
+    fn layout(&self) -> Option<(SaveDataFormatParam, usize)> {
+        None
+    }
+}
+
+impl SaveArchive for SaveData {
+    fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        SaveData::fragmentation(self)
+    }
+    fn defragment(self) -> Result<(), Error> {
+        SaveData::defragment(self)
+    }
+    fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        SaveData::rehash(self, dir_buckets, file_buckets)
+    }
+    fn layout(&self) -> Option<(SaveDataFormatParam, usize)> {
+        Some((self.format_param(), self.image_len()))
+    }
+}
+
+impl SaveArchive for CartSaveData {
+    fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        CartSaveData::fragmentation(self)
+    }
+    fn defragment(self) -> Result<(), Error> {
+        CartSaveData::defragment(self)
+    }
+    fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        CartSaveData::rehash(self, dir_buckets, file_buckets)
+    }
+}
+
+impl SaveArchive for Db {
+    fn fragmentation(&self) -> Result<Fragmentation, Error> {
+        Db::fragmentation(self)
+    }
+    fn defragment(self) -> Result<(), Error> {
+        Db::defragment(self)
+    }
+    fn rehash(self, dir_buckets: usize, file_buckets: usize) -> Result<(), Error> {
+        Db::rehash(self, dir_buckets, file_buckets)
+    }
+}
+
+/// The operations requested on a save archive besides `FileSystemOperation`.
+/// At most one of `defrag`, `rehash_param`, `resize_param` and `restore` is set.
+struct SaveMaintenance {
+    defrag: bool,
+    rehash_param: Option<HashMap<String, String>>,
+    resize_param: Option<HashMap<String, String>>,
+    restore: bool,
+    previous: bool,
+    read_only: bool,
+}
+
+/// Does the requested maintenance on a save archive, or starts `operation` on it if none is
+/// requested. `open` opens the archive for reading or writing, and the other functions do
+/// the operation of the same name in `Resource`.
+fn start_save<T: SaveArchive>(
+    open: impl Fn(bool) -> Result<T, Error>,
+    open_previous: impl FnOnce() -> Result<T, Error>,
+    restore: impl FnOnce() -> Result<(), Error>,
+    resize: impl FnOnce(&SaveDataFormatParam, usize) -> Result<(), Error>,
+    maintenance: SaveMaintenance,
+    operation: FileSystemOperation,
+    mountpoint: &std::path::Path,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T::NameType: NameConvert + Clone,
+{
+    if maintenance.defrag {
+        let save = open(true)?;
+        print_fragmentation(&save.stat()?, &save.fragmentation()?);
+        save.defragment()?;
+        let save = open(false)?;
+        print_fragmentation(&save.stat()?, &save.fragmentation()?);
+    } else if let Some(rehash_param) = maintenance.rehash_param {
+        let stat = open(false)?.stat()?;
+        print_hash_chains(&stat);
+        let (dir_buckets, file_buckets) = to_bucket_counts(rehash_param, &stat)?;
+        println!("Rehashing...");
+        open(true)?.rehash(dir_buckets, file_buckets)?;
+        print_hash_chains(&open(false)?.stat()?);
+    } else if let Some(resize_param) = maintenance.resize_param {
+        let (format_param, image_len) = open(false)?.layout().ok_or(Error::Unsupported)?;
+        let (param, len) = to_save_data_format_param(resize_param, format_param, image_len)?;
+        println!("Resizing...");
+        resize(&param, len)?;
+        println!("Resizing done");
+    } else if maintenance.restore {
+        restore()?;
+        println!("Restored the previous version");
+    } else if maintenance.previous {
+        start(open_previous()?, operation, mountpoint)?
+    } else {
+        start(open(!maintenance.read_only)?, operation, mountpoint)?
+    }
+    Ok(())
+}
+
+const FILE_CHUNK_LEN: usize = 0x10_0000;
+
+fn sd_decrypt(resource: &Resource, path: &str, host_path: &std::path::Path) -> Result<(), Error> {
+    println!("Decrypting...");
+    let file = resource.open_sd_file(path, false)?;
+    let mut host_file = std::fs::File::create(host_path)?;
+    copy_chunks(
+        file.len(),
+        FILE_CHUNK_LEN,
+        |pos, buf| file.read(pos, buf),
+        |_, buf| Ok(host_file.write_all(buf)?),
+    )?;
+    println!("Finished");
+    Ok(())
+}
+
+fn sd_encrypt(resource: &Resource, path: &str, host_path: &std::path::Path) -> Result<(), Error> {
+    println!("Encrypting...");
+    let mut host_file = std::fs::File::open(host_path)?;
+    let file_len = host_file.metadata()?.len() as usize;
+    resource.create_sd_file(path, file_len)?;
+    let file = resource.open_sd_file(path, true)?;
+    copy_chunks(
+        file_len,
+        FILE_CHUNK_LEN,
+        |_, buf| Ok(host_file.read_exact(buf)?),
+        |pos, buf| file.write(pos, buf),
+    )?;
+    file.commit()?;
+    println!("Finished");
+    Ok(())
+}
+
 #[cfg(all(unix, feature = "unixfuse"))]
 struct DirEntry {
     ino: u64,
@@ -1063,6 +1218,37 @@ fn print_usage(program: &str, opts: Options) {
     print!("{}", opts.usage(&brief));
 }
 
+fn print_fragmentation(stat: &Stat, fragmentation: &Fragmentation) {
+    println!(
+        "{} files in {} runs of blocks, at most {} runs in one file. \
+        {} free blocks in {} runs, the largest one has {} blocks",
+        fragmentation.files,
+        fragmentation.file_runs,
+        fragmentation.max_file_runs,
+        stat.free_blocks,
+        fragmentation.free_runs,
+        fragmentation.largest_free_run
+    );
+}
+
+fn print_hash_chains(stat: &Stat) {
+    for (name, chains) in [("Directory", stat.dir_hash), ("File", stat.file_hash)].iter() {
+        if let Some(chains) = chains {
+            println!(
+                "{} hash table: {} entries in {} of {} buckets, the longest chain has {} entries",
+                name, chains.entries, chains.used_buckets, chains.buckets, chains.longest_chain
+            );
+        }
+    }
+}
+
+fn print_quota(quota: &ExtDataQuota) {
+    println!(
+        "Quota: {} of {} blocks free, {} potentially free, {} bytes per block",
+        quota.free_block, quota.max_block, quota.potential_free_block, quota.block_len
+    );
+}
+
 fn get_default_bucket(n: usize) -> usize {
     if n < 3 {
         3
@@ -1119,67 +1305,112 @@ fn to_ext_data_format_param(
     })
 }
 
+fn to_bucket_counts(
+    raw: HashMap<String, String>,
+    stat: &Stat,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let dir_buckets = raw
+        .get("dir_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| get_default_bucket(stat.total_dirs));
+
+    let file_buckets = raw
+        .get("file_buckets")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or_else(|| get_default_bucket(stat.total_files));
+
+    Ok((dir_buckets, file_buckets))
+}
+
+fn to_table_capacity(
+    raw: HashMap<String, String>,
+    default: ExtDataFormatParam,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+    let max_dir = raw
+        .get("max_dir")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(default.max_dir);
+
+    let max_file = raw
+        .get("max_file")
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(default.max_file);
+
+    Ok((max_dir, max_file))
+}
+
+fn default_save_data_format_param(block_type: SaveDataBlockType) -> SaveDataFormatParam {
+    SaveDataFormatParam {
+        block_type,
+        max_dir: 100,
+        dir_buckets: get_default_bucket(100),
+        max_file: 100,
+        file_buckets: get_default_bucket(100),
+        duplicate_data: true,
+    }
+}
+
 fn to_save_data_format_param(
     raw: HashMap<String, String>,
-    default_block_len: usize,
+    default: SaveDataFormatParam,
+    default_len: usize,
 ) -> Result<(SaveDataFormatParam, usize), Box<dyn std::error::Error>> {
     let block_len = raw
         .get("block_len")
         .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(default_block_len);
+        .transpose()?;
 
     let block_type = match block_len {
-        512 => SaveDataBlockType::Small,
-        4096 => SaveDataBlockType::Large,
+        None => default.block_type,
+        Some(512) => SaveDataBlockType::Small,
+        Some(4096) => SaveDataBlockType::Large,
         _ => {
             println!("Unsupported block_len value");
             return Err(Box::from(Error::InvalidValue));
         }
     };
 
-    let max_dir = raw
-        .get("max_dir")
-        .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+    let max_dir = raw.get("max_dir").map(|s| s.parse::<usize>()).transpose()?;
 
     let dir_buckets = raw
         .get("dir_buckets")
         .map(|s| s.parse::<usize>())
         .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_dir));
+        .unwrap_or_else(|| max_dir.map_or(default.dir_buckets, get_default_bucket));
 
     let max_file = raw
         .get("max_file")
         .map(|s| s.parse::<usize>())
-        .transpose()?
-        .unwrap_or(100);
+        .transpose()?;
 
     let file_buckets = raw
         .get("file_buckets")
         .map(|s| s.parse::<usize>())
         .transpose()?
-        .unwrap_or_else(|| get_default_bucket(max_file));
+        .unwrap_or_else(|| max_file.map_or(default.file_buckets, get_default_bucket));
 
     let duplicate_data = raw
         .get("duplicate_data")
         .map(|s| s.parse::<bool>())
         .transpose()?
-        .unwrap_or(true);
+        .unwrap_or(default.duplicate_data);
 
     let len = raw
         .get("len")
         .map(|s| s.parse::<usize>())
         .transpose()?
-        .unwrap_or(512 * 1024);
+        .unwrap_or(default_len);
 
     Ok((
         SaveDataFormatParam {
             block_type,
-            max_dir,
+            max_dir: max_dir.unwrap_or(default.max_dir),
             dir_buckets,
-            max_file,
+            max_file: max_file.unwrap_or(default.max_file),
             file_buckets,
             duplicate_data,
         },
@@ -1187,6 +1418,14 @@ fn to_save_data_format_param(
     ))
 }
 
+/// Parses a length in decimal, or in hexadecimal with a `0x` prefix.
+fn parse_len(s: &str) -> Result<usize, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse::<usize>(),
+    }
+}
+
 fn read_key(s: String) -> std::io::Result<[u8; 16]> {
     let mut key = [0; 16];
     if s.len() == 32 {
@@ -1213,9 +1452,38 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let program = args[0].clone();
 
     let mut opts = Options::new();
+    opts.optflag(
+        "",
+        "atomic",
+        "stage changes in a write-ahead log, so that an interrupted commit can be recovered",
+    );
     opts.optopt("", "bare", "mount a bare DISA file", "FILE");
     opts.optopt("b", "boot9", "boot9.bin file path", "FILE");
     opts.optopt("c", "cart", "(experimental) mount a cartridge save", "FILE");
+    opts.optopt(
+        "",
+        "cartunwrap",
+        "convert a Card1 cartridge save to a plain image without wear leveling",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "cartwrap",
+        "convert a plain cartridge save image to a Card1 cartridge save with wear leveling",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "chiplen",
+        "chip size in bytes for --cartwrap, in decimal or in hexadecimal with 0x. The smallest one that fits is used by default",
+        "LEN",
+    );
+    opts.optopt(
+        "",
+        "chunk",
+        "copy files in chunks of LEN bytes in extract or import mode",
+        "LEN",
+    );
     opts.optopt(
         "",
         "db",
@@ -1223,6 +1491,11 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     nandtitle, nandimport, tmptitle, tmpimport, sdtitle, sdimport, ticket",
         "DB_TYPE",
     );
+    opts.optflag(
+        "",
+        "defrag",
+        "move every file in the save or database to contiguous blocks",
+    );
     opts.optflag("x", "extract", "extract the content instead of mounting");
     opts.optopt(
         "f",
@@ -1231,8 +1504,20 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         "[\"\"|param1:value1[,...]]",
     );
     opts.optopt("g", "game", "cartridge ROM in CCI/NCSD format", "FILE");
+    opts.optopt(
+        "",
+        "growtables",
+        "make room for more directories and files in the extdata",
+        "[\"\"|param1:value1[,...]]",
+    );
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("i", "import", "import the content instead of mounting");
+    opts.optopt(
+        "",
+        "id1",
+        "the ID1 directory to use on SD, if there are multiple ones",
+        "ID1",
+    );
     opts.optopt(
         "k",
         "key",
@@ -1251,16 +1536,73 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         "AES slot 0x19 key X for decrypting New3DS exclusive cartridge save",
         "HEX|FILE",
     );
+    opts.optopt(
+        "",
+        "keys",
+        "key file in aes_keys.txt format, used in place of or in addition to boot9.bin",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "manifest",
+        "save or load the archive metadata in extract or import mode",
+        "FILE",
+    );
     opts.optopt("m", "movable", "movable.sed file path", "FILE");
     opts.optopt("", "nand", "NAND root path", "DIR");
     opts.optopt("", "nandext", "mount the NAND Extdata with the ID", "ID");
     opts.optopt("", "nandsave", "mount the NAND save with the ID", "ID");
     opts.optopt("o", "otp", "OTP file path", "FILE");
     opts.optopt("p", "priv", "cartridge private header path", "FILE");
+    opts.optflag(
+        "",
+        "previous",
+        "open the version of the save or database before its last commit, as read-only",
+    );
+    opts.optopt(
+        "",
+        "quota",
+        "recalculate the free blocks of the NAND extdata quota, optionally with a new capacity",
+        "[\"\"|param1:value1[,...]]",
+    );
     opts.optflag("r", "readonly", "mount as read-only file system");
+    opts.optopt(
+        "",
+        "rehash",
+        "rebuild the directory and file hash tables with new bucket counts",
+        "[\"\"|param1:value1[,...]]",
+    );
+    opts.optopt(
+        "",
+        "resize",
+        "resize the save data and change its format parameters, keeping the content",
+        "PARAMS",
+    );
+    opts.optflag(
+        "",
+        "restore",
+        "make the version of the save or database before its last commit the current one",
+    );
     opts.optopt("", "sd", "SD root path", "DIR");
+    opts.optopt(
+        "",
+        "sddecrypt",
+        "decrypt the file under the SD ID1 directory with the path",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "sdencrypt",
+        "encrypt a file to the SD ID1 directory with the path",
+        "PATH",
+    );
     opts.optopt("", "sdext", "mount the SD Extdata with the ID", "ID");
     opts.optopt("", "sdsave", "mount the SD save with the ID", "ID");
+    opts.optflag(
+        "",
+        "sdtree",
+        "mount the decrypted SD ID1 directory as a whole",
+    );
     opts.optflag("t", "touch", "just try opening and closing the archive");
     opts.optflagmulti("v", "verbose", "more v for more verbose logging");
 
@@ -1288,51 +1630,125 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let touch = matches.opt_present("touch");
     let import = matches.opt_present("import");
     let extract = matches.opt_present("extract");
+    let previous = matches.opt_present("previous");
+    let restore = matches.opt_present("restore");
+    let resize = matches.opt_present("resize");
+    let defrag = matches.opt_present("defrag");
+    let rehash = matches.opt_present("rehash");
+    let grow_tables = matches.opt_present("growtables");
+    let quota = matches.opt_present("quota");
 
-    if touch as i32 + import as i32 + extract as i32 > 1 {
+    if [
+        touch,
+        import,
+        extract,
+        restore,
+        resize,
+        defrag,
+        rehash,
+        grow_tables,
+        quota,
+    ]
+    .iter()
+    .filter(|&&x| x)
+    .count()
+        > 1
+    {
         println!(
             "At most one of the following can be specified:
-    --extract, --import, --touch "
+    --extract, --import, --touch, --restore, --resize, --defrag, --rehash, --growtables,
+    --quota"
+        );
+        return Ok(());
+    }
+
+    if previous && (import || restore || resize || defrag || rehash) {
+        println!(
+            "--previous can't be used with --import, --restore, --resize, --defrag or --rehash"
+        );
+        return Ok(());
+    }
+
+    if matches.opt_present("format")
+        && (previous || restore || resize || defrag || rehash || grow_tables || quota)
+    {
+        println!(
+            "--format can't be used with --previous, --restore, --resize, --defrag, --rehash,
+    --growtables or --quota"
         );
         return Ok(());
     }
 
-    let read_only = matches.opt_present("r") || extract || touch;
+    let manifest_path = matches.opt_str("manifest").map(std::path::PathBuf::from);
+    if manifest_path.is_some() && !extract && !import {
+        println!("--manifest can only be used with --extract or --import");
+        return Ok(());
+    }
+
+    let chunk_len = matches.opt_str("chunk");
+    if chunk_len.is_some() && !extract && !import {
+        println!("--chunk can only be used with --extract or --import");
+        return Ok(());
+    }
+    let chunk_len = chunk_len
+        .map(|s| s.parse::<usize>())
+        .transpose()?
+        .unwrap_or(FILE_CHUNK_LEN);
+    if chunk_len == 0 {
+        println!("--chunk must be greater than 0");
+        return Ok(());
+    }
+
+    let read_only = matches.opt_present("r") || extract || touch || previous;
 
     let operation = if extract {
-        FileSystemOperation::Extract
+        FileSystemOperation::Extract(manifest_path, chunk_len)
     } else if import {
-        FileSystemOperation::Import
+        FileSystemOperation::Import(manifest_path, chunk_len)
     } else if touch {
         FileSystemOperation::Touch
     } else {
         FileSystemOperation::Mount(read_only)
     };
 
-    if matches.free.len() != 1 && !touch {
+    let no_mount = touch || restore || resize || defrag || rehash || grow_tables || quota;
+
+    if matches.free.len() != 1 && !no_mount {
         println!("Please specify one mount path");
         return Ok(());
     }
 
-    let mountpoint = if touch {
+    let mountpoint = if no_mount {
         std::path::Path::new("dummy")
     } else {
         std::path::Path::new(&matches.free[0])
     };
 
     let boot9_path = matches.opt_str("boot9");
+    let keys_path = matches.opt_str("keys");
     let movable_path = matches.opt_str("movable");
     let otp_path = matches.opt_str("otp");
     let bare_path = matches.opt_str("bare");
     let cart_path = matches.opt_str("cart");
+    let cart_unwrap_path = matches.opt_str("cartunwrap");
+    let cart_wrap_path = matches.opt_str("cartwrap");
+    let chip_len = matches.opt_str("chiplen");
     let sd_path = matches.opt_str("sd");
+    let sd_id1 = matches.opt_str("id1");
     let sd_save_id = matches.opt_str("sdsave");
+    let sd_decrypt_path = matches.opt_str("sddecrypt");
+    let sd_encrypt_path = matches.opt_str("sdencrypt");
     let sd_ext_id = matches.opt_str("sdext");
+    let sd_tree = matches.opt_present("sdtree");
     let nand_path = matches.opt_str("nand");
     let nand_ext_id = matches.opt_str("nandext");
     let nand_save_id = matches.opt_str("nandsave");
     let db_type = matches.opt_str("db");
     let format_param = matches.opt_str("format");
+    let resize_param = matches.opt_str("resize");
+    let rehash_param = matches.opt_str("rehash");
+    let grow_param = matches.opt_str("growtables");
+    let quota_param = matches.opt_str("quota");
     let priv_path = matches.opt_str("priv");
     let game_path = matches.opt_str("game");
     let x2f_key_y = matches.opt_str("key");
@@ -1342,8 +1758,9 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
     let x2f_key_y = x2f_key_y.map(read_key).transpose()?;
     let x19_key_x = x19_key_x.map(read_key).transpose()?;
     let x1a_key_x = x1a_key_x.map(read_key).transpose()?;
+    let chip_len = chip_len.map(|s| parse_len(&s)).transpose()?;
 
-    let format_param: Option<HashMap<String, String>> = format_param.map(|s| {
+    let parse_param = |s: String| -> HashMap<String, String> {
         s.split(',')
             .filter_map(|p| {
                 if let Some(mid) = p.find(':') {
@@ -1354,7 +1771,12 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
                 }
             })
             .collect()
-    });
+    };
+    let format_param = format_param.map(parse_param);
+    let resize_param = resize_param.map(parse_param);
+    let rehash_param = rehash_param.map(parse_param);
+    let grow_param = grow_param.map(parse_param);
+    let quota_param = quota_param.map(parse_param);
 
     if [
         &sd_save_id,
@@ -1364,36 +1786,147 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         &bare_path,
         &db_type,
         &cart_path,
+        &cart_unwrap_path,
+        &cart_wrap_path,
+        &sd_decrypt_path,
+        &sd_encrypt_path,
     ]
     .iter()
     .map(|x| if x.is_none() { 0 } else { 1 })
     .sum::<i32>()
+        + if sd_tree { 1 } else { 0 }
         != 1
     {
         println!(
             "One and only one of the following arguments must be supplied:
-    --sdext, --sdsave, --nandsave, --nandext, --bare, --db, --cart"
+    --sdext, --sdsave, --nandsave, --nandext, --bare, --db, --cart, --cartunwrap, --cartwrap,
+    --sddecrypt, --sdencrypt, --sdtree"
         );
         return Ok(());
     }
 
-    let resource = Resource::new(
-        boot9_path,
-        movable_path,
-        sd_path,
-        nand_path,
-        otp_path,
-        priv_path,
-        game_path,
-        x2f_key_y,
-        x19_key_x,
-        x1a_key_x,
-    )?;
+    if (previous || restore || defrag)
+        && [&sd_save_id, &nand_save_id, &bare_path, &db_type, &cart_path]
+            .iter()
+            .all(|x| x.is_none())
+    {
+        println!(
+            "--previous, --restore and --defrag can only be used with the following arguments:
+    --sdsave, --nandsave, --bare, --db, --cart"
+        );
+        return Ok(());
+    }
+
+    if resize
+        && [&sd_save_id, &nand_save_id, &bare_path]
+            .iter()
+            .all(|x| x.is_none())
+    {
+        println!(
+            "--resize can only be used with the following arguments:
+    --sdsave, --nandsave, --bare"
+        );
+        return Ok(());
+    }
+
+    if rehash
+        && [
+            &sd_save_id,
+            &sd_ext_id,
+            &nand_save_id,
+            &nand_ext_id,
+            &bare_path,
+            &db_type,
+            &cart_path,
+        ]
+        .iter()
+        .all(|x| x.is_none())
+    {
+        println!(
+            "--rehash can only be used with the following arguments:
+    --sdsave, --sdext, --nandsave, --nandext, --bare, --db, --cart"
+        );
+        return Ok(());
+    }
+
+    if grow_tables && [&sd_ext_id, &nand_ext_id].iter().all(|x| x.is_none()) {
+        println!(
+            "--growtables can only be used with the following arguments:
+    --sdext, --nandext"
+        );
+        return Ok(());
+    }
+
+    if quota && nand_ext_id.is_none() {
+        println!("--quota can only be used with --nandext");
+        return Ok(());
+    }
+
+    let mut builder = ResourceBuilder::new().atomic(matches.opt_present("atomic"));
+    if let Some(boot9) = boot9_path {
+        builder = builder.boot9(FileSource::Path(boot9));
+    }
+    if let Some(keys_path) = keys_path {
+        let mut keys = KeyStore::new();
+        keys.load_text_file(&keys_path)?;
+        builder = builder.keys(&keys);
+    }
+    builder = builder.console_keys(x2f_key_y, x19_key_x, x1a_key_x);
+    if let Some(nand) = &nand_path {
+        builder = builder.nand(nand);
+    } else if let Some(movable) = movable_path {
+        builder = builder.movable(FileSource::Path(movable));
+    }
+    if let Some(sd) = &sd_path {
+        builder = builder.sd(sd);
+    }
+    if let Some(id1) = &sd_id1 {
+        builder = builder.sd_id1(id1);
+    }
+    if let Some(otp) = otp_path {
+        builder = builder.otp(FileSource::Path(otp));
+    }
+    if let Some(private_header) = priv_path {
+        builder = builder.private_header(FileSource::Path(private_header));
+    }
+    if let Some(game) = game_path {
+        builder = builder.game(FileSource::Path(game));
+    }
+    let resource = builder.build()?;
+
+    if sd_id1.is_none() {
+        if let Ok(id1_list) = resource.list_sd_id1() {
+            if id1_list.len() > 1 {
+                println!("WARNING: Multiple ID1 directories found on SD:");
+                for id1 in id1_list {
+                    println!("    {}", id1);
+                }
+                println!(
+                    "Using {}. Specify --id1 to choose another one.",
+                    resource.sd_id1()?
+                );
+            }
+        }
+    }
+
+    // Only one archive is opened, which takes the parameters for the save archives
+    let save_maintenance = |rehash_param, resize_param| SaveMaintenance {
+        defrag,
+        rehash_param,
+        resize_param,
+        restore,
+        previous,
+        read_only,
+    };
 
     if let Some(bare) = bare_path {
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = to_save_data_format_param(
+                format_param,
+                default_save_data_format_param(SaveDataBlockType::Small),
+                512 * 1024,
+            )?;
             resource.format_bare_save(&bare, &param, len)?;
             println!("Formatting done");
         }
@@ -1402,8 +1935,12 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
             "WARNING: After modification, you need to sign the CMAC header using other tools."
         );
 
-        start(
-            resource.open_bare_save(&bare, !read_only)?,
+        start_save(
+            |write| resource.open_bare_save(&bare, write),
+            || resource.open_bare_save_previous(&bare),
+            || resource.restore_bare_save(&bare),
+            |param, len| resource.resize_bare_save(&bare, param, len),
+            save_maintenance(rehash_param, resize_param),
             operation,
             mountpoint,
         )?
@@ -1411,13 +1948,21 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         let id = u32::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 4096)?;
+            let (param, len) = to_save_data_format_param(
+                format_param,
+                default_save_data_format_param(SaveDataBlockType::Large),
+                512 * 1024,
+            )?;
             resource.format_nand_save(id, &param, len)?;
             println!("Formatting done");
         }
 
-        start(
-            resource.open_nand_save(id, !read_only)?,
+        start_save(
+            |write| resource.open_nand_save(id, write),
+            || resource.open_nand_save_previous(id),
+            || resource.restore_nand_save(id),
+            |param, len| resource.resize_nand_save(id, param, len),
+            save_maintenance(rehash_param, resize_param),
             operation,
             mountpoint,
         )?
@@ -1425,13 +1970,21 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
         let id = u64::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = to_save_data_format_param(
+                format_param,
+                default_save_data_format_param(SaveDataBlockType::Small),
+                512 * 1024,
+            )?;
             resource.format_sd_save(id, &param, len)?;
             println!("Formatting done");
         }
 
-        start(
-            resource.open_sd_save(id, !read_only)?,
+        start_save(
+            |write| resource.open_sd_save(id, write),
+            || resource.open_sd_save_previous(id),
+            || resource.restore_sd_save(id),
+            |param, len| resource.resize_sd_save(id, param, len),
+            save_maintenance(rehash_param, resize_param),
             operation,
             mountpoint,
         )?
@@ -1444,21 +1997,77 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
             println!("Formatting done");
         }
 
-        start(resource.open_sd_ext(id, !read_only)?, operation, mountpoint)?
+        if let Some(grow_param) = grow_param {
+            let ext = resource.open_sd_ext(id, true)?;
+            let (max_dir, max_file) = to_table_capacity(grow_param, ext.format_param())?;
+            println!("Growing tables...");
+            ext.grow_tables(max_dir, max_file)?;
+            println!("Growing tables done");
+        } else if let Some(rehash_param) = rehash_param {
+            let ext = resource.open_sd_ext(id, true)?;
+            let stat = ext.stat()?;
+            print_hash_chains(&stat);
+            let (dir_buckets, file_buckets) = to_bucket_counts(rehash_param, &stat)?;
+            println!("Rehashing...");
+            ext.rehash(dir_buckets, file_buckets)?;
+            print_hash_chains(&resource.open_sd_ext(id, false)?.stat()?);
+        } else {
+            start(resource.open_sd_ext(id, !read_only)?, operation, mountpoint)?
+        }
     } else if let Some(id) = nand_ext_id {
         let id = u64::from_str_radix(&id, 16)?;
         if let Some(format_param) = format_param {
             println!("Formatting...");
+            let capacity = format_param
+                .get("capacity")
+                .map(|s| s.parse::<u32>())
+                .transpose()?
+                .unwrap_or(1024 * 1024);
             let param = to_ext_data_format_param(format_param)?;
-            resource.format_nand_ext(id, &param)?;
+            resource.format_nand_ext_with_capacity(id, &param, capacity)?;
             println!("Formatting done");
         }
 
-        start(
-            resource.open_nand_ext(id, !read_only)?,
-            operation,
-            mountpoint,
-        )?
+        if let Some(grow_param) = grow_param {
+            let ext = resource.open_nand_ext(id, true)?;
+            let (max_dir, max_file) = to_table_capacity(grow_param, ext.format_param())?;
+            println!("Growing tables...");
+            ext.grow_tables(max_dir, max_file)?;
+            println!("Growing tables done");
+        } else if let Some(rehash_param) = rehash_param {
+            let ext = resource.open_nand_ext(id, true)?;
+            let stat = ext.stat()?;
+            print_hash_chains(&stat);
+            let (dir_buckets, file_buckets) = to_bucket_counts(rehash_param, &stat)?;
+            println!("Rehashing...");
+            ext.rehash(dir_buckets, file_buckets)?;
+            print_hash_chains(&resource.open_nand_ext(id, false)?.stat()?);
+        } else if let Some(quota_param) = quota_param {
+            let ext = resource.open_nand_ext(id, true)?;
+            match ext.quota()? {
+                Some(quota) => print_quota(&quota),
+                None => {
+                    println!("The extdata doesn't have a quota");
+                    return Ok(());
+                }
+            }
+            println!("Repairing quota...");
+            let mut quota = ext.repair_quota()?;
+            if let Some(capacity) = quota_param
+                .get("capacity")
+                .map(|s| s.parse::<u32>())
+                .transpose()?
+            {
+                quota = ext.set_quota_capacity(capacity)?;
+            }
+            print_quota(&quota);
+        } else {
+            start(
+                resource.open_nand_ext(id, !read_only)?,
+                operation,
+                mountpoint,
+            )?
+        }
     } else if let Some(db_type) = db_type {
         if format_param.is_some() {
             println!("Warning: formatting not supported");
@@ -1477,23 +2086,53 @@ fn main_inner() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
-        start(
-            resource.open_db(db_type, !read_only)?,
+        start_save(
+            |write| resource.open_db(db_type, write),
+            || resource.open_db_previous(db_type),
+            || resource.restore_db(db_type),
+            |_, _| Err(Error::Unsupported),
+            save_maintenance(rehash_param, resize_param),
             operation,
             mountpoint,
         )?
     } else if let Some(cart) = cart_path {
         if let Some(format_param) = format_param {
             println!("Formatting...");
-            let (param, len) = to_save_data_format_param(format_param, 512)?;
+            let (param, len) = to_save_data_format_param(
+                format_param,
+                default_save_data_format_param(SaveDataBlockType::Small),
+                512 * 1024,
+            )?;
             resource.format_cart_save(&cart, &param, len)?;
             println!("Formatting done");
         }
-        start(
-            resource.open_cart_save(&cart, !read_only)?,
+
+        start_save(
+            |write| resource.open_cart_save(&cart, write),
+            || resource.open_cart_save_previous(&cart),
+            || resource.restore_cart_save(&cart),
+            |_, _| Err(Error::Unsupported),
+            save_maintenance(rehash_param, resize_param),
             operation,
             mountpoint,
         )?
+    } else if let Some(cart) = cart_unwrap_path {
+        println!("Converting...");
+        resource.unwrap_cart_save(&cart, &mountpoint.to_string_lossy())?;
+        println!("Converting done");
+    } else if let Some(cart) = cart_wrap_path {
+        println!("Converting...");
+        resource.wrap_cart_save(&cart, &mountpoint.to_string_lossy(), chip_len)?;
+        println!("Converting done");
+    } else if let Some(path) = sd_decrypt_path {
+        sd_decrypt(&resource, &path, mountpoint)?
+    } else if let Some(path) = sd_encrypt_path {
+        sd_encrypt(&resource, &path, mountpoint)?
+    } else if sd_tree {
+        if format_param.is_some() {
+            println!("Warning: formatting not supported");
+        }
+        start(resource.open_sd_tree(!read_only)?, operation, mountpoint)?
     } else {
         panic!()
     };
@@ -1580,4 +2219,70 @@ mod test {
         assert!(name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaa")).is_some());
         assert!(name_os_to_3ds::<[u8; 16]>(OsStr::new("aaaaaaaaaaaaaaaaa")).is_none());
     }
+
+    #[test]
+    fn test_parse_len() {
+        assert_eq!(parse_len("524288").unwrap(), 0x80000);
+        assert_eq!(parse_len("0x80000").unwrap(), 0x80000);
+        assert_eq!(parse_len("0X1f000").unwrap(), 0x1F000);
+        assert!(parse_len("80000h").is_err());
+    }
+
+    #[test]
+    fn extract_broken_file() {
+        use libsave3ds::memory_file::MemoryFile;
+        use libsave3ds::random_access_file::RandomAccessFile;
+        use std::rc::Rc;
+
+        let param = SaveDataFormatParam {
+            block_type: SaveDataBlockType::Large,
+            max_dir: 10,
+            dir_buckets: 10,
+            max_file: 10,
+            file_buckets: 10,
+            duplicate_data: true,
+        };
+        let resource = ResourceBuilder::new().build().unwrap();
+        let disa_raw = Rc::new(MemoryFile::new(vec![0; 0x80000]));
+        resource
+            .format_bare_save_file(disa_raw.clone(), &param)
+            .unwrap();
+        let save = resource.open_bare_save_file(disa_raw.clone()).unwrap();
+        let root = save.open_root().unwrap();
+        let mut file = root.new_sub_file([b'a'; 16], 0x2000).unwrap();
+        root.new_sub_file([b'b'; 16], 0x1000)
+            .unwrap()
+            .write(0, &[0xFF; 0x1000])
+            .unwrap();
+        file.resize(0x4000).unwrap();
+        for i in 0..4 {
+            file.write(i * 0x1000, &[i as u8 + 1; 0x1000]).unwrap();
+        }
+        drop(file);
+        drop(root);
+        save.commit().unwrap();
+        drop(save);
+
+        // Break the third block of the file, which is in its second fragment
+        let mut raw = vec![0; disa_raw.len()];
+        disa_raw.read(0, &mut raw).unwrap();
+        for pos in (0..raw.len()).step_by(0x1000) {
+            if raw[pos..pos + 0x1000] == [3; 0x1000][..] {
+                disa_raw.write(pos, &[0]).unwrap();
+            }
+        }
+
+        let save = resource.open_bare_save_file(disa_raw).unwrap();
+        let dir = std::env::temp_dir().join(format!("save3ds_extract_test_{}", std::process::id()));
+        extract(save, &dir, None, 0x2000).unwrap();
+        let mut expected = vec![1; 0x1000];
+        expected.extend(&[2; 0x1000][..]);
+        expected.extend(&[0xDD; 0x1000][..]);
+        expected.extend(&[0; 0x1000][..]);
+        assert_eq!(
+            std::fs::read(dir.join("aaaaaaaaaaaaaaaa")).unwrap(),
+            expected
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
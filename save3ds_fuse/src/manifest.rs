@@ -0,0 +1,362 @@
+use libsave3ds::cart_save_data::CartSaveData;
+use libsave3ds::db::Db;
+use libsave3ds::error::*;
+use libsave3ds::ext_data::{ExtData, ExtDataMountInfo};
+use libsave3ds::file_system::FileSystem;
+use libsave3ds::save_data::SaveData;
+use libsave3ds::sd_tree::SdTree;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Archive metadata that is lost when the content is extracted as plain files.
+/// Extract mode saves it next to the extracted content, and import mode restores it.
+///
+/// The manifest is a text file with one record per line, in the form of `KEY VALUE PATH`,
+/// where `PATH` is the path of the entry in the archive, starting with `/`.
+/// Records of the archive itself use the path `/`.
+/// Directories and files are recorded with their inode numbers,
+/// in the order they are listed in their parent directory.
+#[derive(Default)]
+pub struct Manifest {
+    dirs: Vec<(String, u32)>,
+    files: Vec<(String, u32)>,
+    unique_ids: BTreeMap<String, u64>,
+    mount_info: Option<ExtDataMountInfo>,
+}
+
+fn parse_mount_path(value: &str) -> Option<[[u8; 0x10]; 0x10]> {
+    if value.len() != 0x200 {
+        return None;
+    }
+    let mut mount_path = [[0; 0x10]; 0x10];
+    for (i, byte) in mount_path.iter_mut().flat_map(|p| p.iter_mut()).enumerate() {
+        *byte = u8::from_str_radix(value.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(mount_path)
+}
+
+fn format_mount_path(mount_path: &[[u8; 0x10]; 0x10]) -> String {
+    mount_path
+        .iter()
+        .flat_map(|p| p.iter())
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, Error> {
+        let mut manifest = Manifest::default();
+        for line in BufReader::new(std::fs::File::open(path)?).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            let parsed = match (fields.next(), fields.next(), fields.next()) {
+                (Some("dir"), Some(value), Some(path)) => value
+                    .parse::<u32>()
+                    .ok()
+                    .map(|ino| manifest.dirs.push((path.to_owned(), ino))),
+                (Some("file"), Some(value), Some(path)) => value
+                    .parse::<u32>()
+                    .ok()
+                    .map(|ino| manifest.files.push((path.to_owned(), ino))),
+                (Some("unique_id"), Some(value), Some(path)) => {
+                    u64::from_str_radix(value, 16).ok().map(|unique_id| {
+                        manifest.unique_ids.insert(path.to_owned(), unique_id);
+                    })
+                }
+                (Some("action"), Some(value), Some("/")) => u32::from_str_radix(value, 16)
+                    .ok()
+                    .map(|action| manifest.mount_info_mut().action = action),
+                (Some("mount_id"), Some(value), Some("/")) => u32::from_str_radix(value, 16)
+                    .ok()
+                    .map(|mount_id| manifest.mount_info_mut().mount_id = mount_id),
+                (Some("mount_path"), Some(value), Some("/")) => parse_mount_path(value)
+                    .map(|mount_path| manifest.mount_info_mut().mount_path = mount_path),
+                (Some(""), None, None) => Some(()),
+                _ => None,
+            };
+            if parsed.is_none() {
+                println!("Unrecognized manifest record: {}", line);
+                return Err(Error::InvalidValue);
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut file = std::fs::File::create(path)?;
+        if let Some(mount_info) = self.mount_info.as_ref() {
+            writeln!(file, "action {:08x} /", mount_info.action)?;
+            writeln!(file, "mount_id {:08x} /", mount_info.mount_id)?;
+            writeln!(
+                file,
+                "mount_path {} /",
+                format_mount_path(&mount_info.mount_path)
+            )?;
+        }
+        for (path, ino) in &self.dirs {
+            writeln!(file, "dir {} {}", ino, path)?;
+        }
+        for (path, ino) in &self.files {
+            writeln!(file, "file {} {}", ino, path)?;
+        }
+        for (path, unique_id) in &self.unique_ids {
+            writeln!(file, "unique_id {:016x} {}", unique_id, path)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_dir(&mut self, path: &str, ino: u32) {
+        self.dirs.push((path.to_owned(), ino));
+    }
+
+    pub fn add_file(&mut self, path: &str, ino: u32) {
+        self.files.push((path.to_owned(), ino));
+    }
+
+    /// Returns the order to create `entries`, given as `(path, parent path)` of directories
+    /// (if `dir` is true) or files, so that the recorded ones are listed in their parent
+    /// directory in the recorded order.
+    ///
+    /// New entries are inserted at the head of their parent directory, so recorded siblings are
+    /// created from the last one. Among the recorded entries ready to create, the one with the
+    /// lowest inode number goes first, which gives back the recorded inode numbers when
+    /// importing to a freshly formatted archive. Entries not recorded are created last.
+    pub fn creation_order(&self, dir: bool, entries: &[(String, String)]) -> Vec<usize> {
+        let records = if dir { &self.dirs } else { &self.files };
+        let recorded: HashMap<&str, (usize, u32)> = records
+            .iter()
+            .enumerate()
+            .map(|(pos, (path, ino))| (path.as_str(), (pos, *ino)))
+            .collect();
+
+        // Recorded entries grouped by parent, with the one to create next at the back
+        let mut groups: BTreeMap<&str, Vec<(usize, u32, usize)>> = BTreeMap::new();
+        let mut unrecorded = vec![];
+        for (i, (path, parent)) in entries.iter().enumerate() {
+            match recorded.get(path.as_str()) {
+                Some(&(pos, ino)) => groups
+                    .entry(parent.as_str())
+                    .or_default()
+                    .push((pos, ino, i)),
+                None => unrecorded.push(i),
+            }
+        }
+        for group in groups.values_mut() {
+            group.sort();
+        }
+
+        let pending: HashSet<&str> = entries.iter().map(|(path, _)| path.as_str()).collect();
+        let mut created: HashSet<&str> = HashSet::new();
+        let is_ready = |parent: &str, created: &HashSet<&str>| {
+            !pending.contains(parent) || created.contains(parent)
+        };
+
+        let mut order = vec![];
+        loop {
+            let next_recorded = groups
+                .iter()
+                .filter(|(parent, _)| is_ready(parent, &created))
+                .filter_map(|(parent, group)| group.last().map(|&(_, ino, _)| (ino, *parent)))
+                .min();
+            let i = if let Some((_, parent)) = next_recorded {
+                groups.get_mut(parent).unwrap().pop().unwrap().2
+            } else if let Some(k) = unrecorded
+                .iter()
+                .position(|&i| is_ready(&entries[i].1, &created))
+            {
+                unrecorded.remove(k)
+            } else {
+                break;
+            };
+            created.insert(entries[i].0.as_str());
+            order.push(i);
+        }
+        order
+    }
+
+    pub fn unique_id(&self, path: &str) -> Option<u64> {
+        self.unique_ids.get(path).cloned()
+    }
+
+    pub fn set_unique_id(&mut self, path: &str, unique_id: u64) {
+        self.unique_ids.insert(path.to_owned(), unique_id);
+    }
+
+    pub fn mount_info(&self) -> Option<&ExtDataMountInfo> {
+        self.mount_info.as_ref()
+    }
+
+    pub fn set_mount_info(&mut self, mount_info: ExtDataMountInfo) {
+        self.mount_info = Some(mount_info);
+    }
+
+    /// Returns the mount information to fill in, with unspecified fields being zero.
+    fn mount_info_mut(&mut self) -> &mut ExtDataMountInfo {
+        self.mount_info.get_or_insert(ExtDataMountInfo {
+            action: 0,
+            mount_id: 0,
+            mount_path: [[0; 0x10]; 0x10],
+        })
+    }
+}
+
+/// Archive specific handling of the metadata in a manifest.
+/// The default implementation records nothing.
+pub trait ManifestArchive: FileSystem {
+    /// Records the metadata of the archive itself to the manifest.
+    fn save_archive(&self, _manifest: &mut Manifest) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Applies the metadata of the archive itself recorded in the manifest.
+    fn load_archive(&self, _manifest: &Manifest) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Records the metadata of the file at `path` to the manifest.
+    fn save_file(
+        _manifest: &mut Manifest,
+        _path: &str,
+        _file: &Self::FileType,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Applies the metadata recorded for `path` in the manifest to the file.
+    fn load_file(
+        _manifest: &Manifest,
+        _path: &str,
+        _file: &mut Self::FileType,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ManifestArchive for SaveData {}
+impl ManifestArchive for CartSaveData {}
+impl ManifestArchive for Db {}
+impl ManifestArchive for SdTree {}
+
+impl ManifestArchive for ExtData {
+    fn save_archive(&self, manifest: &mut Manifest) -> Result<(), Error> {
+        manifest.set_mount_info(self.mount_info()?);
+        Ok(())
+    }
+
+    fn load_archive(&self, manifest: &Manifest) -> Result<(), Error> {
+        if let Some(mount_info) = manifest.mount_info() {
+            self.set_mount_info(mount_info)?;
+        }
+        Ok(())
+    }
+
+    fn save_file(manifest: &mut Manifest, path: &str, file: &Self::FileType) -> Result<(), Error> {
+        manifest.set_unique_id(path, file.unique_id()?);
+        Ok(())
+    }
+
+    fn load_file(manifest: &Manifest, path: &str, file: &mut Self::FileType) -> Result<(), Error> {
+        if let Some(unique_id) = manifest.unique_id(path) {
+            file.set_unique_id(unique_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::manifest::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "save3ds_manifest_test_{}_{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut manifest = Manifest::default();
+        manifest.add_dir("/a", 2);
+        manifest.add_dir("/a/b c", 3);
+        manifest.add_file("/a/f", 1);
+        manifest.add_file("/g", 4);
+        manifest.set_unique_id("/a/f", 0x0123_4567_89ab_cdef);
+        let mut mount_path = [[0; 0x10]; 0x10];
+        mount_path[0][..4].copy_from_slice(b"abcd");
+        mount_path[0xF][0xF] = 0xFF;
+        let mount_info = ExtDataMountInfo {
+            action: 0x1234,
+            mount_id: 0xFEDC_BA98,
+            mount_path,
+        };
+        manifest.set_mount_info(mount_info);
+
+        let path = temp_path("round_trip");
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        loaded.save(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.dirs, manifest.dirs);
+        assert_eq!(loaded.files, manifest.files);
+        assert_eq!(loaded.unique_id("/a/f"), Some(0x0123_4567_89ab_cdef));
+        assert_eq!(loaded.unique_id("/g"), None);
+        assert_eq!(loaded.mount_info(), Some(&mount_info));
+    }
+
+    #[test]
+    fn malformed() {
+        let path = temp_path("malformed");
+        std::fs::write(&path, "dir 2 /a\n\nfile 1 /a/f\naction 1 /\n").unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.dirs, [("/a".to_owned(), 2)]);
+        assert_eq!(manifest.mount_info().unwrap().action, 1);
+        assert_eq!(manifest.mount_info().unwrap().mount_id, 0);
+
+        for line in &[
+            "dir x /a",
+            "dir 2",
+            "file -1 /a",
+            "unique_id xyz /a",
+            "action 1 /a",
+            "mount_id 100000000 /",
+            "mount_path 00 /",
+            "size 1 /a",
+            " dir 2 /a",
+        ] {
+            std::fs::write(&path, format!("dir 2 /a\n{}\n", line)).unwrap();
+            assert!(matches!(Manifest::load(&path), Err(Error::InvalidValue)));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn creation_order() {
+        let mut manifest = Manifest::default();
+        manifest.add_dir("/a", 2);
+        manifest.add_dir("/c", 3);
+        manifest.add_dir("/a/b", 4);
+        let entries: Vec<(String, String)> = [
+            ("/a/b", "/a"),
+            ("/z/w", "/z"),
+            ("/c", "/"),
+            ("/z", "/"),
+            ("/a", "/"),
+        ]
+        .iter()
+        .map(|&(path, parent)| (path.to_owned(), parent.to_owned()))
+        .collect();
+
+        // Recorded siblings are created from the last one, and their children after them.
+        // Unrecorded ones go last, still after their parents.
+        assert_eq!(manifest.creation_order(true, &entries), [2, 4, 0, 3, 1]);
+
+        // Nothing is recorded for files, so they are created in the given order once ready
+        assert_eq!(manifest.creation_order(false, &entries), [2, 3, 1, 4, 0]);
+    }
+}